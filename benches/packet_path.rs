@@ -0,0 +1,49 @@
+//! Compares sending and receiving whole messages with the path that copied them on both ends.
+//!
+//! Run with `cargo bench --features bench`.
+
+use bevy_replicon_matchbox::shared::PacketPath;
+use bytes::Bytes;
+use criterion::{BenchmarkId, Criterion, Throughput, criterion_group, criterion_main};
+use std::hint::black_box;
+
+const MAX_PACKET_SIZE: usize = 65_536;
+
+/// Returns a message with the spare capacity left by a growing serialization buffer.
+fn message(size: usize) -> Bytes {
+    let mut buffer = Vec::with_capacity(2 * size);
+    buffer.resize(size, 0xAB);
+    buffer.into()
+}
+
+/// The previous path, which prepended the marker to a copy and copied the payload out again.
+fn copying(message: Bytes) -> Bytes {
+    let mut packet = Vec::with_capacity(message.len() + 1);
+    packet.push(0);
+    packet.extend_from_slice(&message);
+    let packet: Box<[u8]> = black_box(packet.into());
+    Bytes::copy_from_slice(&packet[1..])
+}
+
+fn packet_path(c: &mut Criterion) {
+    let mut group = c.benchmark_group("packet_path");
+    for size in [64, 1_200, 60_000] {
+        group.throughput(Throughput::Bytes(size as u64));
+        group.bench_with_input(BenchmarkId::new("copying", size), &size, |b, &size| {
+            b.iter_batched(|| message(size), copying, criterion::BatchSize::SmallInput)
+        });
+
+        let mut path = PacketPath::default();
+        group.bench_with_input(BenchmarkId::new("reusing", size), &size, |b, &size| {
+            b.iter_batched(
+                || message(size),
+                |message| path.send(message, MAX_PACKET_SIZE).unwrap(),
+                criterion::BatchSize::SmallInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, packet_path);
+criterion_main!(benches);
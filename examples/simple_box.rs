@@ -7,13 +7,15 @@ use bevy::{
     prelude::*,
     winit::{UpdateMode::Continuous, WinitSettings},
 };
-use bevy_matchbox::matchbox_signaling::SignalingServer;
 use bevy_replicon::prelude::*;
-use bevy_replicon_matchbox::{MatchboxClient, MatchboxHost, RepliconMatchboxPlugins};
+use bevy_replicon_matchbox::{
+    MatchboxBackendConfig, MatchboxClient, MatchboxHost, RepliconMatchboxPlugins, RoomAddress,
+    SignalingConfig,
+};
 use clap::Parser;
 use serde::{Deserialize, Serialize};
 use std::hash::{DefaultHasher, Hash, Hasher};
-use std::net::{Ipv4Addr, SocketAddrV4};
+use std::net::Ipv4Addr;
 
 fn main() {
     let log_plugin = LogPlugin {
@@ -59,10 +61,14 @@ fn read_cli(mut commands: Commands, cli: Res<Cli>, channels: Res<RepliconChannel
         }
         Cli::Server { port } => {
             info!("starting server at port {port}");
-            start_signaling_server(&mut commands, port);
-            let room_url = format!("ws://localhost:{port}/simple-box");
+            commands.insert_resource(
+                SignalingConfig::default()
+                    .with_bind_address((Ipv4Addr::LOCALHOST, port))
+                    .with_cors(true),
+            );
+            let room_url = RoomAddress::new(format!("ws://localhost:{port}"), "simple-box");
 
-            let server = MatchboxHost::new(room_url, &channels)?;
+            let server = MatchboxHost::new(room_url, &channels, MatchboxBackendConfig::default())?;
             commands.insert_resource(server);
             commands.spawn((
                 Text::new("Server"),
@@ -81,9 +87,10 @@ fn read_cli(mut commands: Commands, cli: Res<Cli>, channels: Res<RepliconChannel
         }
         Cli::Client { port } => {
             info!("connecting to port {port}");
-            let room_url = format!("ws://localhost:{port}/simple-box");
+            let room_url = RoomAddress::new(format!("ws://localhost:{port}"), "simple-box");
 
-            let client = MatchboxClient::new(room_url, &channels)?;
+            let client =
+                MatchboxClient::new(room_url, &channels, MatchboxBackendConfig::default())?;
             commands.insert_resource(client);
             commands.spawn((
                 Text("Client".to_string()),
@@ -99,27 +106,6 @@ fn read_cli(mut commands: Commands, cli: Res<Cli>, channels: Res<RepliconChannel
     Ok(())
 }
 
-fn start_signaling_server(commands: &mut Commands, port: u16) {
-    info!("Starting signaling server on port {port}");
-    let addr = SocketAddrV4::new(Ipv4Addr::LOCALHOST, port);
-    let signaling_server = bevy_matchbox::MatchboxServer::from(
-        SignalingServer::client_server_builder(addr)
-            .on_connection_request(|connection| {
-                info!("Connecting: {connection:?}");
-                Ok(true) // Allow all connections
-            })
-            .on_id_assignment(|(socket, id)| info!("{socket} received {id}"))
-            .on_host_connected(|id| info!("Host joined: {id}"))
-            .on_host_disconnected(|id| info!("Host left: {id}"))
-            .on_client_connected(|id| info!("Client joined: {id}"))
-            .on_client_disconnected(|id| info!("Client left: {id}"))
-            .cors()
-            .trace()
-            .build(),
-    );
-    commands.insert_resource(signaling_server);
-}
-
 fn spawn_camera(mut commands: Commands) {
     commands.spawn(Camera2d);
 }
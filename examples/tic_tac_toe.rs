@@ -3,16 +3,18 @@
 
 use std::{
     fmt::{self, Formatter},
-    net::{IpAddr, Ipv4Addr, SocketAddrV4},
+    net::{IpAddr, Ipv4Addr},
 };
 
 use bevy::{
     ecs::{relationship::RelatedSpawner, spawn::SpawnWith},
     prelude::*,
 };
-use bevy_matchbox::{MatchboxServer, matchbox_signaling::SignalingServer};
 use bevy_replicon::prelude::*;
-use bevy_replicon_matchbox::{MatchboxClient, MatchboxHost, RepliconMatchboxPlugins};
+use bevy_replicon_matchbox::{
+    MatchboxBackendConfig, MatchboxClient, MatchboxHost, RepliconMatchboxPlugins, RoomAddress,
+    SignalingConfig,
+};
 use clap::{Parser, ValueEnum};
 use serde::{Deserialize, Serialize};
 
@@ -95,11 +97,19 @@ fn read_cli(
         }
         Cli::Server { port, symbol } => {
             info!("starting signaling server at port {port} ");
-            let room_url = format!("ws://localhost:{port}/tic-tac-toe");
-            start_signaling_server(&mut commands, port);
+            let room_url = RoomAddress::new(format!("ws://localhost:{port}"), "tic-tac-toe");
+            commands.insert_resource(
+                SignalingConfig::default()
+                    .with_bind_address((Ipv4Addr::LOCALHOST, port))
+                    .with_cors(true),
+            );
 
             info!("starting host as {symbol} ");
-            let server = MatchboxHost::new(room_url, &replicon_channels)?;
+            let server = MatchboxHost::new(
+                room_url,
+                &replicon_channels,
+                MatchboxBackendConfig::default(),
+            )?;
             commands.insert_resource(server);
 
             commands.spawn((LocalPlayer, symbol));
@@ -108,9 +118,13 @@ fn read_cli(
             info!("connecting to {ip}:{port}");
 
             // Backend initialization
-            let room_url = format!("ws://localhost:{port}/tic-tac-toe");
+            let room_url = RoomAddress::new(format!("ws://localhost:{port}"), "tic-tac-toe");
             info!("connecting to port {port}");
-            let client = MatchboxClient::new(room_url, &replicon_channels)?;
+            let client = MatchboxClient::new(
+                room_url,
+                &replicon_channels,
+                MatchboxBackendConfig::default(),
+            )?;
             commands.insert_resource(client);
 
             commands.spawn((LocalPlayer, ClientPlayer));
@@ -120,27 +134,6 @@ fn read_cli(
     Ok(())
 }
 
-fn start_signaling_server(commands: &mut Commands, port: u16) {
-    info!("Starting signaling server on port {port}");
-    let addr = SocketAddrV4::new(Ipv4Addr::LOCALHOST, port);
-    let signaling_server = MatchboxServer::from(
-        SignalingServer::client_server_builder(addr)
-            .on_connection_request(|connection| {
-                info!("Connecting: {connection:?}");
-                Ok(true) // Allow all connections
-            })
-            .on_id_assignment(|(socket, id)| info!("{socket} received {id}"))
-            .on_host_connected(|id| info!("Host joined: {id}"))
-            .on_host_disconnected(|id| info!("Host left: {id}"))
-            .on_client_connected(|id| info!("Client joined: {id}"))
-            .on_client_disconnected(|id| info!("Client left: {id}"))
-            .cors()
-            .trace()
-            .build(),
-    );
-    commands.insert_resource(signaling_server);
-}
-
 fn setup_ui(mut commands: Commands, symbol_font: Res<SymbolFont>) {
     info!("setting up UI");
     commands.spawn(Camera2d);
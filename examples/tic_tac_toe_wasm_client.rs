@@ -13,7 +13,9 @@ use bevy::{
     prelude::*,
 };
 use bevy_replicon::prelude::*;
-use bevy_replicon_matchbox::{MatchboxClient, MatchboxHost, RepliconMatchboxPlugins};
+use bevy_replicon_matchbox::{
+    MatchboxBackendConfig, MatchboxClient, MatchboxHost, RepliconMatchboxPlugins, RoomAddress,
+};
 use serde::{Deserialize, Serialize};
 
 fn main() {
@@ -106,6 +108,10 @@ struct IsHost(bool);
 /// In WASM, reads from `?lobby=CODE&host=true` query parameters.
 /// Falls back to DEFAULT_LOBBY_CODE if not found.
 /// Host mode is enabled if `host=true` is in the URL.
+#[cfg_attr(
+    not(target_arch = "wasm32"),
+    expect(unused_mut, reason = "only read from the URL in browsers")
+)]
 fn read_lobby_code_from_url(mut commands: Commands) {
     let mut lobby_code = DEFAULT_LOBBY_CODE.to_string();
     let mut is_host = false;
@@ -113,6 +119,7 @@ fn read_lobby_code_from_url(mut commands: Commands) {
     #[cfg(target_arch = "wasm32")]
     {
         // Try to read from URL query parameters
+        use bevy_replicon_matchbox::RoomCode;
         use wasm_bindgen::JsCast;
         use web_sys::window;
 
@@ -123,10 +130,13 @@ fn read_lobby_code_from_url(mut commands: Commands) {
                 for param in params {
                     if let Some((key, value)) = param.split_once('=') {
                         match key {
-                            "lobby" if !value.is_empty() => {
-                                lobby_code = value.to_string();
-                                info!("Found lobby code in URL: {}", lobby_code);
-                            }
+                            "lobby" if !value.is_empty() => match RoomCode::parse(value) {
+                                Ok(code) => {
+                                    lobby_code = code.into();
+                                    info!("Found lobby code in URL: {}", lobby_code);
+                                }
+                                Err(e) => warn!("Ignoring lobby code {value}: {e}"),
+                            },
                             "host" if value == "true" => {
                                 is_host = true;
                                 info!("Host mode enabled via URL parameter");
@@ -150,7 +160,7 @@ fn connect_to_server(
     lobby_code: Res<LobbyCode>,
     is_host: Res<IsHost>,
 ) {
-    let room_url = format!("{}/{}", SIGNALING_SERVER_BASE, lobby_code.0);
+    let room_url = RoomAddress::new(SIGNALING_SERVER_BASE, &lobby_code.0);
 
     if is_host.0 {
         // Host mode: create server and spawn as Cross player
@@ -158,7 +168,11 @@ fn connect_to_server(
             "Starting as host (server) on signaling server: {}",
             room_url
         );
-        match MatchboxHost::new(room_url, &replicon_channels) {
+        match MatchboxHost::new(
+            room_url,
+            &replicon_channels,
+            MatchboxBackendConfig::default(),
+        ) {
             Ok(host) => {
                 commands.insert_resource(host);
                 commands.spawn((LocalPlayer, Symbol::Cross));
@@ -172,7 +186,11 @@ fn connect_to_server(
     } else {
         // Client mode: connect to host
         info!("Connecting to signaling server: {}", room_url);
-        match MatchboxClient::new(room_url, &replicon_channels) {
+        match MatchboxClient::new(
+            room_url,
+            &replicon_channels,
+            MatchboxBackendConfig::default(),
+        ) {
             Ok(client) => {
                 commands.insert_resource(client);
                 commands.spawn((LocalPlayer, ClientPlayer));
@@ -361,7 +379,7 @@ fn init_symbols(
 
 /// Starts the game after connection (client mode only).
 fn client_start(mut commands: Commands, lobby_code: Res<LobbyCode>) {
-    let room_url = format!("{}/{}", SIGNALING_SERVER_BASE, lobby_code.0);
+    let room_url = RoomAddress::new(SIGNALING_SERVER_BASE, &lobby_code.0);
     info!("Successfully connected to signaling server: {}", room_url);
     commands.set_state(GameState::InGame);
 }
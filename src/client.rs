@@ -1,37 +1,59 @@
 use crate::shared::*;
 use bevy::prelude::*;
-use bevy_matchbox::MatchboxSocket;
 use bevy_matchbox::matchbox_socket::PeerId;
 use bevy_matchbox::prelude::PeerState;
 use bevy_replicon::prelude::*;
-use std::io;
+use std::collections::HashMap;
+use std::mem;
+use std::time::Duration;
+
+#[cfg(feature = "server")]
+mod migration;
+
+#[cfg(feature = "server")]
+pub use migration::HostMigrated;
 
 /// Adds a client messaging backend made for examples to `bevy_replicon`.
 pub struct RepliconMatchboxClientPlugin;
 
 impl Plugin for RepliconMatchboxClientPlugin {
     fn build(&self, app: &mut App) {
+        app.add_message::<HandshakeRejected>()
+            .init_resource::<ChannelStats>();
+        add_lifecycle_messages(app);
         app.add_systems(
             PreUpdate,
             (
+                clear_disconnect_reason.run_if(resource_added::<MatchboxClient>),
+                reset_channel_stats.run_if(resource_added::<MatchboxClient>),
+                reconnect.run_if(resource_exists::<MatchboxClient>),
+                apply_network_conditions.run_if(resource_exists::<MatchboxClient>),
                 receive_packets.run_if(resource_exists::<MatchboxClient>),
                 receive_system_channel_packets.run_if(resource_exists::<MatchboxClient>),
                 update_peers.run_if(resource_exists::<MatchboxClient>),
+                update_phase.run_if(resource_exists::<MatchboxClient>),
+                // Replicon expects the state to change while receiving, from `PostUpdate` it would only
+                // apply on the next frame, after the client already ran a frame without a connection.
+                set_disconnected.run_if(resource_removed::<MatchboxClient>),
             )
                 .chain()
                 .in_set(ClientSystems::ReceivePackets),
         );
+        #[cfg(feature = "server")]
+        app.add_message::<HostMigrated>().add_systems(
+            PreUpdate,
+            migration::migrate_host
+                .after(update_peers)
+                .before(set_disconnected)
+                .in_set(ClientSystems::ReceivePackets),
+        );
 
         app.add_systems(
             PostUpdate,
-            (
-                set_disconnected
-                    .in_set(ClientSystems::Send)
-                    .run_if(resource_removed::<MatchboxClient>),
-                send_packets
-                    .in_set(ClientSystems::SendPackets)
-                    .run_if(not(no_host_defined).and(resource_exists::<MatchboxClient>)),
-            ),
+            (send_packets, update_stats)
+                .chain()
+                .in_set(ClientSystems::SendPackets)
+                .run_if(not(no_host_defined).and(resource_exists::<MatchboxClient>)),
         );
     }
 }
@@ -43,43 +65,242 @@ fn no_host_defined(client: Option<Res<MatchboxClient>>) -> bool {
     true
 }
 
-fn set_disconnected(mut state: ResMut<NextState<ClientState>>) {
+fn set_disconnected(mut commands: Commands, mut state: ResMut<NextState<ClientState>>) {
+    commands.remove_resource::<MatchboxConnectionPhase>();
     state.set(ClientState::Disconnected);
 }
 
-fn update_peers(mut client: ResMut<MatchboxClient>, mut commands: Commands) {
-    let Ok(peers) = client.socket.try_update_peers() else {
-        commands.remove_resource::<MatchboxClient>();
+fn clear_disconnect_reason(mut commands: Commands) {
+    commands.remove_resource::<ClientDisconnectReason>();
+}
+
+fn reset_channel_stats(mut channel_stats: ResMut<ChannelStats>) {
+    *channel_stats = Default::default();
+}
+
+/// Removes the client and keeps the reason around for [`ClientState::Disconnected`].
+fn drop_client(commands: &mut Commands, reason: DisconnectReason) {
+    commands.insert_resource(ClientDisconnectReason(reason));
+    commands.remove_resource::<MatchboxClient>();
+}
+
+fn reconnect(mut commands: Commands, mut client: ResMut<MatchboxClient>, time: Res<Time<Real>>) {
+    let client = &mut *client;
+    let Some(reconnection) = &mut client.reconnection else {
         return;
     };
 
+    let now = time.elapsed();
+    if let Some(retry_at) = reconnection.retry_at {
+        if now >= retry_at {
+            info!(
+                "reconnecting, attempt {}/{}",
+                reconnection.attempt, reconnection.policy.max_attempts
+            );
+            reconnection.retry_at = None;
+            reconnection.started_at = Some(now);
+            client.socket = client
+                .endpoint
+                .connect(&client.replicon_channels, &client.config);
+            client.reassembler = Reassembler::new(&client.config);
+        }
+    } else if let Some(started_at) = reconnection.started_at
+        && now - started_at > reconnection.policy.attempt_timeout
+    {
+        trace!("reconnection attempt {} timed out", reconnection.attempt);
+        if !client.schedule_reconnect(now) {
+            drop_client(&mut commands, DisconnectReason::ConnectionLost);
+        }
+    }
+}
+
+fn apply_network_conditions(
+    mut client: ResMut<MatchboxClient>,
+    conditions: Option<Res<NetworkConditions>>,
+    channels: Res<RepliconChannels>,
+) {
+    client.socket.condition(conditions.as_deref(), &channels);
+}
+
+fn update_peers(
+    mut client: ResMut<MatchboxClient>,
+    mut commands: Commands,
+    mut next_state: ResMut<NextState<ClientState>>,
+    mut lifecycle: LifecycleMessages,
+    time: Res<Time<Real>>,
+) {
+    if client.is_reconnect_scheduled() {
+        return;
+    }
+
+    let peers = match client.socket.try_update_peers() {
+        Ok(peers) => peers,
+        Err(error) => {
+            warn!("socket closed: {error}");
+            lifecycle.closed.write(SocketClosed);
+            lifecycle.errors.write(error);
+            if client.schedule_reconnect(time.elapsed()) {
+                if client.session.is_none() {
+                    next_state.set(ClientState::Connecting);
+                }
+                return;
+            }
+            error!("socket closed, disconnecting");
+            drop_client(&mut commands, DisconnectReason::SocketError);
+            return;
+        }
+    };
+    for &(peer_id, state) in &peers {
+        match state {
+            PeerState::Connected => {
+                lifecycle.joined.write(PeerJoined { peer_id });
+            }
+            PeerState::Disconnected => {
+                client.reassembler.remove_peer(peer_id);
+                client.fragmenter.remove_peer(peer_id);
+                lifecycle.left.write(PeerLeft {
+                    peer_id,
+                    client: None,
+                });
+            }
+        }
+    }
+
     let Some(host_peer_id) = client.host_peer_id else {
+        for (peer_id, state) in peers {
+            match state {
+                PeerState::Connected => {
+                    // Only the host answers, other clients in the room ignore it.
+                    trace!("sending hello to peer {}", peer_id);
+                    let packet = to_packet(&SystemChannelMessage::Hello(client.hello()));
+                    client.socket.send(SYSTEM_CHANNEL_ID, packet, peer_id);
+                }
+                #[cfg(feature = "server")]
+                PeerState::Disconnected => {
+                    if !client.successor_left(peer_id) {
+                        warn!("no successor left to migrate to");
+                        drop_client(&mut commands, DisconnectReason::ConnectionLost);
+                        return;
+                    }
+                }
+                #[cfg(not(feature = "server"))]
+                PeerState::Disconnected => {}
+            }
+        }
         return;
     };
     for (peer_id, state) in peers {
-        if matches!(state, PeerState::Disconnected) && peer_id != host_peer_id {
+        if matches!(state, PeerState::Disconnected) && peer_id == host_peer_id {
             trace!("host {} disconnected", peer_id);
-            commands.remove_resource::<MatchboxClient>();
+            #[cfg(feature = "server")]
+            if client.start_host_migration(peer_id, time.elapsed()) {
+                return;
+            }
+            if client.schedule_reconnect(time.elapsed()) {
+                if client.session.is_none() {
+                    // Without a session the host will see us as a new client.
+                    next_state.set(ClientState::Connecting);
+                }
+                return;
+            }
+            drop_client(&mut commands, DisconnectReason::ConnectionLost);
+            return;
+        }
+    }
+
+    let now = time.elapsed();
+    if client.monitor.timed_out(now) {
+        warn!("host {host_peer_id} timed out");
+        // The host may still be in the room, so it's not migrated away from.
+        if client.schedule_reconnect(now) {
+            if client.session.is_none() {
+                next_state.set(ClientState::Connecting);
+            }
             return;
         }
+        drop_client(&mut commands, DisconnectReason::Timeout);
+    }
+}
+
+/// Mirrors the phase into [`MatchboxConnectionPhase`] and enforces the phase timeouts.
+fn update_phase(
+    mut commands: Commands,
+    mut client: ResMut<MatchboxClient>,
+    mut next_state: ResMut<NextState<ClientState>>,
+    mut errors: MessageWriter<MatchboxBackendError>,
+    phase_resource: Option<ResMut<MatchboxConnectionPhase>>,
+    time: Res<Time<Real>>,
+) {
+    if client.is_reconnect_scheduled() {
+        return;
+    }
+    #[cfg(feature = "server")]
+    if client.is_migrating() {
+        // The migration has its own timeout.
+        return;
+    }
+
+    let now = time.elapsed();
+    let phase = client.current_phase();
+    if client.phase_started_at.is_none() || client.phase != phase {
+        debug!("entering connection phase {phase:?}");
+        client.phase = phase;
+        client.phase_started_at = Some(now);
+    }
+    match phase_resource {
+        Some(mut phase_resource) => {
+            phase_resource.set_if_neq(phase);
+        }
+        None => commands.insert_resource(phase),
+    }
+
+    let Some(&timeout) = client.phase_timeouts.get(&phase) else {
+        return;
+    };
+    let Some(started_at) = client.phase_started_at else {
+        return;
+    };
+    if phase == MatchboxConnectionPhase::Connected || now - started_at <= timeout {
+        return;
+    }
+
+    warn!("connection phase {phase:?} timed out after {timeout:?}");
+    errors.write(MatchboxBackendError::PhaseTimeout(phase));
+    if client.schedule_reconnect(now) {
+        if client.session.is_none() {
+            next_state.set(ClientState::Connecting);
+        }
+        return;
     }
+    drop_client(&mut commands, DisconnectReason::Timeout);
 }
 
 fn receive_system_channel_packets(
+    mut commands: Commands,
     mut client: ResMut<MatchboxClient>,
     mut state: ResMut<NextState<ClientState>>,
+    mut rejections: MessageWriter<HandshakeRejected>,
+    mut lifecycle: LifecycleMessages,
+    current_state: Res<State<ClientState>>,
+    time: Res<Time<Real>>,
 ) {
     if client.socket.all_channels_closed() {
         trace!("matchbox socket was closed");
         return;
     }
-    let Ok(channel) = client.socket.get_channel_mut(SYSTEM_CHANNEL_ID) else {
-        error!("system channel not found!");
-        return;
-    };
-    for (peer_id, packet) in channel.receive() {
+    for (peer_id, packet) in client.socket.receive(SYSTEM_CHANNEL_ID) {
+        if client.host_peer_id == Some(peer_id) {
+            client.monitor.received(time.elapsed());
+        }
         let Ok(message) = from_packet(&packet) else {
             error!("failed to deserialize system message {}", packet.len());
+            lifecycle.malformed.write(MalformedPacket {
+                peer_id,
+                client: None,
+                channel: SYSTEM_CHANNEL_ID,
+                len: packet.len(),
+                kind: MalformedPacketKind::Deserialization,
+            });
             continue;
         };
         trace!(
@@ -88,17 +309,78 @@ fn receive_system_channel_packets(
         );
 
         match message {
-            SystemChannelMessage::ConnectedToHost => {
+            SystemChannelMessage::ConnectedToHost(max_packet_size) => {
+                #[cfg(feature = "server")]
+                if client.on_migration_connected() {
+                    trace!("connected to new host {peer_id}");
+                }
                 client.host_peer_id = Some(peer_id);
-                state.set(ClientState::Connected);
+                // The host never goes above our limit, but a lower one could break fragmentation.
+                client.max_packet_size = (max_packet_size as usize)
+                    .clamp(MIN_PACKET_SIZE, client.config.max_packet_size());
+                lifecycle.host_assigned.write(HostAssigned { peer_id });
+                client.monitor = ConnectionMonitor::new(client.config.heartbeat());
+                if let Some(reconnection) = &mut client.reconnection {
+                    reconnection.attempt = 0;
+                    reconnection.started_at = None;
+                }
+                // A resumed session stays connected to keep the replicated state.
+                if *current_state != ClientState::Connected {
+                    state.set(ClientState::Connected);
+                }
+            }
+            SystemChannelMessage::SessionAssigned(token) => {
+                trace!("host assigned session {token:?}");
+                client.session = Some(token);
             }
-            SystemChannelMessage::HostRequestsDisconnect => {
-                info!("disconnected by server");
-                client.should_disconnect = true;
+            SystemChannelMessage::HostRequestsDisconnect(reason) => {
+                info!("disconnected by server: {reason}");
+                // Messages received this frame are still processed, the socket is closed on send.
+                client.disconnect_reason = Some(reason);
+                state.set(ClientState::Disconnected);
             }
 
-            SystemChannelMessage::ClientDisconnects => {
-                error!("Unexpected message received from host");
+            SystemChannelMessage::HandshakeRejected(rejection) => {
+                error!("host {peer_id} rejected the connection: {rejection}");
+                if rejection == HandshakeRejection::ChannelLayout {
+                    lifecycle
+                        .errors
+                        .write(MatchboxBackendError::ChannelLayoutMismatch);
+                }
+                client.socket.close();
+                drop_client(&mut commands, rejection.clone().into());
+                rejections.write(HandshakeRejected { peer_id, rejection });
+                return;
+            }
+            SystemChannelMessage::Hello(_) => {
+                trace!("ignoring hello from non-host peer {peer_id}");
+            }
+            #[cfg(feature = "server")]
+            SystemChannelMessage::Successors(successors) => {
+                client.set_successors(peer_id, successors);
+            }
+            #[cfg(feature = "server")]
+            SystemChannelMessage::NewHost => client.on_new_host(peer_id),
+            SystemChannelMessage::Ping(id) => {
+                let packet = to_packet(&SystemChannelMessage::Pong(id));
+                client.socket.send(SYSTEM_CHANNEL_ID, packet, peer_id);
+            }
+            SystemChannelMessage::Pong(id) if client.host_peer_id == Some(peer_id) => {
+                client.monitor.pong(id, time.elapsed());
+            }
+            SystemChannelMessage::Spectating(spectator) if client.host_peer_id == Some(peer_id) => {
+                debug!("host changed the role to spectator: {spectator}");
+                client.spectator = spectator;
+            }
+            message => {
+                error!("Unexpected message {message:?} received from peer {peer_id}");
+                lifecycle.malformed.write(MalformedPacket {
+                    peer_id,
+                    client: None,
+                    channel: SYSTEM_CHANNEL_ID,
+                    len: packet.len(),
+                    kind: MalformedPacketKind::UnexpectedMessage,
+                });
             }
         }
     }
@@ -107,35 +389,64 @@ fn receive_system_channel_packets(
 fn receive_packets(
     mut client: ResMut<MatchboxClient>,
     mut replicon_client: ResMut<ClientMessages>,
+    mut channel_stats: ResMut<ChannelStats>,
+    mut malformed: MessageWriter<MalformedPacket>,
     channels: Res<RepliconChannels>,
+    time: Res<Time<Real>>,
 ) {
     if client.socket.all_channels_closed() {
         trace!("matchbox socket was closed");
         return;
     }
 
-    for (channel_id, _) in channels.server_channels().iter().enumerate() {
+    let client = &mut *client;
+    let now = time.elapsed();
+    for (channel_id, &channel) in channels.server_channels().iter().enumerate() {
         //server socket channels are the same as the channel id +1 for the system channel
         let socket_channel_id = 1 + channel_id;
-        let Ok(channel) = client.socket.get_channel_mut(socket_channel_id) else {
-            continue;
-        };
-        for (id, packet) in channel.receive() {
+        let reliable = channel != Channel::Unreliable;
+        for (id, packet) in client.socket.receive(socket_channel_id) {
             trace!(
                 "client received packet from peer {}, c:{} size {}",
                 id,
                 channel_id,
                 packet.len()
             );
-            replicon_client.insert_received(channel_id, strip_marker(packet.as_ref()));
+            channel_stats.record_received(channel_id, packet.len());
+            client.monitor.received(now);
+            let Some((message, compressed)) = client
+                .reassembler
+                .receive(id, channel_id, reliable, packet, now)
+            else {
+                continue;
+            };
+            let len = message.len();
+            match client
+                .codec
+                .decompress(channel_id, message, compressed, &mut channel_stats)
+            {
+                Some(message) => replicon_client.insert_received(channel_id, message),
+                None => {
+                    malformed.write(MalformedPacket {
+                        peer_id: id,
+                        client: None,
+                        channel: socket_channel_id,
+                        len,
+                        kind: MalformedPacketKind::Decompression,
+                    });
+                }
+            }
         }
     }
+    client.reassembler.expire(now);
 }
 
 fn send_packets(
+    mut commands: Commands,
     mut client: ResMut<MatchboxClient>,
     mut replicon_client: ResMut<ClientMessages>,
     mut state: ResMut<NextState<ClientState>>,
+    mut channel_stats: ResMut<ChannelStats>,
     channels: Res<RepliconChannels>,
 ) {
     if client.socket.any_channel_closed() {
@@ -150,38 +461,346 @@ fn send_packets(
     for (channel_id, message) in replicon_client.drain_sent() {
         //client socket channels are offset by the server channel length + 1 for the system channel
         let socket_channel_id = 1 + channels.server_channels().len() + channel_id;
-        client
-            .socket
-            .channel_mut(socket_channel_id)
-            .send(add_marker(message.as_ref()), host_peer_id);
+        let client = &mut *client;
+        let (message, compressed) = client
+            .codec
+            .compress(channel_id, message, &mut channel_stats);
+        client.fragmenter.split(
+            host_peer_id,
+            channel_id,
+            message,
+            compressed,
+            client.max_packet_size,
+            |packet| {
+                channel_stats.record_sent(channel_id, packet.len());
+                client.socket.send(socket_channel_id, packet, host_peer_id)
+            },
+        );
     }
 
-    if client.should_disconnect {
+    if let Some(reason) = client.disconnect_reason.take() {
+        // Intentional disconnects are final.
+        client.reconnection = None;
         client.socket.close();
         client.host_peer_id = None;
-        client.should_disconnect = false;
+        commands.insert_resource(ClientDisconnectReason(reason));
         state.set(ClientState::Disconnected);
     }
 }
 
+/// Pings the host and fills [`ClientStats`].
+fn update_stats(
+    mut client: ResMut<MatchboxClient>,
+    mut stats: ResMut<ClientStats>,
+    channel_stats: Res<ChannelStats>,
+    time: Res<Time<Real>>,
+) {
+    let Some(host_peer_id) = client.host_peer_id else {
+        return;
+    };
+    let now = time.elapsed();
+    if let Some(ping) = client.monitor.ping(now) {
+        client
+            .socket
+            .send(SYSTEM_CHANNEL_ID, to_packet(&ping), host_peer_id);
+    }
+    client.monitor.update(now, &channel_stats, &mut stats);
+}
+
 #[derive(Resource)]
 pub struct MatchboxClient {
-    pub socket: MatchboxSocket,
+    /// Connection to the host, over WebRTC or a [`LoopbackNetwork`].
+    pub socket: Transport,
     pub host_peer_id: Option<PeerId>,
-    should_disconnect: bool,
+    disconnect_reason: Option<DisconnectReason>,
+    game_version: String,
+    channels_hash: u64,
+    auth_token: Vec<u8>,
+    identity: String,
+    spectator: bool,
+    endpoint: Endpoint,
+    replicon_channels: RepliconChannels,
+    config: MatchboxBackendConfig,
+    /// Largest packet sent to the host, negotiated during the handshake.
+    max_packet_size: usize,
+    fragmenter: Fragmenter,
+    reassembler: Reassembler,
+    codec: Codec,
+    monitor: ConnectionMonitor,
+    session: Option<SessionToken>,
+    reconnection: Option<Reconnection>,
+    phase: MatchboxConnectionPhase,
+    /// Time at which the current phase started, `None` until the first update.
+    phase_started_at: Option<Duration>,
+    phase_timeouts: HashMap<MatchboxConnectionPhase, Duration>,
+    #[cfg(feature = "server")]
+    host_migration: Option<migration::HostMigration>,
+}
+
+/// Controls how [`MatchboxClient`] retries after losing the host, see [`MatchboxClient::with_reconnect`].
+#[derive(Debug, Clone, Copy)]
+pub struct ReconnectPolicy {
+    /// Attempts before giving up with [`DisconnectReason::ConnectionLost`].
+    pub max_attempts: u32,
+    /// Delay before the first attempt.
+    pub initial_delay: Duration,
+    /// Upper bound for the delay between attempts.
+    pub max_delay: Duration,
+    /// Multiplier applied to the delay after each failed attempt.
+    pub backoff_factor: f32,
+    /// Time an attempt may take to reach the host before it is considered failed.
+    pub attempt_timeout: Duration,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 5,
+            initial_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(10),
+            backoff_factor: 2.0,
+            attempt_timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+impl ReconnectPolicy {
+    /// Returns the delay before the given attempt, starting at 1.
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let exponent = attempt.saturating_sub(1).min(i32::MAX as u32) as i32;
+        let secs = self.initial_delay.as_secs_f64() * f64::from(self.backoff_factor).powi(exponent);
+        Duration::try_from_secs_f64(secs).map_or(self.max_delay, |delay| delay.min(self.max_delay))
+    }
+}
+
+/// Where a [`Transport`] connects to, kept around to reconnect.
+#[derive(Clone)]
+enum Endpoint {
+    Room(String),
+    Loopback(LoopbackNetwork),
+}
+
+impl Endpoint {
+    fn connect(
+        &self,
+        replicon_channels: &RepliconChannels,
+        config: &MatchboxBackendConfig,
+    ) -> Transport {
+        match self {
+            Self::Room(room_url) => create_matchbox_socket(room_url, replicon_channels, config),
+            Self::Loopback(network) => network.join(replicon_channels).into(),
+        }
+    }
+}
+
+struct Reconnection {
+    policy: ReconnectPolicy,
+    attempt: u32,
+    /// Time of the next attempt, `None` if no attempt is scheduled.
+    retry_at: Option<Duration>,
+    /// Time at which the current attempt started, `None` if connected.
+    started_at: Option<Duration>,
 }
 
 impl MatchboxClient {
+    /// Joins the matchbox room at `room_url`, which can also be a [`RoomAddress`].
+    ///
+    /// Fails with [`MatchboxBackendError::InvalidUrl`] if the URL can't reach a signaling server.
     pub fn new(
         room_url: impl Into<String>,
         replicon_channels: &RepliconChannels,
-    ) -> io::Result<Self> {
-        let socket = create_matchbox_socket(room_url, replicon_channels);
-        Ok(Self {
-            socket,
+        config: MatchboxBackendConfig,
+    ) -> Result<Self, MatchboxBackendError> {
+        let room_url = room_url.into();
+        validate_room_url(&room_url)?;
+        Ok(Self::with_endpoint(
+            Endpoint::Room(room_url),
+            replicon_channels,
+            config,
+        ))
+    }
+
+    /// Creates a client on a [`LoopbackNetwork`] instead of a signaling server room.
+    ///
+    /// Reconnects join the same network again.
+    pub fn loopback(
+        network: &LoopbackNetwork,
+        replicon_channels: &RepliconChannels,
+        config: MatchboxBackendConfig,
+    ) -> Self {
+        Self::with_endpoint(
+            Endpoint::Loopback(network.clone()),
+            replicon_channels,
+            config,
+        )
+    }
+
+    fn with_endpoint(
+        endpoint: Endpoint,
+        replicon_channels: &RepliconChannels,
+        config: MatchboxBackendConfig,
+    ) -> Self {
+        Self {
+            socket: endpoint.connect(replicon_channels, &config),
             host_peer_id: None,
-            should_disconnect: false,
-        })
+            disconnect_reason: None,
+            game_version: String::new(),
+            channels_hash: channels_hash(replicon_channels, &config),
+            auth_token: Vec::new(),
+            identity: String::new(),
+            spectator: false,
+            endpoint,
+            replicon_channels: replicon_channels.clone(),
+            reassembler: Reassembler::new(&config),
+            max_packet_size: config.max_packet_size(),
+            fragmenter: Fragmenter::default(),
+            codec: Codec::client(&config),
+            monitor: ConnectionMonitor::new(config.heartbeat()),
+            config,
+            session: None,
+            reconnection: None,
+            phase: MatchboxConnectionPhase::Signaling,
+            phase_started_at: None,
+            phase_timeouts: HashMap::new(),
+            #[cfg(feature = "server")]
+            host_migration: None,
+        }
+    }
+
+    /// Rebuilds the socket according to `policy` when the connection to the host is lost.
+    ///
+    /// If the host enabled `MatchboxHost::with_session_resumption`, the client resumes its session and
+    /// stays in [`ClientState::Connected`] while reconnecting. Otherwise it goes back to
+    /// [`ClientState::Connecting`] and joins as a new client.
+    pub fn with_reconnect(mut self, policy: ReconnectPolicy) -> Self {
+        self.reconnection = Some(Reconnection {
+            policy,
+            attempt: 0,
+            retry_at: None,
+            started_at: None,
+        });
+        self
+    }
+
+    /// Returns `true` while the client is trying to get back to the host.
+    pub fn is_reconnecting(&self) -> bool {
+        self.reconnection
+            .as_ref()
+            .is_some_and(|reconnection| reconnection.attempt > 0)
+    }
+
+    fn is_reconnect_scheduled(&self) -> bool {
+        self.reconnection
+            .as_ref()
+            .is_some_and(|reconnection| reconnection.retry_at.is_some())
+    }
+
+    /// Closes the socket and schedules the next attempt, returns `false` if there are none left.
+    fn schedule_reconnect(&mut self, now: Duration) -> bool {
+        let Some(reconnection) = &mut self.reconnection else {
+            return false;
+        };
+        if reconnection.attempt >= reconnection.policy.max_attempts {
+            return false;
+        }
+
+        reconnection.attempt += 1;
+        let delay = reconnection.policy.delay(reconnection.attempt);
+        trace!("scheduling reconnection in {delay:?}");
+        reconnection.retry_at = Some(now + delay);
+        reconnection.started_at = None;
+        self.socket.close();
+        self.host_peer_id = None;
+        // The next socket starts over from the first phase.
+        self.phase_started_at = None;
+        true
+    }
+
+    /// Gives up if the client stays in `phase` for longer than `timeout`.
+    ///
+    /// Writes [`MatchboxBackendError::PhaseTimeout`], then reconnects if [`Self::with_reconnect`] is set
+    /// or disconnects with [`DisconnectReason::Timeout`]. Phases have no timeout by default and the
+    /// timeout of [`MatchboxConnectionPhase::Connected`] is ignored, see
+    /// `MatchboxBackendConfig::with_idle_timeout` instead.
+    pub fn with_phase_timeout(mut self, phase: MatchboxConnectionPhase, timeout: Duration) -> Self {
+        self.phase_timeouts.insert(phase, timeout);
+        self
+    }
+
+    /// Returns how far the client got towards the host, also available as a resource.
+    pub fn phase(&self) -> MatchboxConnectionPhase {
+        self.phase
+    }
+
+    fn current_phase(&mut self) -> MatchboxConnectionPhase {
+        if self.host_peer_id.is_some() {
+            MatchboxConnectionPhase::Connected
+        } else if self.socket.id().is_none() {
+            MatchboxConnectionPhase::Signaling
+        } else if !self.socket.connected_peers().is_empty() {
+            MatchboxConnectionPhase::Handshake
+        } else {
+            MatchboxConnectionPhase::WaitingForHost
+        }
+    }
+
+    /// Sets the game version presented to the host during the handshake.
+    ///
+    /// Must match the version passed to `MatchboxHost::with_game_version`.
+    pub fn with_game_version(mut self, game_version: impl Into<String>) -> Self {
+        self.game_version = game_version.into();
+        self
+    }
+
+    /// Attaches an opaque token presented to the host authenticator during the handshake.
+    pub fn with_auth_token(mut self, token: impl Into<Vec<u8>>) -> Self {
+        self.auth_token = token.into();
+        self
+    }
+
+    /// Sets a stable identity presented to the host during the handshake, for example an account id.
+    ///
+    /// The host can ban it with `BanTarget::Identity`, validate it in the authenticator if it matters.
+    pub fn with_identity(mut self, identity: impl Into<String>) -> Self {
+        self.identity = identity.into();
+        self
+    }
+
+    /// Joins as a spectator, which receives replication but whose client messages are dropped by the host.
+    ///
+    /// Spectators don't count against `MatchboxHost::with_max_clients`. The host can turn them into players
+    /// with `MatchboxHost::promote`.
+    pub fn with_spectator(mut self, spectator: bool) -> Self {
+        self.spectator = spectator;
+        self
+    }
+
+    /// Changes the largest packet announced to the host on the next handshake, for example before
+    /// reconnecting over a path with a smaller MTU.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `size` is below [`MIN_PACKET_SIZE`].
+    pub fn set_max_packet_size(&mut self, size: usize) {
+        self.config = mem::take(&mut self.config).with_max_packet_size(size);
+    }
+
+    /// Returns `true` if the host currently treats the client as a spectator.
+    pub fn is_spectator(&self) -> bool {
+        self.spectator
+    }
+
+    fn hello(&self) -> ClientHello {
+        ClientHello {
+            protocol_version: PROTOCOL_VERSION,
+            game_version: self.game_version.clone(),
+            channels_hash: self.channels_hash,
+            auth_token: self.auth_token.clone(),
+            identity: self.identity.clone(),
+            session: self.session,
+            max_packet_size: u32::try_from(self.config.max_packet_size()).unwrap_or(u32::MAX),
+            spectator: self.spectator,
+        }
     }
 
     pub fn is_connected(&self) -> bool {
@@ -189,16 +808,35 @@ impl MatchboxClient {
     }
 
     pub fn disconnect(&mut self) {
-        let Ok(channel) = self.socket.get_channel_mut(SYSTEM_CHANNEL_ID) else {
-            return;
-        };
         let Some(host_peer) = self.host_peer_id else {
             return;
         };
         trace!("sending disconnect message to host");
-        let mut buf = [0u8; 1];
-        let package = to_packet(&SystemChannelMessage::ClientDisconnects, &mut buf).into();
-        channel.send(package, host_peer);
-        self.should_disconnect = true;
+        let package = to_packet(&SystemChannelMessage::ClientDisconnects);
+        self.socket.send(SYSTEM_CHANNEL_ID, package, host_peer);
+        self.disconnect_reason = Some(DisconnectReason::Left);
     }
 }
+
+/// Why the client was last disconnected, inserted when it enters [`ClientState::Disconnected`].
+///
+/// Removed once a new [`MatchboxClient`] is inserted. Missing if the resource was removed manually.
+#[derive(Resource, Deref, Debug, Clone, PartialEq, Eq)]
+pub struct ClientDisconnectReason(pub DisconnectReason);
+
+#[test]
+fn test_reconnect_backoff() {
+    let policy = ReconnectPolicy {
+        max_attempts: 10,
+        initial_delay: Duration::from_millis(100),
+        max_delay: Duration::from_secs(1),
+        backoff_factor: 2.0,
+        attempt_timeout: Duration::from_secs(5),
+    };
+
+    assert_eq!(policy.delay(1), Duration::from_millis(100));
+    assert_eq!(policy.delay(2), Duration::from_millis(200));
+    assert_eq!(policy.delay(4), Duration::from_millis(800));
+    assert_eq!(policy.delay(5), Duration::from_secs(1));
+    assert_eq!(policy.delay(u32::MAX), Duration::from_secs(1));
+}
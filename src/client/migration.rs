@@ -0,0 +1,436 @@
+use super::{ClientDisconnectReason, MatchboxClient};
+use crate::server::MatchboxHost;
+use crate::shared::*;
+use bevy::prelude::*;
+use bevy_matchbox::matchbox_socket::PeerId;
+use bevy_replicon::prelude::*;
+use std::any::Any;
+use std::sync::Arc;
+use std::time::Duration;
+
+const DEFAULT_MIGRATION_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Sent on every peer once a host migration completes, see [`MatchboxClient::with_host_migration`].
+#[derive(Message, Debug, Clone)]
+pub struct HostMigrated {
+    pub previous_host: PeerId,
+    pub new_host: PeerId,
+    /// `true` on the peer that took over as host.
+    pub is_new_host: bool,
+}
+
+type Snapshot = Box<dyn Any + Send + Sync>;
+type SnapshotHook = Box<dyn Fn(&mut World) -> Snapshot + Send + Sync>;
+type RestoreHook = Box<dyn Fn(&mut World, Snapshot) + Send + Sync>;
+type HostSetup = dyn Fn(MatchboxHost) -> MatchboxHost + Send + Sync;
+
+struct MigrationHooks {
+    snapshot: SnapshotHook,
+    restore: RestoreHook,
+}
+
+pub(super) struct HostMigration {
+    timeout: Duration,
+    hooks: Option<Arc<MigrationHooks>>,
+    /// Applied to the host this client becomes, see [`MatchboxClient::with_migration_host`].
+    host_setup: Option<Arc<HostSetup>>,
+    /// Order announced by the host, the first peer still connected takes over.
+    successors: Vec<PeerId>,
+    /// Peer that announced itself as the new host, possibly before we noticed the old one left.
+    announced_host: Option<PeerId>,
+    pending: Option<PendingMigration>,
+}
+
+impl Default for HostMigration {
+    fn default() -> Self {
+        Self {
+            timeout: DEFAULT_MIGRATION_TIMEOUT,
+            hooks: None,
+            host_setup: None,
+            successors: Vec::new(),
+            announced_host: None,
+            pending: None,
+        }
+    }
+}
+
+struct PendingMigration {
+    previous_host: PeerId,
+    successor: PeerId,
+    started_at: Duration,
+    phase: MigrationPhase,
+    snapshot: Option<Snapshot>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum MigrationPhase {
+    /// A successor was picked, the peer still needs to take over or reset its replicated state.
+    Started,
+    /// Waiting for the successor to accept the handshake.
+    AwaitingHost,
+    /// The successor accepted us, the snapshot still needs to be restored.
+    Connected,
+}
+
+impl MatchboxClient {
+    /// Lets the peers pick a new host among themselves when the host leaves, instead of disconnecting.
+    ///
+    /// The host announces the order in which its clients take over, lowest [`PeerId`] first. The successor
+    /// turns its socket into a [`MatchboxHost`] and the other clients handshake with it without rejoining
+    /// the signaling room, so the room must use a full mesh signaling server where clients are also
+    /// connected to each other. Replicated entities are kept on the new host and despawned on the other
+    /// clients, which receive them again from the new host.
+    ///
+    /// Clients that don't reach the new host within `timeout` are disconnected with
+    /// [`DisconnectReason::ConnectionLost`].
+    pub fn with_host_migration(mut self, timeout: Duration) -> Self {
+        self.host_migration.get_or_insert_default().timeout = timeout;
+        self
+    }
+
+    /// Calls `snapshot` when the host leaves and `restore` with its result once the migration completes.
+    ///
+    /// Use it to carry state that is lost when the replicated entities are despawned. Enables
+    /// [`Self::with_host_migration`] with a 10 seconds timeout if it wasn't already.
+    pub fn with_migration_hooks<S: Send + Sync + 'static>(
+        mut self,
+        snapshot: impl Fn(&mut World) -> S + Send + Sync + 'static,
+        restore: impl Fn(&mut World, S) + Send + Sync + 'static,
+    ) -> Self {
+        let hooks = MigrationHooks {
+            snapshot: Box::new(move |world| Box::new(snapshot(world))),
+            restore: Box::new(move |world, snapshot| {
+                let snapshot = snapshot
+                    .downcast()
+                    .expect("snapshot should have the type returned by the hook");
+                restore(world, *snapshot)
+            }),
+        };
+        self.host_migration.get_or_insert_default().hooks = Some(Arc::new(hooks));
+        self
+    }
+
+    /// Configures the [`MatchboxHost`] this client becomes if it takes over.
+    ///
+    /// Only the game version is carried over by default, use it to set the same max clients,
+    /// authenticator, ban list or session resumption as the original host. Enables
+    /// [`Self::with_host_migration`] with a 10 seconds timeout if it wasn't already.
+    pub fn with_migration_host(
+        mut self,
+        setup: impl Fn(MatchboxHost) -> MatchboxHost + Send + Sync + 'static,
+    ) -> Self {
+        self.host_migration.get_or_insert_default().host_setup = Some(Arc::new(setup));
+        self
+    }
+
+    /// Returns `true` while the client is switching to a new host.
+    pub fn is_migrating(&self) -> bool {
+        self.host_migration
+            .as_ref()
+            .is_some_and(|migration| migration.pending.is_some())
+    }
+
+    pub(super) fn set_successors(&mut self, peer_id: PeerId, successors: Vec<PeerId>) {
+        if self.host_peer_id != Some(peer_id) {
+            warn!("ignoring successors from non-host peer {peer_id}");
+            return;
+        }
+        if let Some(migration) = &mut self.host_migration {
+            migration.successors = successors;
+        }
+    }
+
+    /// Picks a successor for `previous_host`, returns `false` if migration is disabled or nobody is left.
+    pub(super) fn start_host_migration(&mut self, previous_host: PeerId, now: Duration) -> bool {
+        let Some(successor) = self.next_successor(previous_host) else {
+            return false;
+        };
+        let Some(migration) = &mut self.host_migration else {
+            return false;
+        };
+
+        info!("host {previous_host} left, migrating to {successor}");
+        migration.pending = Some(PendingMigration {
+            previous_host,
+            successor,
+            started_at: now,
+            phase: MigrationPhase::Started,
+            snapshot: None,
+        });
+        // The new host knows nothing about sessions of the previous one.
+        self.session = None;
+        self.host_peer_id = None;
+        true
+    }
+
+    /// Moves on to the next successor if the current one left, returns `false` if nobody is left.
+    pub(super) fn successor_left(&mut self, peer_id: PeerId) -> bool {
+        let Some(migration) = &mut self.host_migration else {
+            return true;
+        };
+        let Some(pending) = &migration.pending else {
+            return true;
+        };
+        if pending.successor != peer_id {
+            return true;
+        }
+
+        let previous_host = pending.previous_host;
+        migration
+            .successors
+            .retain(|&successor| successor != peer_id);
+        if migration.announced_host == Some(peer_id) {
+            migration.announced_host = None;
+        }
+        let Some(successor) = self.next_successor(previous_host) else {
+            return false;
+        };
+
+        info!("successor {peer_id} left, migrating to {successor}");
+        let pending = self
+            .host_migration
+            .as_mut()
+            .and_then(|migration| migration.pending.as_mut())
+            .expect("migration should be pending");
+        pending.successor = successor;
+        pending.phase = MigrationPhase::Started;
+        true
+    }
+
+    pub(super) fn on_new_host(&mut self, peer_id: PeerId) {
+        let Some(migration) = &mut self.host_migration else {
+            warn!("peer {peer_id} announced itself as host, but migration is disabled");
+            return;
+        };
+        if !migration.successors.contains(&peer_id) {
+            warn!("ignoring host claim from {peer_id}, which isn't a successor");
+            return;
+        }
+        migration.announced_host = Some(peer_id);
+        let Some(pending) = &mut migration.pending else {
+            return;
+        };
+        if pending.phase != MigrationPhase::AwaitingHost {
+            return;
+        }
+
+        if pending.successor != peer_id {
+            warn!(
+                "ignoring host claim from {peer_id}, expected {} to take over",
+                pending.successor
+            );
+            return;
+        }
+        self.send_hello(peer_id);
+    }
+
+    /// Returns `true` if the host that accepted us completes a migration.
+    pub(super) fn on_migration_connected(&mut self) -> bool {
+        let Some(pending) = self
+            .host_migration
+            .as_mut()
+            .and_then(|migration| migration.pending.as_mut())
+        else {
+            return false;
+        };
+        if pending.phase != MigrationPhase::AwaitingHost {
+            return false;
+        }
+
+        pending.phase = MigrationPhase::Connected;
+        true
+    }
+
+    fn next_successor(&mut self, previous_host: PeerId) -> Option<PeerId> {
+        let own_id = self.socket.id();
+        let connected = self.socket.connected_peers();
+        let migration = self.host_migration.as_ref()?;
+        let is_reachable =
+            |peer_id: PeerId| Some(peer_id) == own_id || connected.contains(&peer_id);
+
+        // Every peer picks the same one, claims from the others are ignored.
+        migration
+            .successors
+            .iter()
+            .copied()
+            .find(|&peer_id| peer_id != previous_host && is_reachable(peer_id))
+    }
+
+    fn send_hello(&mut self, peer_id: PeerId) {
+        trace!("sending hello to peer {}", peer_id);
+        let packet = to_packet(&SystemChannelMessage::Hello(self.hello()));
+        self.socket.send(SYSTEM_CHANNEL_ID, packet, peer_id);
+    }
+}
+
+/// Advances a pending migration, exclusive to let the hooks access the whole world.
+pub(super) fn migrate_host(world: &mut World) {
+    let now = world.resource::<Time<Real>>().elapsed();
+    let Some(mut client) = world.get_resource_mut::<MatchboxClient>() else {
+        return;
+    };
+    let own_id = client.socket.id();
+    let Some(migration) = &mut client.host_migration else {
+        return;
+    };
+    let timeout = migration.timeout;
+    let hooks = migration.hooks.clone();
+    let host_setup = migration.host_setup.clone();
+    let Some(pending) = &mut migration.pending else {
+        return;
+    };
+    let previous_host = pending.previous_host;
+    let successor = pending.successor;
+
+    match pending.phase {
+        MigrationPhase::Started => {
+            if pending.snapshot.is_none()
+                && let Some(hooks) = &hooks
+            {
+                let snapshot = (hooks.snapshot)(world);
+                pending_migration(world).snapshot = Some(snapshot);
+            }
+
+            if Some(successor) == own_id {
+                become_host(world, previous_host, successor, hooks, host_setup);
+            } else {
+                await_host(world, successor);
+            }
+        }
+        MigrationPhase::AwaitingHost => {
+            if now - pending.started_at > timeout {
+                warn!("new host {successor} didn't accept us in time");
+                world.insert_resource(ClientDisconnectReason(DisconnectReason::ConnectionLost));
+                world.remove_resource::<MatchboxClient>();
+            }
+        }
+        MigrationPhase::Connected => {
+            let snapshot = pending.snapshot.take();
+            migration.pending = None;
+            migration.announced_host = None;
+            info!("migrated from host {previous_host} to {successor}");
+            if let Some(hooks) = hooks
+                && let Some(snapshot) = snapshot
+            {
+                (hooks.restore)(world, snapshot);
+            }
+            world.write_message(HostMigrated {
+                previous_host,
+                new_host: successor,
+                is_new_host: false,
+            });
+        }
+    }
+}
+
+fn become_host(
+    world: &mut World,
+    previous_host: PeerId,
+    successor: PeerId,
+    hooks: Option<Arc<MigrationHooks>>,
+    host_setup: Option<Arc<HostSetup>>,
+) {
+    let snapshot = pending_migration(world).snapshot.take();
+    let client = world
+        .remove_resource::<MatchboxClient>()
+        .expect("client should exist during migration");
+    info!("taking over as host from {previous_host}");
+
+    let mut host = MatchboxHost::from_socket(client.socket, client.channels_hash, &client.config)
+        .with_game_version(client.game_version);
+    if let Some(setup) = host_setup {
+        host = setup(host);
+    }
+    let packet = to_packet(&SystemChannelMessage::NewHost);
+    for peer_id in host.socket.connected_peers() {
+        host.socket.send(SYSTEM_CHANNEL_ID, packet.clone(), peer_id);
+    }
+    world.insert_resource(host);
+
+    if let Some(hooks) = hooks
+        && let Some(snapshot) = snapshot
+    {
+        (hooks.restore)(world, snapshot);
+    }
+    world.write_message(HostMigrated {
+        previous_host,
+        new_host: successor,
+        is_new_host: true,
+    });
+}
+
+fn await_host(world: &mut World, successor: PeerId) {
+    // The new host replicates them again once we are connected.
+    let replicated: Vec<_> = world
+        .query_filtered::<Entity, With<Replicated>>()
+        .iter(world)
+        .collect();
+    for entity in replicated {
+        // Children may already be gone with their parent.
+        let _ = world.try_despawn(entity);
+    }
+    world
+        .resource_mut::<NextState<ClientState>>()
+        .set(ClientState::Connecting);
+
+    let mut client = world.resource_mut::<MatchboxClient>();
+    let migration = client
+        .host_migration
+        .as_mut()
+        .expect("migration should be enabled");
+    let announced = migration.announced_host == Some(successor);
+    migration
+        .pending
+        .as_mut()
+        .expect("migration should be pending")
+        .phase = MigrationPhase::AwaitingHost;
+    if announced {
+        client.send_hello(successor);
+    }
+}
+
+fn pending_migration(world: &mut World) -> Mut<'_, PendingMigration> {
+    world
+        .resource_mut::<MatchboxClient>()
+        .map_unchanged(|client| {
+            client
+                .host_migration
+                .as_mut()
+                .and_then(|migration| migration.pending.as_mut())
+                .expect("migration should be pending")
+        })
+}
+
+#[test]
+fn test_host_claim() {
+    use uuid::Uuid;
+
+    let network = LoopbackNetwork::default();
+    let mut client = MatchboxClient::loopback(
+        &network,
+        &RepliconChannels::default(),
+        MatchboxBackendConfig::default(),
+    )
+    .with_host_migration(DEFAULT_MIGRATION_TIMEOUT);
+    let [host, successor, next, intruder] = [1, 2, 3, 4].map(|id| PeerId(Uuid::from_u128(id)));
+    client.host_peer_id = Some(host);
+    client.set_successors(host, vec![successor, next]);
+
+    client.on_new_host(intruder);
+    let migration = client.host_migration.as_mut().unwrap();
+    assert_eq!(
+        migration.announced_host, None,
+        "should ignore non-successors"
+    );
+
+    migration.pending = Some(PendingMigration {
+        previous_host: host,
+        successor,
+        started_at: Duration::ZERO,
+        phase: MigrationPhase::AwaitingHost,
+        snapshot: None,
+    });
+    client.on_new_host(next);
+    let pending = client.host_migration.as_ref().unwrap().pending.as_ref();
+    assert_eq!(pending.map(|pending| pending.successor), Some(successor));
+}
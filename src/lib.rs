@@ -1,14 +1,37 @@
 #[cfg(feature = "client")]
 mod client;
+#[cfg(any(feature = "client", feature = "server"))]
+mod lobby;
+#[cfg(all(feature = "client", feature = "server"))]
+mod matchmaking;
 #[cfg(feature = "server")]
 mod server;
 #[cfg(any(feature = "client", feature = "server"))]
 pub mod shared;
+#[cfg(all(feature = "signaling", not(target_arch = "wasm32")))]
+mod signaling;
 
 #[cfg(feature = "client")]
 pub use client::*;
+#[cfg(any(feature = "client", feature = "server"))]
+pub use lobby::{LOBBY_PATH, LobbyBrowser, LobbyRooms, RepliconMatchboxLobbyPlugin, RoomInfo};
+#[cfg(all(feature = "client", feature = "server"))]
+pub use matchmaking::*;
 #[cfg(feature = "server")]
 pub use server::*;
+#[cfg(all(feature = "signaling", not(target_arch = "wasm32")))]
+pub use signaling::*;
 
+#[cfg(all(feature = "lz4_flex", any(feature = "client", feature = "server")))]
+pub use shared::Lz4Compressor;
+#[cfg(all(feature = "zstd", any(feature = "client", feature = "server")))]
+pub use shared::ZstdCompressor;
 #[cfg(any(feature = "client", feature = "server"))]
-pub use shared::RepliconMatchboxPlugins;
+pub use shared::{
+    ChannelCompression, ChannelStats, ChannelTraffic, DirectionConditions, DisconnectReason,
+    HandshakeRejected, HandshakeRejection, HostAssigned, LinkConditions, LoopbackNetwork,
+    LoopbackSocket, MIN_PACKET_SIZE, MalformedPacket, MalformedPacketKind, MatchboxBackendConfig,
+    MatchboxBackendError, MatchboxConnectionPhase, NetworkConditions, PROTOCOL_VERSION,
+    PacketCompressor, PeerJoined, PeerLeft, RepliconMatchboxPlugins, RoomAddress, RoomCode,
+    RoomCodeError, SessionToken, SocketClosed, Transport, TurnCredentials,
+};
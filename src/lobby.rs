@@ -0,0 +1,385 @@
+use bevy::prelude::*;
+use bevy::tasks::{IoTaskPool, Task};
+use futures::channel::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use futures::{StreamExt, select};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+#[cfg(feature = "server")]
+use std::time::Duration;
+
+/// Path of the lobby registry on the signaling server, a room with this name can't be joined.
+pub const LOBBY_PATH: &str = "_lobby";
+
+/// Keeps [`LobbyRooms`] in sync with the [`LobbyBrowser`] resource.
+///
+/// Part of [`RepliconMatchboxPlugins`](crate::RepliconMatchboxPlugins).
+pub struct RepliconMatchboxLobbyPlugin;
+
+impl Plugin for RepliconMatchboxLobbyPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<LobbyRooms>().add_systems(
+            PreUpdate,
+            (
+                clear_lobby_rooms.run_if(resource_removed::<LobbyBrowser>),
+                receive_lobby_rooms.run_if(resource_exists::<LobbyBrowser>),
+            ),
+        );
+    }
+}
+
+fn clear_lobby_rooms(mut rooms: ResMut<LobbyRooms>) {
+    rooms.0.clear();
+}
+
+fn receive_lobby_rooms(mut browser: ResMut<LobbyBrowser>, mut rooms: ResMut<LobbyRooms>) {
+    let mut latest = None;
+    while let Ok(LobbyResponse::Rooms(list)) = browser.responses.try_recv() {
+        latest = Some(list);
+    }
+    let Some(list) = latest else {
+        return;
+    };
+
+    trace!("received {} rooms from the lobby", list.len());
+    rooms.0 = list
+        .into_iter()
+        .filter(|room| browser.is_joinable(room))
+        .collect();
+}
+
+/// Metadata of a room published to the lobby registry of the signaling server.
+///
+/// When advertised with `MatchboxHost::with_lobby`, the host keeps [`Self::players`],
+/// [`Self::max_players`] and [`Self::version`] up to date.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RoomInfo {
+    /// Room to pass to [`RoomAddress::new`](crate::RoomAddress::new) to join.
+    pub room: String,
+    /// Name shown to players.
+    pub name: String,
+    /// Connected players, the host and spectators excluded.
+    pub players: usize,
+    pub max_players: Option<usize>,
+    /// Game version passed to `MatchboxHost::with_game_version`.
+    pub version: String,
+    /// Whether joining needs a password, checked by the host authenticator.
+    pub password: bool,
+    /// Game-specific values such as the map or mode.
+    pub properties: BTreeMap<String, String>,
+}
+
+impl RoomInfo {
+    pub fn new(name: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            ..Default::default()
+        }
+    }
+
+    pub fn with_password(mut self, password: bool) -> Self {
+        self.password = password;
+        self
+    }
+
+    pub fn with_property(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.properties.insert(key.into(), value.into());
+        self
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.max_players
+            .is_some_and(|max_players| self.players >= max_players)
+    }
+}
+
+/// Sent by peers to the lobby registry as JSON.
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) enum LobbyRequest {
+    /// Publishes or updates the room of the host, removed once the connection closes.
+    Advertise(RoomInfo),
+    /// Asks for the list of rooms now and after every change.
+    Subscribe,
+}
+
+/// Sent by the lobby registry to peers as JSON.
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) enum LobbyResponse {
+    Rooms(Vec<RoomInfo>),
+}
+
+/// Returns the URL of the lobby registry on the signaling server at `base_url`.
+pub(crate) fn lobby_url(base_url: &str) -> String {
+    format!("{}/{LOBBY_PATH}", base_url.trim_end_matches('/'))
+}
+
+/// Lists the rooms of a lobby registry into [`LobbyRooms`].
+///
+/// Insert it as a resource, the list is updated whenever a room changes and cleared when it is removed.
+#[derive(Resource)]
+pub struct LobbyBrowser {
+    game_version: Option<String>,
+    responses: UnboundedReceiver<LobbyResponse>,
+    _connection: LobbyConnection,
+}
+
+impl LobbyBrowser {
+    /// Connects to the lobby registry of the signaling server at `base_url`, like `ws://localhost:3536`.
+    pub fn new(base_url: &str) -> Self {
+        let (connection, responses) = LobbyConnection::open(lobby_url(base_url));
+        connection.send(LobbyRequest::Subscribe);
+        Self {
+            game_version: None,
+            responses,
+            _connection: connection,
+        }
+    }
+
+    /// Lists only the rooms of hosts with the same `MatchboxHost::with_game_version`.
+    pub fn with_game_version(mut self, game_version: impl Into<String>) -> Self {
+        self.game_version = Some(game_version.into());
+        self
+    }
+
+    fn is_joinable(&self, room: &RoomInfo) -> bool {
+        !room.is_full()
+            && self
+                .game_version
+                .as_ref()
+                .is_none_or(|version| *version == room.version)
+    }
+}
+
+/// Rooms from [`LobbyBrowser`] that aren't full and run the same game version.
+#[derive(Resource, Default, Debug, Deref)]
+pub struct LobbyRooms(Vec<RoomInfo>);
+
+/// Delay before the first attempt to reconnect [`LobbyAdvertisement`].
+#[cfg(feature = "server")]
+const MIN_RETRY_DELAY: Duration = Duration::from_secs(1);
+/// Limit for the delay, which doubles after each attempt that couldn't connect.
+#[cfg(feature = "server")]
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(30);
+
+/// Room published by the host, see `MatchboxHost::with_lobby`.
+///
+/// Reconnects with an exponential backoff if the connection to the registry fails or is closed.
+#[cfg(feature = "server")]
+pub(crate) struct LobbyAdvertisement {
+    pub(crate) info: RoomInfo,
+    url: String,
+    /// Last version sent to the registry.
+    sent: Option<RoomInfo>,
+    connection: LobbyConnection,
+    /// Delay before the next reconnection.
+    retry_delay: Duration,
+    /// When to reconnect, set once the connection closed.
+    retry_at: Option<Duration>,
+}
+
+#[cfg(feature = "server")]
+impl LobbyAdvertisement {
+    pub(crate) fn new(address: &crate::RoomAddress, mut info: RoomInfo) -> Self {
+        info.room = address.room().to_string();
+        let url = lobby_url(address.base_url());
+        let (connection, _) = LobbyConnection::open(url.clone());
+        Self {
+            info,
+            url,
+            sent: None,
+            connection,
+            retry_delay: MIN_RETRY_DELAY,
+            retry_at: None,
+        }
+    }
+
+    /// Publishes the room again if it changed or the registry was reconnected.
+    pub(crate) fn update(&mut self, now: Duration) {
+        if self.connection.is_closed() {
+            match self.retry_at {
+                None => {
+                    if self.connection.was_connected() {
+                        self.retry_delay = MIN_RETRY_DELAY;
+                    }
+                    debug!(
+                        "reconnecting to lobby {} in {:?}",
+                        self.url, self.retry_delay
+                    );
+                    self.retry_at = Some(now + self.retry_delay);
+                    self.retry_delay = (self.retry_delay * 2).min(MAX_RETRY_DELAY);
+                    return;
+                }
+                Some(retry_at) if now < retry_at => return,
+                Some(_) => {
+                    (self.connection, _) = LobbyConnection::open(self.url.clone());
+                    self.retry_at = None;
+                    self.sent = None;
+                }
+            }
+        }
+
+        if self.sent.as_ref() == Some(&self.info) {
+            return;
+        }
+        trace!("advertising room {:?}", self.info);
+        self.connection
+            .send(LobbyRequest::Advertise(self.info.clone()));
+        self.sent = Some(self.info.clone());
+    }
+}
+
+/// Websocket to the lobby registry, closed on drop.
+struct LobbyConnection {
+    requests: UnboundedSender<LobbyRequest>,
+    /// Set by the task once the websocket is open.
+    #[cfg_attr(
+        not(feature = "server"),
+        allow(dead_code, reason = "only read by `LobbyAdvertisement`")
+    )]
+    connected: Arc<AtomicBool>,
+    _task: Task<()>,
+}
+
+impl LobbyConnection {
+    fn open(url: String) -> (Self, UnboundedReceiver<LobbyResponse>) {
+        let (requests, requests_rx) = mpsc::unbounded();
+        let (responses_tx, responses) = mpsc::unbounded();
+        let connected = Arc::new(AtomicBool::new(false));
+        let task_connected = connected.clone();
+        let task = IoTaskPool::get().spawn(async move {
+            if let Err(e) = run_connection(&url, requests_rx, responses_tx, &task_connected).await {
+                warn!("lobby connection to {url} failed: {e}");
+            }
+        });
+        let connection = Self {
+            requests,
+            connected,
+            _task: task,
+        };
+        (connection, responses)
+    }
+
+    fn send(&self, request: LobbyRequest) {
+        // The task only stops if the connection failed, which is already logged.
+        let _ = self.requests.unbounded_send(request);
+    }
+
+    /// Returns `true` if the task stopped, requests are no longer sent.
+    #[cfg(feature = "server")]
+    fn is_closed(&self) -> bool {
+        self.requests.is_closed()
+    }
+
+    #[cfg(feature = "server")]
+    fn was_connected(&self) -> bool {
+        self.connected.load(Ordering::Relaxed)
+    }
+}
+
+async fn run_connection(
+    url: &str,
+    mut requests: UnboundedReceiver<LobbyRequest>,
+    responses: UnboundedSender<LobbyResponse>,
+    connected: &AtomicBool,
+) -> Result<(), String> {
+    let (mut sender, receiver) = connect(url).await?;
+    let mut receiver = receiver.fuse();
+    connected.store(true, Ordering::Relaxed);
+    debug!("connected to lobby {url}");
+    loop {
+        select! {
+            request = requests.next() => {
+                let Some(request) = request else {
+                    return Ok(());
+                };
+                let text = serde_json::to_string(&request).expect("lobby requests should serialize");
+                sender.send(text).await?;
+            }
+            text = receiver.next() => {
+                let Some(text) = text else {
+                    return Err("closed by the server".into());
+                };
+                match serde_json::from_str(&text) {
+                    Ok(response) => {
+                        let _ = responses.unbounded_send(response);
+                    }
+                    Err(e) => debug!("ignoring invalid lobby message: {e}"),
+                }
+            }
+        }
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+use native::connect;
+#[cfg(target_arch = "wasm32")]
+use wasm::connect;
+
+#[cfg(not(target_arch = "wasm32"))]
+mod native {
+    use async_tungstenite::async_std::{ConnectStream, connect_async};
+    use async_tungstenite::{WebSocketSender, tungstenite::Message};
+    use futures::{Stream, StreamExt, future};
+
+    pub(super) struct WebsocketSender(WebSocketSender<ConnectStream>);
+
+    impl WebsocketSender {
+        pub(super) async fn send(&mut self, text: String) -> Result<(), String> {
+            self.0
+                .send(Message::text(text))
+                .await
+                .map_err(|e| e.to_string())
+        }
+    }
+
+    pub(super) async fn connect(
+        url: &str,
+    ) -> Result<(WebsocketSender, impl Stream<Item = String> + Unpin), String> {
+        let (websocket, _) = connect_async(url).await.map_err(|e| e.to_string())?;
+        let (sender, receiver) = websocket.split();
+        let receiver = receiver
+            .take_while(|message| future::ready(message.is_ok()))
+            .filter_map(|message| {
+                future::ready(match message {
+                    Ok(Message::Text(text)) => Some(text.to_string()),
+                    _ => None,
+                })
+            });
+        Ok((WebsocketSender(sender), Box::pin(receiver)))
+    }
+}
+
+#[cfg(target_arch = "wasm32")]
+mod wasm {
+    use futures::stream::SplitSink;
+    use futures::{SinkExt, Stream, StreamExt, future};
+    use ws_stream_wasm::{WsMessage, WsMeta, WsStream};
+
+    pub(super) struct WebsocketSender(SplitSink<WsStream, WsMessage>);
+
+    impl WebsocketSender {
+        pub(super) async fn send(&mut self, text: String) -> Result<(), String> {
+            self.0
+                .send(WsMessage::Text(text))
+                .await
+                .map_err(|e| e.to_string())
+        }
+    }
+
+    pub(super) async fn connect(
+        url: &str,
+    ) -> Result<(WebsocketSender, impl Stream<Item = String> + Unpin), String> {
+        let (_, websocket) = WsMeta::connect(url, None)
+            .await
+            .map_err(|e| e.to_string())?;
+        let (sender, receiver) = websocket.split();
+        let receiver = receiver.filter_map(|message| {
+            future::ready(match message {
+                WsMessage::Text(text) => Some(text),
+                WsMessage::Binary(_) => None,
+            })
+        });
+        Ok((WebsocketSender(sender), receiver))
+    }
+}
@@ -0,0 +1,357 @@
+use crate::shared::*;
+use crate::{MatchboxClient, MatchboxHost};
+use bevy::prelude::*;
+use bevy_matchbox::prelude::PeerId;
+use bevy_replicon::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::mem;
+use std::time::Duration;
+
+/// Turns a [`MatchmakingQueue`] into a [`MatchboxHost`] or a [`MatchboxClient`] once enough players
+/// joined it.
+///
+/// Part of [`RepliconMatchboxPlugins`](crate::RepliconMatchboxPlugins) when both the `client` and
+/// `server` features are enabled.
+pub struct RepliconMatchboxMatchmakingPlugin;
+
+impl Plugin for RepliconMatchboxMatchmakingPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_message::<MatchFound>().add_systems(
+            PreUpdate,
+            update_queue.run_if(resource_exists::<MatchmakingQueue>),
+        );
+    }
+}
+
+fn update_queue(
+    mut commands: Commands,
+    mut queue: ResMut<MatchmakingQueue>,
+    host: Option<ResMut<MatchboxHost>>,
+    channels: Res<RepliconChannels>,
+    time: Res<Time<Real>>,
+    mut found: MessageWriter<MatchFound>,
+    mut errors: MessageWriter<MatchboxBackendError>,
+) {
+    let queue = &mut *queue;
+    if let Err(e) = queue.socket.try_update_peers() {
+        error!("matchmaking queue {} closed: {e}", queue.address);
+        errors.write(e);
+        commands.remove_resource::<MatchmakingQueue>();
+        return;
+    }
+    let Some(id) = queue.socket.id() else {
+        return;
+    };
+
+    let now = time.elapsed();
+    for (peer_id, packet) in queue.socket.receive(SYSTEM_CHANNEL_ID) {
+        let message = match from_packet(&packet) {
+            Ok(message) => message,
+            Err(e) => {
+                debug!("ignoring invalid matchmaking message from {peer_id}: {e}");
+                continue;
+            }
+        };
+        trace!("received {message:?} from {peer_id}");
+        match (message, &mut queue.state) {
+            (MatchmakingMessage::Propose, QueueState::Waiting) => {
+                queue.accept(peer_id, now);
+            }
+            // The lowest id wins when two peers propose at the same time.
+            (MatchmakingMessage::Propose, QueueState::Proposing { .. }) if peer_id < id => {
+                queue.cancel_proposal(now);
+                queue.accept(peer_id, now);
+            }
+            (MatchmakingMessage::Propose, _) => {
+                queue.send(MatchmakingMessage::Reject, peer_id);
+            }
+            (
+                MatchmakingMessage::Accept,
+                QueueState::Proposing {
+                    members, accepted, ..
+                },
+            ) if members.contains(&peer_id) => {
+                accepted.push(peer_id);
+            }
+            (MatchmakingMessage::Accept, QueueState::Waiting | QueueState::Proposing { .. }) => {
+                // Accepted a proposal that was already cancelled.
+                queue.send(MatchmakingMessage::Cancel, peer_id);
+            }
+            (MatchmakingMessage::Reject, QueueState::Proposing { members, .. })
+                if members.contains(&peer_id) =>
+            {
+                debug!("{peer_id} rejected the match proposal");
+                queue.cancel_proposal(now);
+            }
+            (MatchmakingMessage::Cancel, QueueState::Accepted { host_id, .. })
+                if *host_id == peer_id =>
+            {
+                debug!("{peer_id} cancelled the match proposal");
+                queue.state = QueueState::Waiting;
+            }
+            (MatchmakingMessage::Join, QueueState::Accepted { host_id, .. })
+                if *host_id == peer_id =>
+            {
+                let room = queue.match_room(peer_id);
+                debug!("joining match {room} hosted by {peer_id}");
+                match MatchboxClient::new(&room, &channels, queue.config.clone()) {
+                    Ok(client) => {
+                        commands.insert_resource(client);
+                        found.write(MatchFound {
+                            role: MatchRole::Client,
+                            room,
+                        });
+                    }
+                    Err(e) => error!("unable to join match {room}: {e}"),
+                }
+                commands.remove_resource::<MatchmakingQueue>();
+                return;
+            }
+            (message, _) => trace!("ignoring {message:?} from {peer_id}"),
+        }
+    }
+
+    let connected = queue.socket.connected_peers();
+    match &mut queue.state {
+        QueueState::Waiting => {
+            if now < queue.next_election {
+                return;
+            }
+            let mut group = connected;
+            if group.len() + 1 < queue.players {
+                return;
+            }
+            group.push(id);
+            group.sort();
+            group.truncate(queue.players);
+            if group[0] != id {
+                return;
+            }
+
+            let members = group.split_off(1);
+            debug!("proposing a match to {members:?}");
+            for &peer_id in &members {
+                queue.send(MatchmakingMessage::Propose, peer_id);
+            }
+            queue.state = QueueState::Proposing {
+                members,
+                accepted: Vec::new(),
+                since: now,
+            };
+        }
+        QueueState::Proposing {
+            members,
+            accepted,
+            since,
+        } => {
+            if members.iter().any(|peer_id| !connected.contains(peer_id)) {
+                debug!("a member left the queue before accepting the match");
+                queue.cancel_proposal(now);
+                return;
+            }
+            if now - *since > ELECTION_TIMEOUT {
+                debug!("members didn't accept the match in time");
+                queue.cancel_proposal(now);
+                return;
+            }
+            if accepted.len() < members.len() {
+                return;
+            }
+
+            let members = mem::take(members);
+            let room = queue.match_room(id);
+            debug!("elected as the host of {room} for {members:?}");
+            match MatchboxHost::new(&room, &channels, queue.config.clone()) {
+                Ok(host) => {
+                    commands.insert_resource(host.with_max_clients(queue.players - 1));
+                    found.write(MatchFound {
+                        role: MatchRole::Host,
+                        room,
+                    });
+                    queue.state = QueueState::Hosting { members };
+                }
+                Err(e) => {
+                    error!("unable to host match {room}: {e}");
+                    commands.remove_resource::<MatchmakingQueue>();
+                }
+            }
+        }
+        QueueState::Accepted { host_id, since } => {
+            if !connected.contains(host_id) || now - *since > ELECTION_TIMEOUT {
+                debug!("{host_id} didn't start the accepted match");
+                queue.state = QueueState::Waiting;
+            }
+        }
+        QueueState::Hosting { members } => {
+            // Invite only after joining the private room, so the host is the first peer in it.
+            let Some(mut host) = host else {
+                return;
+            };
+            if host.socket.id().is_none() {
+                return;
+            }
+            let members = mem::take(members);
+            for &peer_id in &members {
+                queue.send(MatchmakingMessage::Join, peer_id);
+            }
+            queue.state = QueueState::Invited { members };
+        }
+        QueueState::Invited { members } => {
+            // Keep the queue open until the members received the invitation and left it.
+            if members.iter().all(|peer_id| !connected.contains(peer_id)) {
+                commands.remove_resource::<MatchmakingQueue>();
+            }
+        }
+    }
+}
+
+/// Time for members to accept a proposal and for the proposer to invite them once they did.
+const ELECTION_TIMEOUT: Duration = Duration::from_secs(5);
+/// Delay before proposing again after a proposal was cancelled.
+const ELECTION_RETRY: Duration = Duration::from_millis(250);
+
+/// Shared room where players wait to be grouped into a match.
+///
+/// Once `players` peers are in the queue, the one with the lowest id proposes a match to the others.
+/// After all of them accepted, it creates a [`MatchboxHost`] in a private room named after it and
+/// invites them, and they replace the queue with a [`MatchboxClient`] in that room. Peers accept
+/// only one proposal at a time, a proposal that isn't accepted within a few seconds is cancelled and
+/// the peers keep waiting. [`MatchFound`] is written on both sides and the resource removes
+/// itself, so the app doesn't have to decide up front whether it hosts. If the queue socket fails, a
+/// [`MatchboxBackendError`] is written instead.
+///
+/// Peers waiting in the queue have to see each other, so the queue needs a full-mesh signaling server
+/// like `matchbox_server`, optionally with [`RoomAddress::with_next`] to split it into groups of
+/// `players`.
+#[derive(Resource)]
+pub struct MatchmakingQueue {
+    address: RoomAddress,
+    players: usize,
+    config: MatchboxBackendConfig,
+    socket: Transport,
+    state: QueueState,
+    /// Time before which this peer doesn't propose a match.
+    next_election: Duration,
+}
+
+enum QueueState {
+    Waiting,
+    /// Elected itself, waiting for the members to accept.
+    Proposing {
+        members: Vec<PeerId>,
+        accepted: Vec<PeerId>,
+        since: Duration,
+    },
+    /// Accepted the proposal of `host_id`, waiting for the invitation.
+    Accepted {
+        host_id: PeerId,
+        since: Duration,
+    },
+    /// All members accepted, waiting to join the private room.
+    Hosting {
+        members: Vec<PeerId>,
+    },
+    /// Invited the members, waiting for them to leave the queue.
+    Invited {
+        members: Vec<PeerId>,
+    },
+}
+
+impl MatchmakingQueue {
+    /// Joins the queue at `address` to play a match of `players` peers, including this one.
+    ///
+    /// `replicon_channels` and `config` are also used for the host or client created for the match.
+    pub fn new(
+        address: RoomAddress,
+        players: usize,
+        replicon_channels: &RepliconChannels,
+        config: MatchboxBackendConfig,
+    ) -> Result<Self, MatchboxBackendError> {
+        validate_room_url(&address.url())?;
+        let socket = create_matchbox_socket(&address, replicon_channels, &config);
+        Ok(Self {
+            address,
+            players: players.max(1),
+            config,
+            socket,
+            state: QueueState::Waiting,
+            next_election: Duration::ZERO,
+        })
+    }
+
+    pub fn address(&self) -> &RoomAddress {
+        &self.address
+    }
+
+    pub fn players(&self) -> usize {
+        self.players
+    }
+
+    /// Returns the number of peers in the queue, including this one.
+    pub fn waiting(&self) -> usize {
+        self.socket.connected_peers().len() + 1
+    }
+
+    fn send(&mut self, message: MatchmakingMessage, peer_id: PeerId) {
+        self.socket
+            .send(SYSTEM_CHANNEL_ID, to_packet(&message), peer_id);
+    }
+
+    fn accept(&mut self, host_id: PeerId, now: Duration) {
+        debug!("accepting the match proposed by {host_id}");
+        self.send(MatchmakingMessage::Accept, host_id);
+        self.state = QueueState::Accepted {
+            host_id,
+            since: now,
+        };
+    }
+
+    /// Cancels the proposal of this peer and goes back to waiting.
+    fn cancel_proposal(&mut self, now: Duration) {
+        if let QueueState::Proposing { members, .. } =
+            mem::replace(&mut self.state, QueueState::Waiting)
+        {
+            for peer_id in members {
+                self.send(MatchmakingMessage::Cancel, peer_id);
+            }
+        }
+        self.next_election = now + ELECTION_RETRY;
+    }
+
+    /// Returns the private room of the match hosted by `host_id`.
+    fn match_room(&self, host_id: PeerId) -> RoomAddress {
+        RoomAddress::new(
+            self.address.base_url(),
+            format!("{}-{host_id}", self.address.room()),
+        )
+    }
+}
+
+/// Sent on the system channel of the queue socket.
+#[derive(Debug, Serialize, Deserialize)]
+enum MatchmakingMessage {
+    /// The sender elected itself as the host of a match with the receiver.
+    Propose,
+    /// Answers [`Self::Propose`], the receiver can create the match.
+    Accept,
+    /// Answers [`Self::Propose`], the receiver already accepted another match.
+    Reject,
+    /// The proposal of the sender was cancelled.
+    Cancel,
+    /// The elected host joined its private room and expects the receiver there.
+    Join,
+}
+
+/// The [`MatchmakingQueue`] was replaced by a [`MatchboxHost`] or a [`MatchboxClient`].
+#[derive(Message, Debug, Clone, PartialEq, Eq)]
+pub struct MatchFound {
+    pub role: MatchRole,
+    /// Private room of the match.
+    pub room: RoomAddress,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MatchRole {
+    Host,
+    Client,
+}
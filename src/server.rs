@@ -1,23 +1,40 @@
+use crate::lobby::{LobbyAdvertisement, RoomInfo};
 use crate::shared::*;
 use bevy::prelude::*;
-use bevy::tasks::futures_lite::io;
-use bevy_matchbox::MatchboxSocket;
-use bevy_matchbox::matchbox_socket::Packet;
+use bevy::tasks::futures::check_ready;
+use bevy::tasks::{IoTaskPool, Task};
 use bevy_matchbox::prelude::{PeerId, PeerState};
+use bevy_replicon::bytes::Bytes;
 use bevy_replicon::prelude::*;
 use bevy_replicon::shared::backend::connected_client::NetworkId;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::mem;
+use std::time::Duration;
+
+mod bans;
+
+pub use bans::{Ban, BanList, BanTarget};
 
 pub struct RepliconMatchboxServerPlugin;
 
 impl Plugin for RepliconMatchboxServerPlugin {
     fn build(&self, app: &mut App) {
+        app.add_message::<HandshakeRejected>()
+            .add_message::<ClientDisconnected>();
+        add_lifecycle_messages(app);
         app.add_systems(
             PreUpdate,
             (
                 set_running.run_if(resource_added::<MatchboxHost>),
+                // Same frame as the removal, like `set_disconnected` on the client.
+                set_stopped.run_if(resource_removed::<MatchboxHost>),
+                apply_network_conditions.run_if(resource_exists::<MatchboxHost>),
                 receive_system_channel_packets.run_if(resource_exists::<MatchboxHost>),
+                poll_authentications.run_if(resource_exists::<MatchboxHost>),
+                expire_sessions.run_if(resource_exists::<MatchboxHost>),
                 receive_packets.run_if(resource_exists::<MatchboxHost>),
+                disconnect_idle_clients.run_if(resource_exists::<MatchboxHost>),
                 received_disconnect.run_if(resource_exists::<MatchboxHost>),
             )
                 .chain()
@@ -34,9 +51,18 @@ impl Plugin for RepliconMatchboxServerPlugin {
                     .run_if(resource_exists::<MatchboxHost>)
                     .after(update_client_presence)
                     .before(received_disconnect),
-                set_stopped
-                    .in_set(ServerSystems::Send)
-                    .run_if(resource_removed::<MatchboxHost>),
+                announce_successors
+                    .in_set(ServerSystems::SendPackets)
+                    .run_if(resource_exists::<MatchboxHost>)
+                    .after(send_packets),
+                update_stats
+                    .in_set(ServerSystems::SendPackets)
+                    .run_if(resource_exists::<MatchboxHost>)
+                    .after(send_packets),
+                advertise_room
+                    .in_set(ServerSystems::SendPackets)
+                    .run_if(resource_exists::<MatchboxHost>)
+                    .after(send_packets),
             ),
         );
     }
@@ -51,66 +77,124 @@ fn set_running(mut server: ResMut<NextState<ServerState>>) {
     server.set(ServerState::Running);
 }
 
-fn update_client_presence(mut commands: Commands, mut server: ResMut<MatchboxHost>) {
-    let Ok(updated_peers) = server.socket.try_update_peers() else {
-        for client_entity in server.client_entities.values() {
-            commands.entity(*client_entity).despawn();
+fn apply_network_conditions(
+    mut server: ResMut<MatchboxHost>,
+    conditions: Option<Res<NetworkConditions>>,
+    channels: Res<RepliconChannels>,
+) {
+    server.socket.condition(conditions.as_deref(), &channels);
+}
+
+fn update_client_presence(
+    mut commands: Commands,
+    mut server: ResMut<MatchboxHost>,
+    mut disconnected: MessageWriter<ClientDisconnected>,
+    mut lifecycle: LifecycleMessages,
+    time: Res<Time<Real>>,
+    clients: Query<&MatchboxClientConnection>,
+) {
+    let updated_peers = match server.socket.try_update_peers() {
+        Ok(updated_peers) => updated_peers,
+        Err(error) => {
+            for (&peer_id, &client_entity) in server.client_entities.iter() {
+                commands.entity(client_entity).despawn();
+                disconnected.write(ClientDisconnected {
+                    client: client_entity,
+                    peer_id,
+                    reason: DisconnectReason::SocketError,
+                });
+            }
+            for session in server.sessions.values() {
+                let Some(peer_id) = session.suspended_peer(&clients) else {
+                    continue;
+                };
+                commands.entity(session.client_entity).despawn();
+                disconnected.write(ClientDisconnected {
+                    client: session.client_entity,
+                    peer_id,
+                    reason: DisconnectReason::SocketError,
+                });
+            }
+            error!("sockets closed, shutting down: {error}");
+            lifecycle.closed.write(SocketClosed);
+            lifecycle.errors.write(error);
+            commands.remove_resource::<MatchboxHost>();
+            return;
         }
-        error!("sockets closed, shutting down");
-        commands.remove_resource::<MatchboxHost>();
-        return;
     };
 
     for (peer, state) in updated_peers {
         match state {
             PeerState::Connected => {
-                if server.client_entities.contains_key(&peer) {
-                    continue;
-                }
-                let network_id = NetworkId::new(uuid_to_u64_truncated(peer));
-                let client_entity = commands
-                    .spawn((
-                        ConnectedClient { max_size: 1200 },
-                        network_id,
-                        MatchboxClientConnection { peer_id: peer },
-                    ))
-                    .id();
-                trace!(
-                    "new client peer: {}, network_id: {:?} entity: {}",
-                    peer, network_id, client_entity
-                );
-                server.client_entities.insert(peer, client_entity);
-                let mut buf = [0u8; 1];
-                let packet: Packet =
-                    to_packet(&SystemChannelMessage::ConnectedToHost, &mut buf).into();
-                server
-                    .socket
-                    .channel_mut(SYSTEM_CHANNEL_ID)
-                    .send(packet, peer);
+                // The client entity is spawned once the peer completes the handshake.
+                trace!("peer {} connected, waiting for hello", peer);
+                lifecycle.joined.write(PeerJoined { peer_id: peer });
             }
             PeerState::Disconnected => {
-                let Some(client_entity) = server.client_entities.remove(&peer) else {
+                server.reassembler.remove_peer(peer);
+                server.fragmenter.remove_peer(peer);
+                if server.pending_auth.remove(&peer).is_some() {
+                    trace!("peer {} left during authentication", peer);
+                }
+                let client_entity = server.remove_client_peer(peer);
+                lifecycle.left.write(PeerLeft {
+                    peer_id: peer,
+                    client: client_entity,
+                });
+                let Some(client_entity) = client_entity else {
                     continue;
                 };
+                let session = clients
+                    .get(client_entity)
+                    .ok()
+                    .and_then(|connection| connection.session);
+                if let Some(token) = session
+                    && server.suspend_session(token, time.elapsed())
+                {
+                    continue;
+                }
                 trace!("client disconnected {:?}: {}", peer, client_entity);
                 commands.entity(client_entity).despawn();
+                server.remove_client(client_entity);
+                disconnected.write(ClientDisconnected {
+                    client: client_entity,
+                    peer_id: peer,
+                    reason: DisconnectReason::ConnectionLost,
+                });
             }
         }
     }
 }
 
-fn receive_system_channel_packets(mut commands: Commands, mut server: ResMut<MatchboxHost>) {
+fn receive_system_channel_packets(
+    mut commands: Commands,
+    mut server: ResMut<MatchboxHost>,
+    mut rejections: MessageWriter<HandshakeRejected>,
+    mut disconnected: MessageWriter<ClientDisconnected>,
+    mut lifecycle: LifecycleMessages,
+    mut monitors: Query<&mut ConnectionMonitor>,
+    time: Res<Time<Real>>,
+) {
     if server.socket.all_channels_closed() {
         trace!("matchbox socket was closed");
         return;
     }
-    let Ok(channel) = server.socket.get_channel_mut(SYSTEM_CHANNEL_ID) else {
-        error!("system channel not found!");
-        return;
-    };
-    for (peer_id, packet) in channel.receive() {
+    for (peer_id, packet) in server.socket.receive(SYSTEM_CHANNEL_ID) {
+        let client = server.client_entities.get(&peer_id).copied();
+        if let Some(client_entity) = client
+            && let Ok(mut monitor) = monitors.get_mut(client_entity)
+        {
+            monitor.received(time.elapsed());
+        }
         let Ok(message) = from_packet(&packet) else {
             error!("failed to deserialize system message {}", packet.len());
+            lifecycle.malformed.write(MalformedPacket {
+                peer_id,
+                client,
+                channel: SYSTEM_CHANNEL_ID,
+                len: packet.len(),
+                kind: MalformedPacketKind::Deserialization,
+            });
             continue;
         };
         trace!(
@@ -119,82 +203,413 @@ fn receive_system_channel_packets(mut commands: Commands, mut server: ResMut<Mat
         );
 
         match message {
+            SystemChannelMessage::Hello(hello) => {
+                if server.client_entities.contains_key(&peer_id)
+                    || server.pending_auth.contains_key(&peer_id)
+                {
+                    trace!("ignoring repeated hello from {peer_id}");
+                    continue;
+                }
+                if let Err(rejection) = server.check_hello(&hello) {
+                    server.reject_client(peer_id, rejection, &mut rejections);
+                    continue;
+                }
+                if let Some(ban) = server.bans.find(&hello.identity, &hello.auth_token) {
+                    let rejection = HandshakeRejection::Banned(ban.reason.clone());
+                    server.reject_client(peer_id, rejection, &mut rejections);
+                    continue;
+                }
+                if !hello.spectator && server.is_full(hello.session) {
+                    server.reject_client(peer_id, HandshakeRejection::ServerFull, &mut rejections);
+                    continue;
+                }
+
+                let admission = Admission {
+                    session: hello.session,
+                    max_packet_size: hello.max_packet_size as usize,
+                    ban_targets: ban_targets(&hello.identity, &hello.auth_token),
+                    spectator: hello.spectator,
+                };
+                let request = AuthRequest {
+                    peer_id,
+                    token: hello.auth_token,
+                    identity: hello.identity,
+                };
+                match &server.authenticator {
+                    None => server.accept_client(&mut commands, peer_id, admission),
+                    Some(Authenticator::Sync(validate)) => match validate(&request) {
+                        Ok(()) => server.accept_client(&mut commands, peer_id, admission),
+                        Err(reason) => server.reject_client(
+                            peer_id,
+                            HandshakeRejection::Unauthorized(reason),
+                            &mut rejections,
+                        ),
+                    },
+                    Some(Authenticator::Async(validate)) => {
+                        trace!("authenticating peer {peer_id}");
+                        let task = validate(request);
+                        server.pending_auth.insert(peer_id, (task, admission));
+                    }
+                }
+            }
             SystemChannelMessage::ClientDisconnects => {
-                let Some(client_entity) = server.client_entities.remove(&peer_id) else {
+                let Some(client_entity) = server.remove_client_peer(peer_id) else {
                     continue;
                 };
                 trace!("client disconnected {peer_id}: {client_entity}");
                 commands.entity(client_entity).despawn();
+                server.remove_client(client_entity);
+                disconnected.write(ClientDisconnected {
+                    client: client_entity,
+                    peer_id,
+                    reason: DisconnectReason::Left,
+                });
+            }
+            SystemChannelMessage::Ping(id) => {
+                if client.is_none() {
+                    // Unanswered, so a peer whose session was suspended times out and reconnects.
+                    trace!("ignoring ping from peer {peer_id} without a client");
+                    continue;
+                }
+                let packet = to_packet(&SystemChannelMessage::Pong(id));
+                server.socket.send(SYSTEM_CHANNEL_ID, packet, peer_id);
+            }
+            SystemChannelMessage::Pong(id) => {
+                let Some(&client_entity) = server.client_entities.get(&peer_id) else {
+                    continue;
+                };
+                if let Ok(mut monitor) = monitors.get_mut(client_entity) {
+                    monitor.pong(id, time.elapsed());
+                }
             }
             _ => {
                 error!("Unexpected message {message:?} received from client {peer_id}");
+                lifecycle.malformed.write(MalformedPacket {
+                    peer_id,
+                    client,
+                    channel: SYSTEM_CHANNEL_ID,
+                    len: packet.len(),
+                    kind: MalformedPacketKind::UnexpectedMessage,
+                });
             }
         }
     }
 }
 
+fn poll_authentications(
+    mut commands: Commands,
+    mut server: ResMut<MatchboxHost>,
+    mut rejections: MessageWriter<HandshakeRejected>,
+) {
+    let mut finished = Vec::new();
+    for (&peer_id, (task, admission)) in server.pending_auth.iter_mut() {
+        if let Some(result) = check_ready(task) {
+            finished.push((peer_id, admission.clone(), result));
+        }
+    }
+
+    for (peer_id, admission, result) in finished {
+        server.pending_auth.remove(&peer_id);
+        match result {
+            // The limit could have been lowered while the authentication was running.
+            Ok(()) if !admission.spectator && server.is_full(admission.session) => {
+                server.reject_client(peer_id, HandshakeRejection::ServerFull, &mut rejections)
+            }
+            Ok(()) => server.accept_client(&mut commands, peer_id, admission),
+            Err(reason) => server.reject_client(
+                peer_id,
+                HandshakeRejection::Unauthorized(reason),
+                &mut rejections,
+            ),
+        }
+    }
+}
+
+fn expire_sessions(
+    mut commands: Commands,
+    mut server: ResMut<MatchboxHost>,
+    mut disconnected: MessageWriter<ClientDisconnected>,
+    time: Res<Time<Real>>,
+    clients: Query<&MatchboxClientConnection>,
+) {
+    let grace_period = server.session_grace_period;
+    let mut expired = Vec::new();
+    server.sessions.retain(|_, session| {
+        let Some(suspended_since) = session.suspended_since else {
+            return true;
+        };
+        if time.elapsed() - suspended_since < grace_period
+            && session.queued_bytes <= MAX_QUEUED_SESSION_BYTES
+        {
+            return true;
+        }
+
+        trace!("session of client {} expired", session.client_entity);
+        if let Some(peer_id) = session.suspended_peer(&clients) {
+            disconnected.write(ClientDisconnected {
+                client: session.client_entity,
+                peer_id,
+                reason: DisconnectReason::ConnectionLost,
+            });
+        }
+        commands.entity(session.client_entity).despawn();
+        expired.push(session.client_entity);
+        false
+    });
+    for client_entity in expired {
+        server.remove_client(client_entity);
+    }
+}
+
 fn receive_packets(
     mut replicon_server: ResMut<ServerMessages>,
     mut server: ResMut<MatchboxHost>,
+    mut malformed: MessageWriter<MalformedPacket>,
+    mut clients: Query<(&mut ChannelStats, &mut ConnectionMonitor)>,
     channels: Res<RepliconChannels>,
+    time: Res<Time<Real>>,
 ) {
-    for (channel_id, _) in channels.client_channels().iter().enumerate() {
+    let server = &mut *server;
+    let now = time.elapsed();
+    for (channel_id, &channel) in channels.client_channels().iter().enumerate() {
         let socket_channel_id = 1 + channels.server_channels().len() + channel_id;
-        for (id, packet) in server.socket.channel_mut(socket_channel_id).receive() {
+        let reliable = channel != Channel::Unreliable;
+        for (id, packet) in server.socket.receive(socket_channel_id) {
             let Some(client_entity) = server.client_entities.get(&id) else {
                 trace!("received packet from unknown client {}", id);
+                malformed.write(MalformedPacket {
+                    peer_id: id,
+                    client: None,
+                    channel: socket_channel_id,
+                    len: packet.len(),
+                    kind: MalformedPacketKind::UnknownPeer,
+                });
+                continue;
+            };
+            let Ok((mut channel_stats, mut monitor)) = clients.get_mut(*client_entity) else {
                 continue;
             };
-            replicon_server.insert_received(*client_entity, channel_id, strip_marker(&packet));
+            channel_stats.record_received(channel_id, packet.len());
+            monitor.received(now);
+            if server.spectators.contains(client_entity) {
+                trace!("dropping packet from spectator {}", client_entity);
+                continue;
+            }
+            let Some((message, compressed)) = server
+                .reassembler
+                .receive(id, channel_id, reliable, packet, now)
+            else {
+                continue;
+            };
+            let len = message.len();
+            match server
+                .codec
+                .decompress(channel_id, message, compressed, &mut channel_stats)
+            {
+                Some(message) => {
+                    replicon_server.insert_received(*client_entity, channel_id, message)
+                }
+                None => {
+                    malformed.write(MalformedPacket {
+                        peer_id: id,
+                        client: Some(*client_entity),
+                        channel: socket_channel_id,
+                        len,
+                        kind: MalformedPacketKind::Decompression,
+                    });
+                }
+            }
         }
     }
+    server.reassembler.expire(now);
+}
+
+/// Drops clients that stopped sending anything, including heartbeats.
+///
+/// Clients with a session are suspended instead, like when their peer disconnects.
+fn disconnect_idle_clients(
+    mut server: ResMut<MatchboxHost>,
+    mut clients: Query<(&MatchboxClientConnection, &mut ConnectionMonitor)>,
+    time: Res<Time<Real>>,
+) {
+    for (connection, mut monitor) in &mut clients {
+        // Suspended sessions expire on their own.
+        if !server.client_entities.contains_key(&connection.peer_id)
+            || !monitor.timed_out(time.elapsed())
+        {
+            continue;
+        }
+        warn!("client {} timed out", connection.peer_id);
+        if let Some(token) = connection.session
+            && server.suspend_session(token, time.elapsed())
+        {
+            // The stale peer is ignored from now on, the client resumes with a new one.
+            server.remove_client_peer(connection.peer_id);
+            server.reassembler.remove_peer(connection.peer_id);
+            server.fragmenter.remove_peer(connection.peer_id);
+            continue;
+        }
+        server
+            .clients_to_disconnect
+            .push((connection.peer_id, DisconnectReason::Timeout));
+    }
 }
 
 fn send_packets(
     mut commands: Commands,
     mut replicon_server: ResMut<ServerMessages>,
     mut server: ResMut<MatchboxHost>,
-    clients: Query<&MatchboxClientConnection>,
+    mut disconnected: MessageWriter<ClientDisconnected>,
+    channels: Res<RepliconChannels>,
+    mut clients: Query<(&MatchboxClientConnection, &mut ChannelStats)>,
 ) {
     for (client_entity, channel_id, message) in replicon_server.drain_sent() {
-        let Ok(connection) = clients.get(client_entity) else {
+        let Ok((connection, mut channel_stats)) = clients.get_mut(client_entity) else {
             trace!("client {} not connected", client_entity);
             continue;
         };
+        let server = &mut *server;
         if !server.client_entities.contains_key(&connection.peer_id) {
-            trace!("client {} was disconnected", client_entity);
+            let session = connection
+                .session
+                .and_then(|token| server.sessions.get_mut(&token));
+            match session {
+                Some(session) if channels.server_channels()[channel_id] != Channel::Unreliable => {
+                    trace!("queuing packet for suspended client {}", client_entity);
+                    let (message, compressed) =
+                        server
+                            .codec
+                            .compress(channel_id, message, &mut channel_stats);
+                    session.queued_bytes += message.len();
+                    session.queued.push((channel_id, message, compressed));
+                }
+                _ => trace!("client {} was disconnected", client_entity),
+            }
             continue;
         }
+        // Compressed only for clients that receive it, the dropped messages above aren't.
+        let (message, compressed) = server
+            .codec
+            .compress(channel_id, message, &mut channel_stats);
         trace!(
-            "sending packet to client {}: c:{} - {:?}",
+            "sending message to client {}: c:{} - {:?}",
             client_entity,
             channel_id,
-            add_marker(message.as_ref()).len()
+            message.len()
         );
-        let socket_channel_id = 1 + channel_id;
+        server.fragmenter.split(
+            connection.peer_id,
+            channel_id,
+            message,
+            compressed,
+            connection.max_packet_size,
+            |packet| {
+                channel_stats.record_sent(channel_id, packet.len());
+                server
+                    .socket
+                    .send(1 + channel_id, packet, connection.peer_id)
+            },
+        );
+    }
+    for (client_entity, duration, reason) in mem::take(&mut server.pending_bans) {
+        let Ok((connection, _)) = clients.get(client_entity) else {
+            continue;
+        };
+        if connection.ban_targets.is_empty() {
+            warn!("client {client_entity} has no identity or token to ban, kicking it instead");
+        }
+        for target in &connection.ban_targets {
+            server.bans.ban(target.clone(), duration, reason.clone());
+        }
         server
-            .socket
-            .channel_mut(socket_channel_id)
-            .send(add_marker(message.as_ref()), connection.peer_id);
+            .clients_to_disconnect
+            .push((connection.peer_id, DisconnectReason::Banned));
     }
     let disconnect_ids: Vec<_> = server.clients_to_disconnect.drain(..).collect();
 
-    for peer_id in disconnect_ids {
-        let Some(client_entity) = server.client_entities.remove(&peer_id) else {
+    for (peer_id, reason) in disconnect_ids {
+        let Some(client_entity) = server.remove_client_peer(peer_id) else {
             continue;
         };
-        let mut buf = [0u8; 1];
-        let packet: Packet =
-            to_packet(&SystemChannelMessage::HostRequestsDisconnect, &mut buf).into();
+        let packet = to_packet(&SystemChannelMessage::HostRequestsDisconnect(
+            reason.clone(),
+        ));
+        server.socket.send(SYSTEM_CHANNEL_ID, packet, peer_id);
+        trace!("disconnecting client `{}`: {}", client_entity, reason);
+        commands.entity(client_entity).despawn();
+        server.remove_client(client_entity);
+        disconnected.write(ClientDisconnected {
+            client: client_entity,
+            peer_id,
+            reason,
+        });
+    }
+}
+
+/// Tells the clients who takes over if the host leaves, see `MatchboxClient::with_host_migration`.
+///
+/// Spectators receive the order, but can't take over.
+fn announce_successors(mut server: ResMut<MatchboxHost>) {
+    let mut recipients: Vec<_> = server.client_entities.keys().copied().collect();
+    recipients.sort();
+    let successors: Vec<_> = recipients
+        .iter()
+        .copied()
+        .filter(|peer_id| !server.spectators.contains(&server.client_entities[peer_id]))
+        .collect();
+    if successors == server.announced_successors && recipients == server.successor_recipients {
+        return;
+    }
+
+    trace!("announcing successors {successors:?}");
+    let packet = to_packet(&SystemChannelMessage::Successors(successors.clone()));
+    for &peer_id in &recipients {
         server
             .socket
-            .channel_mut(SYSTEM_CHANNEL_ID)
-            .send(packet, peer_id);
-        trace!("disconnecting client `{}`", client_entity);
-        commands.entity(client_entity).despawn();
+            .send(SYSTEM_CHANNEL_ID, packet.clone(), peer_id);
+    }
+    server.announced_successors = successors;
+    server.successor_recipients = recipients;
+}
+
+/// Pings connected clients and fills their [`ClientStats`].
+fn update_stats(
+    mut server: ResMut<MatchboxHost>,
+    mut clients: Query<(
+        &MatchboxClientConnection,
+        &ChannelStats,
+        &mut ConnectionMonitor,
+        &mut ClientStats,
+    )>,
+    time: Res<Time<Real>>,
+) {
+    let now = time.elapsed();
+    for (connection, channel_stats, mut monitor, mut stats) in &mut clients {
+        // Suspended sessions have no peer to answer.
+        if server.client_entities.contains_key(&connection.peer_id)
+            && let Some(ping) = monitor.ping(now)
+        {
+            server
+                .socket
+                .send(SYSTEM_CHANNEL_ID, to_packet(&ping), connection.peer_id);
+        }
+        monitor.update(now, channel_stats, &mut stats);
     }
 }
 
+/// Keeps the room published with [`MatchboxHost::with_lobby`] in sync with the host.
+fn advertise_room(mut server: ResMut<MatchboxHost>, time: Res<Time<Real>>) {
+    let server = &mut *server;
+    let players = server.players();
+    let Some(lobby) = &mut server.lobby else {
+        return;
+    };
+    lobby.info.players = players;
+    lobby.info.max_players = server.max_clients;
+    lobby.info.version.clone_from(&server.game_version);
+    lobby.update(time.elapsed());
+}
+
 fn received_disconnect(
     mut disconnect_events: MessageReader<DisconnectRequest>,
     mut server: ResMut<MatchboxHost>,
@@ -205,44 +620,710 @@ fn received_disconnect(
             continue;
         };
         trace!("queuing disconnecting client `{}` by request", event.client);
-        server.clients_to_disconnect.push(connection.peer_id);
+        server
+            .clients_to_disconnect
+            .push((connection.peer_id, DisconnectReason::Kicked(String::new())));
     }
 }
 
 // The socket used by the server.
 #[derive(Resource)]
 pub struct MatchboxHost {
-    pub socket: MatchboxSocket,
-    pub client_entities: HashMap<PeerId, Entity>,
-    pub clients_to_disconnect: Vec<PeerId>,
+    /// Connection to the clients, over WebRTC or a [`LoopbackNetwork`].
+    pub socket: Transport,
+    client_entities: HashMap<PeerId, Entity>,
+    /// Reverse of `client_entities`.
+    client_peers: HashMap<Entity, PeerId>,
+    /// Ids of the client entities, kept while their session is suspended.
+    network_ids: HashMap<NetworkId, Entity>,
+    /// Reverse of `network_ids`.
+    client_network_ids: HashMap<Entity, NetworkId>,
+    pub clients_to_disconnect: Vec<(PeerId, DisconnectReason)>,
+    game_version: String,
+    channels_hash: u64,
+    authenticator: Option<Authenticator>,
+    pending_auth: HashMap<PeerId, (Task<AuthResult>, Admission)>,
+    max_packet_size: usize,
+    heartbeat: Heartbeat,
+    fragmenter: Fragmenter,
+    reassembler: Reassembler,
+    codec: Codec,
+    session_grace_period: Duration,
+    sessions: HashMap<SessionToken, Session>,
+    announced_successors: Vec<PeerId>,
+    /// Clients that received `announced_successors`.
+    successor_recipients: Vec<PeerId>,
+    max_clients: Option<usize>,
+    bans: BanList,
+    pending_bans: Vec<(Entity, Option<Duration>, String)>,
+    /// Clients that receive replication but whose messages are dropped.
+    spectators: HashSet<Entity>,
+    lobby: Option<LobbyAdvertisement>,
+    #[cfg(all(feature = "signaling", not(target_arch = "wasm32")))]
+    signaling_server: Option<crate::MatchboxSignalingServer>,
+}
+
+/// Reliable data queued for a suspended session before it gets dropped.
+const MAX_QUEUED_SESSION_BYTES: usize = 1024 * 1024;
+
+struct Session {
+    client_entity: Entity,
+    /// Time at which the peer left, `None` while connected.
+    suspended_since: Option<Duration>,
+    /// Reliable messages sent while suspended, flushed to the new peer on resume.
+    ///
+    /// Already compressed, the flag tells if they were.
+    queued: Vec<(usize, Bytes, bool)>,
+    queued_bytes: usize,
+}
+
+impl Session {
+    fn suspended_peer(&self, clients: &Query<&MatchboxClientConnection>) -> Option<PeerId> {
+        self.suspended_since?;
+        clients
+            .get(self.client_entity)
+            .ok()
+            .map(|connection| connection.peer_id)
+    }
+}
+
+/// What the host needs from a client hello once the client is accepted.
+#[derive(Clone)]
+struct Admission {
+    session: Option<SessionToken>,
+    max_packet_size: usize,
+    ban_targets: Vec<BanTarget>,
+    spectator: bool,
+}
+
+/// Credentials a client can be banned by, empty ones are skipped.
+fn ban_targets(identity: &str, token: &[u8]) -> Vec<BanTarget> {
+    let mut targets = Vec::new();
+    if !identity.is_empty() {
+        targets.push(BanTarget::Identity(identity.into()));
+    }
+    if !token.is_empty() {
+        targets.push(BanTarget::Token(token.into()));
+    }
+    targets
+}
+
+/// Credentials presented by a client during the handshake, see [`MatchboxHost::with_authenticator`].
+#[derive(Debug, Clone)]
+pub struct AuthRequest {
+    pub peer_id: PeerId,
+    /// Opaque token set with `MatchboxClient::with_auth_token`, empty if none was set.
+    pub token: Vec<u8>,
+    /// Identity set with `MatchboxClient::with_identity`, empty if none was set.
+    pub identity: String,
+}
+
+/// Outcome of a client authentication, the error is sent to the client as the rejection reason.
+pub type AuthResult = Result<(), String>;
+
+enum Authenticator {
+    Sync(Box<dyn Fn(&AuthRequest) -> AuthResult + Send + Sync>),
+    Async(Box<dyn Fn(AuthRequest) -> Task<AuthResult> + Send + Sync>),
 }
 
 impl MatchboxHost {
+    /// Joins the matchbox room at `room_url`, which can also be a [`RoomAddress`].
+    ///
+    /// Fails with [`MatchboxBackendError::InvalidUrl`] if the URL can't reach a signaling server.
     pub fn new(
         room_url: impl Into<String>,
         replicon_channels: &RepliconChannels,
-    ) -> io::Result<Self> {
-        let socket = create_matchbox_socket(room_url, replicon_channels);
+        config: MatchboxBackendConfig,
+    ) -> Result<Self, MatchboxBackendError> {
+        let room_url = room_url.into();
+        validate_room_url(&room_url)?;
+        let socket = create_matchbox_socket(room_url, replicon_channels, &config);
 
-        Ok(Self {
+        Ok(Self::from_socket(
+            socket,
+            channels_hash(replicon_channels, &config),
+            &config,
+        ))
+    }
+
+    /// Creates a host on a [`LoopbackNetwork`] instead of a signaling server room.
+    pub fn loopback(
+        network: &LoopbackNetwork,
+        replicon_channels: &RepliconChannels,
+        config: MatchboxBackendConfig,
+    ) -> Self {
+        Self::from_socket(
+            network.join(replicon_channels).into(),
+            channels_hash(replicon_channels, &config),
+            &config,
+        )
+    }
+
+    /// Creates a host on an already connected socket, used when a client takes over after a migration.
+    pub(crate) fn from_socket(
+        socket: Transport,
+        channels_hash: u64,
+        config: &MatchboxBackendConfig,
+    ) -> Self {
+        Self {
             socket,
-            // unreliable_socket,
             client_entities: HashMap::new(),
+            client_peers: HashMap::new(),
+            network_ids: HashMap::new(),
+            client_network_ids: HashMap::new(),
             clients_to_disconnect: Vec::new(),
-        })
+            game_version: String::new(),
+            channels_hash,
+            authenticator: None,
+            pending_auth: HashMap::new(),
+            max_packet_size: config.max_packet_size(),
+            heartbeat: config.heartbeat(),
+            fragmenter: Fragmenter::default(),
+            reassembler: Reassembler::new(config),
+            codec: Codec::host(config),
+            session_grace_period: Duration::ZERO,
+            sessions: HashMap::new(),
+            announced_successors: Vec::new(),
+            successor_recipients: Vec::new(),
+            max_clients: None,
+            bans: BanList::default(),
+            pending_bans: Vec::new(),
+            spectators: HashSet::new(),
+            lobby: None,
+            #[cfg(all(feature = "signaling", not(target_arch = "wasm32")))]
+            signaling_server: None,
+        }
+    }
+
+    /// Keeps `server` running as long as the host, for listen servers that do their own signaling.
+    ///
+    /// Its join and leave messages are still sent by
+    /// [`RepliconMatchboxSignalingPlugin`](crate::RepliconMatchboxSignalingPlugin).
+    #[cfg(all(feature = "signaling", not(target_arch = "wasm32")))]
+    pub fn with_signaling_server(mut self, server: crate::MatchboxSignalingServer) -> Self {
+        self.signaling_server = Some(server);
+        self
+    }
+
+    /// Returns the signaling server attached with [`Self::with_signaling_server`].
+    #[cfg(all(feature = "signaling", not(target_arch = "wasm32")))]
+    pub fn signaling_server(&self) -> Option<&crate::MatchboxSignalingServer> {
+        self.signaling_server.as_ref()
+    }
+
+    /// Keeps the entity of a client whose peer left or timed out for `grace_period`, so it can resume its
+    /// session.
+    ///
+    /// A client reconnecting with `MatchboxClient::with_reconnect` within that time is mapped back to the
+    /// same entity and [`NetworkId`]. Reliable messages sent in the meantime are delivered on resume.
+    pub fn with_session_resumption(mut self, grace_period: Duration) -> Self {
+        self.session_grace_period = grace_period;
+        self
+    }
+
+    /// Validates every client with `validate` before its [`ConnectedClient`] entity is spawned.
+    ///
+    /// Rejected clients receive the returned reason as [`HandshakeRejection::Unauthorized`].
+    pub fn with_authenticator(
+        mut self,
+        validate: impl Fn(&AuthRequest) -> AuthResult + Send + Sync + 'static,
+    ) -> Self {
+        self.authenticator = Some(Authenticator::Sync(Box::new(validate)));
+        self
+    }
+
+    /// Same as [`Self::with_authenticator`], but the validation runs as a task on the [`IoTaskPool`],
+    /// for example to ask a backend service.
+    ///
+    /// Other clients keep being served while the task is pending.
+    pub fn with_async_authenticator<F>(
+        mut self,
+        validate: impl Fn(AuthRequest) -> F + Send + Sync + 'static,
+    ) -> Self
+    where
+        F: Future<Output = AuthResult> + Send + 'static,
+    {
+        self.authenticator = Some(Authenticator::Async(Box::new(move |request| {
+            IoTaskPool::get().spawn(validate(request))
+        })));
+        self
+    }
+
+    /// Accepts at most `max_clients` players, others are rejected with [`HandshakeRejection::ServerFull`]
+    /// before their [`ConnectedClient`] entity is spawned.
+    ///
+    /// Spectators joining with `MatchboxClient::with_spectator` are always accepted.
+    pub fn with_max_clients(mut self, max_clients: usize) -> Self {
+        self.max_clients = Some(max_clients);
+        self
+    }
+
+    /// Changes the client limit at runtime, `None` removes it.
+    ///
+    /// Clients that are already connected are kept, lower the limit to the current count to lock a match.
+    pub fn set_max_clients(&mut self, max_clients: Option<usize>) {
+        self.max_clients = max_clients;
+    }
+
+    pub fn max_clients(&self) -> Option<usize> {
+        self.max_clients
+    }
+
+    /// Publishes `info` to the lobby registry of the signaling server at `address`, see `LobbyBrowser`.
+    ///
+    /// The room, player counts and game version are filled from the host and updated as clients come and
+    /// go. The room is withdrawn once the host is removed.
+    pub fn with_lobby(mut self, address: &RoomAddress, info: RoomInfo) -> Self {
+        self.lobby = Some(LobbyAdvertisement::new(address, info));
+        self
+    }
+
+    /// Returns the advertised room to change its name or properties, republished on the next frame.
+    pub fn lobby_info_mut(&mut self) -> Option<&mut RoomInfo> {
+        self.lobby.as_mut().map(|lobby| &mut lobby.info)
+    }
+
+    /// Refuses the clients on `bans` during the handshake with [`HandshakeRejection::Banned`].
+    pub fn with_ban_list(mut self, bans: BanList) -> Self {
+        self.bans = bans;
+        self
+    }
+
+    pub fn ban_list(&self) -> &BanList {
+        &self.bans
+    }
+
+    /// Returns the ban list to lift bans or save it, changes apply to the next joins.
+    pub fn ban_list_mut(&mut self) -> &mut BanList {
+        &mut self.bans
+    }
+
+    /// Sets the game version clients need to present during the handshake.
+    ///
+    /// Clients built with a different version are rejected with [`HandshakeRejection::GameVersion`].
+    pub fn with_game_version(mut self, game_version: impl Into<String>) -> Self {
+        self.game_version = game_version.into();
+        self
+    }
+
+    fn accept_client(&mut self, commands: &mut Commands, peer_id: PeerId, admission: Admission) {
+        // Both sides may limit the packet size, replicon messages also need room for the marker.
+        let max_packet_size = admission
+            .max_packet_size
+            .clamp(MIN_PACKET_SIZE, self.max_packet_size);
+
+        if let Some(token) = admission.session
+            && self.resume_session(commands, peer_id, token, max_packet_size, &admission)
+        {
+            return;
+        }
+
+        let session = (!self.session_grace_period.is_zero()).then(SessionToken::new);
+        let network_id = self.allocate_network_id(peer_id);
+        let client_entity = commands
+            .spawn((
+                ConnectedClient {
+                    max_size: max_packet_size - MARKER_LEN,
+                },
+                network_id,
+                MatchboxClientConnection {
+                    peer_id,
+                    session,
+                    max_packet_size,
+                    ban_targets: admission.ban_targets,
+                },
+                ChannelStats::default(),
+                ConnectionMonitor::new(self.heartbeat),
+            ))
+            .id();
+        trace!(
+            "new client peer: {}, network_id: {:?} entity: {}",
+            peer_id, network_id, client_entity
+        );
+        self.insert_client_peer(peer_id, client_entity);
+        self.network_ids.insert(network_id, client_entity);
+        self.client_network_ids.insert(client_entity, network_id);
+        if admission.spectator {
+            trace!("client {client_entity} joined as a spectator");
+            self.spectators.insert(client_entity);
+        }
+        if let Some(token) = session {
+            self.sessions.insert(
+                token,
+                Session {
+                    client_entity,
+                    suspended_since: None,
+                    queued: Vec::new(),
+                    queued_bytes: 0,
+                },
+            );
+            let packet = to_packet(&SystemChannelMessage::SessionAssigned(token));
+            self.socket.send(SYSTEM_CHANNEL_ID, packet, peer_id);
+        }
+        // Clamped to the size announced by the client, so it fits.
+        let packet = to_packet(&SystemChannelMessage::ConnectedToHost(
+            max_packet_size as u32,
+        ));
+        self.socket.send(SYSTEM_CHANNEL_ID, packet, peer_id);
+    }
+
+    /// Maps `peer_id` back to the entity of a session, returns `false` if there is none.
+    fn resume_session(
+        &mut self,
+        commands: &mut Commands,
+        peer_id: PeerId,
+        token: SessionToken,
+        max_packet_size: usize,
+        admission: &Admission,
+    ) -> bool {
+        let Some(session) = self.sessions.get_mut(&token) else {
+            return false;
+        };
+        let client_entity = session.client_entity;
+        if session.suspended_since.take().is_none() {
+            // The client can reconnect before the host notices the old peer is gone.
+            trace!("peer {peer_id} took over the active session of client {client_entity}");
+        }
+
+        let queued = mem::take(&mut session.queued);
+        session.queued_bytes = 0;
+        trace!("peer {peer_id} resumed session of client {client_entity}");
+        let mut entity = commands.entity(client_entity);
+        entity.insert((
+            MatchboxClientConnection {
+                peer_id,
+                session: Some(token),
+                max_packet_size,
+                ban_targets: admission.ban_targets.clone(),
+            },
+            ConnectionMonitor::new(self.heartbeat),
+        ));
+        // The new peer may allow a different size, replicon splits mutations with it.
+        entity
+            .entry::<ConnectedClient>()
+            .and_modify(move |mut client| client.max_size = max_packet_size - MARKER_LEN);
+        self.insert_client_peer(peer_id, client_entity);
+
+        let packet = to_packet(&SystemChannelMessage::ConnectedToHost(
+            max_packet_size as u32,
+        ));
+        self.socket.send(SYSTEM_CHANNEL_ID, packet, peer_id);
+        // The role could have changed while the client was away.
+        let spectator = self.spectators.contains(&client_entity);
+        let packet = to_packet(&SystemChannelMessage::Spectating(spectator));
+        self.socket.send(SYSTEM_CHANNEL_ID, packet, peer_id);
+        for (channel_id, message, compressed) in queued {
+            let socket = &mut self.socket;
+            self.fragmenter.split(
+                peer_id,
+                channel_id,
+                message,
+                compressed,
+                max_packet_size,
+                |packet| socket.send(1 + channel_id, packet, peer_id),
+            );
+        }
+
+        true
+    }
+
+    /// Derives the id from `peer_id` and skips the ones in use, since only part of the peer id fits.
+    ///
+    /// Clients connect to a new host with a new peer id, so they get a new id after a host migration.
+    /// A resumed session keeps its id.
+    fn allocate_network_id(&self, peer_id: PeerId) -> NetworkId {
+        let mut value = uuid_to_u64_truncated(peer_id);
+        while self.network_ids.contains_key(&NetworkId::new(value)) {
+            value = value.wrapping_add(1);
+        }
+        NetworkId::new(value)
+    }
+
+    /// Keeps the client of the session around until it reconnects or the grace period ends, returns
+    /// `false` if the session doesn't exist.
+    fn suspend_session(&mut self, token: SessionToken, now: Duration) -> bool {
+        let Some(session) = self.sessions.get_mut(&token) else {
+            return false;
+        };
+        trace!("suspending session of client {}", session.client_entity);
+        session.suspended_since = Some(now);
+        true
     }
 
+    /// Forgets the session, role and network id of a despawned client.
+    fn remove_client(&mut self, client_entity: Entity) {
+        self.sessions
+            .retain(|_, session| session.client_entity != client_entity);
+        self.spectators.remove(&client_entity);
+        if let Some(network_id) = self.client_network_ids.remove(&client_entity) {
+            self.network_ids.remove(&network_id);
+        }
+    }
+
+    /// Maps `peer_id` and `client_entity` to each other, replacing the previous peer of the client.
+    fn insert_client_peer(&mut self, peer_id: PeerId, client_entity: Entity) {
+        if let Some(old_peer_id) = self.client_peers.insert(client_entity, peer_id) {
+            self.client_entities.remove(&old_peer_id);
+        }
+        self.client_entities.insert(peer_id, client_entity);
+    }
+
+    /// Forgets the client of `peer_id` and returns its entity.
+    fn remove_client_peer(&mut self, peer_id: PeerId) -> Option<Entity> {
+        let client_entity = self.client_entities.remove(&peer_id)?;
+        self.client_peers.remove(&client_entity);
+        Some(client_entity)
+    }
+
+    /// Matchbox can't drop a single peer, so the client closes its socket once it receives the rejection.
+    fn reject_client(
+        &mut self,
+        peer_id: PeerId,
+        rejection: HandshakeRejection,
+        rejections: &mut MessageWriter<HandshakeRejected>,
+    ) {
+        warn!("rejecting peer {peer_id}: {rejection}");
+        let packet = to_packet(&SystemChannelMessage::HandshakeRejected(rejection.clone()));
+        self.socket.send(SYSTEM_CHANNEL_ID, packet, peer_id);
+        rejections.write(HandshakeRejected { peer_id, rejection });
+    }
+
+    fn check_hello(&self, hello: &ClientHello) -> Result<(), HandshakeRejection> {
+        if hello.protocol_version != PROTOCOL_VERSION {
+            return Err(HandshakeRejection::ProtocolVersion {
+                host: PROTOCOL_VERSION,
+                client: hello.protocol_version,
+            });
+        }
+        if hello.game_version != self.game_version {
+            return Err(HandshakeRejection::GameVersion {
+                host: self.game_version.clone(),
+                client: hello.game_version.clone(),
+            });
+        }
+        if hello.channels_hash != self.channels_hash {
+            return Err(HandshakeRejection::ChannelLayout);
+        }
+        Ok(())
+    }
+
+    /// Returns `true` if a new player can't join, clients resuming a session always can.
+    ///
+    /// Suspended sessions and pending authentications hold a slot, spectators don't.
+    fn is_full(&self, session: Option<SessionToken>) -> bool {
+        let Some(max_clients) = self.max_clients else {
+            return false;
+        };
+        if session.is_some_and(|token| self.sessions.contains_key(&token)) {
+            return false;
+        }
+        let suspended = self
+            .sessions
+            .values()
+            .filter(|session| {
+                session.suspended_since.is_some()
+                    && !self.spectators.contains(&session.client_entity)
+            })
+            .count();
+        let pending = self
+            .pending_auth
+            .values()
+            .filter(|(_, admission)| !admission.spectator)
+            .count();
+        self.players() + suspended + pending >= max_clients
+    }
+
+    /// Returns the number of connected clients, spectators included.
     pub fn connected_clients(&self) -> usize {
         self.client_entities.len()
     }
 
+    /// Returns the number of connected clients that aren't spectators.
+    pub fn players(&self) -> usize {
+        self.client_entities
+            .values()
+            .filter(|entity| !self.spectators.contains(entity))
+            .count()
+    }
+
+    pub fn is_spectator(&self, client: Entity) -> bool {
+        self.spectators.contains(&client)
+    }
+
+    /// Lets the spectator `client` send messages, returns `false` if it isn't a connected spectator.
+    ///
+    /// The client limit isn't checked, compare [`Self::players`] with [`Self::max_clients`] first.
+    pub fn promote(&mut self, client: Entity) -> bool {
+        self.set_spectator(client, false)
+    }
+
+    /// Drops the messages of `client` from now on, returns `false` if it isn't a connected player.
+    ///
+    /// Its slot is freed for another player.
+    pub fn demote(&mut self, client: Entity) -> bool {
+        self.set_spectator(client, true)
+    }
+
+    fn set_spectator(&mut self, client: Entity, spectator: bool) -> bool {
+        let Some(peer_id) = self.client_peer(client) else {
+            return false;
+        };
+        let changed = if spectator {
+            self.spectators.insert(client)
+        } else {
+            self.spectators.remove(&client)
+        };
+        if changed {
+            debug!("client {client} is now a spectator: {spectator}");
+            let packet = to_packet(&SystemChannelMessage::Spectating(spectator));
+            self.socket.send(SYSTEM_CHANNEL_ID, packet, peer_id);
+        }
+        changed
+    }
+
+    /// Iterates over the peers of the connected clients and their entities.
+    pub fn clients(&self) -> impl Iterator<Item = (PeerId, Entity)> + '_ {
+        self.client_entities
+            .iter()
+            .map(|(&peer_id, &entity)| (peer_id, entity))
+    }
+
+    /// Returns the entity of the connected client `peer_id`.
+    pub fn client_entity(&self, peer_id: PeerId) -> Option<Entity> {
+        self.client_entities.get(&peer_id).copied()
+    }
+
+    /// Returns the peer of `client`, `None` while its session is suspended.
+    pub fn client_peer(&self, client: Entity) -> Option<PeerId> {
+        self.client_peers.get(&client).copied()
+    }
+
+    /// Returns the [`NetworkId`] of `client`, which it keeps while its session is suspended.
+    pub fn network_id(&self, client: Entity) -> Option<NetworkId> {
+        self.client_network_ids.get(&client).copied()
+    }
+
+    /// Returns the client entity with `network_id`.
+    pub fn network_client(&self, network_id: NetworkId) -> Option<Entity> {
+        self.network_ids.get(&network_id).copied()
+    }
+
+    /// Returns the [`NetworkId`] of the connected client `peer_id`.
+    pub fn peer_network_id(&self, peer_id: PeerId) -> Option<NetworkId> {
+        self.client_entity(peer_id)
+            .and_then(|client| self.network_id(client))
+    }
+
+    /// Returns the peer of the client with `network_id`, `None` while its session is suspended.
+    pub fn network_peer(&self, network_id: NetworkId) -> Option<PeerId> {
+        self.network_client(network_id)
+            .and_then(|client| self.client_peer(client))
+    }
+
+    /// Queues a disconnection of all clients with [`DisconnectReason::HostShuttingDown`].
     pub fn disconnect_all(&mut self) {
-        self.clients_to_disconnect
-            .extend(self.client_entities.keys().cloned());
+        self.clients_to_disconnect.extend(
+            self.client_entities
+                .keys()
+                .map(|&peer_id| (peer_id, DisconnectReason::HostShuttingDown)),
+        );
+    }
+
+    /// Queues a disconnection of `client` with [`DisconnectReason::Kicked`], it can join again right away.
+    pub fn kick(&mut self, client: Entity, reason: impl Into<String>) {
+        self.disconnect_with_reason(client, DisconnectReason::Kicked(reason.into()));
+    }
+
+    /// Adds the identity and token of `client` to the ban list for `duration`, or permanently if `None`,
+    /// and queues its disconnection with [`DisconnectReason::Banned`].
+    ///
+    /// A client without identity nor token can't be recognized when it comes back, so it is only kicked.
+    pub fn ban(&mut self, client: Entity, duration: Option<Duration>, reason: impl Into<String>) {
+        self.pending_bans.push((client, duration, reason.into()));
+    }
+
+    /// Queues a disconnection of `client`, the reason is sent to it after its pending messages.
+    pub fn disconnect_with_reason(&mut self, client: Entity, reason: DisconnectReason) {
+        let Some(peer_id) = self.client_peer(client) else {
+            return;
+        };
+        self.clients_to_disconnect.push((peer_id, reason));
     }
 }
 
-#[derive(Component)]
-struct MatchboxClientConnection {
+/// Sent on the host when a client entity is despawned.
+#[derive(Message, Debug, Clone)]
+pub struct ClientDisconnected {
+    pub client: Entity,
     pub peer_id: PeerId,
+    pub reason: DisconnectReason,
+}
+
+/// Matchbox connection of a client entity on the host.
+///
+/// The peer changes when the client resumes its session from a new socket.
+#[derive(Component)]
+pub struct MatchboxClientConnection {
+    peer_id: PeerId,
+    session: Option<SessionToken>,
+    /// Negotiated during the handshake, larger messages are fragmented.
+    max_packet_size: usize,
+    ban_targets: Vec<BanTarget>,
+}
+
+impl MatchboxClientConnection {
+    pub fn peer_id(&self) -> PeerId {
+        self.peer_id
+    }
+}
+
+#[test]
+fn test_network_id_collision() {
+    let channels = RepliconChannels::default();
+    let mut host = MatchboxHost::loopback(
+        &LoopbackNetwork::default(),
+        &channels,
+        MatchboxBackendConfig::default(),
+    );
+    let peer_id = PeerId(uuid::Uuid::new_v4());
+    let derived = uuid_to_u64_truncated(peer_id);
+    assert_eq!(host.allocate_network_id(peer_id).get(), derived);
+
+    // Another peer with the same first 8 bytes already took the id.
+    host.network_ids
+        .insert(NetworkId::new(derived), Entity::PLACEHOLDER);
+    host.client_network_ids
+        .insert(Entity::PLACEHOLDER, NetworkId::new(derived));
+    assert_eq!(
+        host.allocate_network_id(peer_id).get(),
+        derived.wrapping_add(1)
+    );
+
+    host.remove_client(Entity::PLACEHOLDER);
+    assert_eq!(host.allocate_network_id(peer_id).get(), derived);
+}
+
+#[test]
+fn test_client_peer_lookups() {
+    let channels = RepliconChannels::default();
+    let mut host = MatchboxHost::loopback(
+        &LoopbackNetwork::default(),
+        &channels,
+        MatchboxBackendConfig::default(),
+    );
+    let client = Entity::PLACEHOLDER;
+    let old_peer_id = PeerId(uuid::Uuid::new_v4());
+    host.insert_client_peer(old_peer_id, client);
+    assert_eq!(host.client_peer(client), Some(old_peer_id));
+
+    // Resuming the session replaces the peer.
+    let peer_id = PeerId(uuid::Uuid::new_v4());
+    host.insert_client_peer(peer_id, client);
+    assert_eq!(host.client_entity(old_peer_id), None);
+    assert_eq!(host.client_entity(peer_id), Some(client));
+    assert_eq!(host.client_peer(client), Some(peer_id));
+
+    assert_eq!(host.remove_client_peer(peer_id), Some(client));
+    assert_eq!(host.client_peer(client), None);
+    assert_eq!(host.connected_clients(), 0);
 }
@@ -0,0 +1,156 @@
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use std::time::Duration;
+use std::{fs, io};
+use web_time::{SystemTime, UNIX_EPOCH};
+
+/// Clients refused by [`MatchboxHost`](crate::MatchboxHost) during the handshake.
+///
+/// Expiration times are stored as wall-clock time, so temporary bans survive a restart when the list is
+/// saved with [`Self::save`] or any serde format.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BanList {
+    bans: Vec<Ban>,
+}
+
+/// Stable credentials a ban applies to.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum BanTarget {
+    /// Identity set with `MatchboxClient::with_identity`.
+    Identity(String),
+    /// Token set with `MatchboxClient::with_auth_token`.
+    Token(Vec<u8>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Ban {
+    pub target: BanTarget,
+    /// Sent to the client when it gets refused.
+    pub reason: String,
+    /// Seconds since the Unix epoch at which the ban ends, `None` for a permanent ban.
+    pub expires_at: Option<u64>,
+}
+
+impl Ban {
+    pub fn is_expired(&self) -> bool {
+        self.expires_at
+            .is_some_and(|expires_at| now() >= expires_at)
+    }
+}
+
+impl BanList {
+    /// Bans `target` for `duration`, or permanently if `None`, replacing any previous ban of it.
+    ///
+    /// Only refuses future joins, use `MatchboxHost::ban` to also drop a connected client.
+    pub fn ban(
+        &mut self,
+        target: BanTarget,
+        duration: Option<Duration>,
+        reason: impl Into<String>,
+    ) {
+        self.unban(&target);
+        self.bans.push(Ban {
+            target,
+            reason: reason.into(),
+            expires_at: duration.map(|duration| now().saturating_add(duration.as_secs())),
+        });
+    }
+
+    /// Lifts the ban of `target`, returns `false` if it wasn't banned.
+    pub fn unban(&mut self, target: &BanTarget) -> bool {
+        let len = self.bans.len();
+        self.bans.retain(|ban| ban.target != *target);
+        self.bans.len() != len
+    }
+
+    /// Returns the active ban matching any of the credentials presented by a client.
+    pub fn find(&self, identity: &str, token: &[u8]) -> Option<&Ban> {
+        self.bans.iter().find(|ban| {
+            let matches = match &ban.target {
+                BanTarget::Identity(banned) => !identity.is_empty() && banned == identity,
+                BanTarget::Token(banned) => !token.is_empty() && banned == token,
+            };
+            matches && !ban.is_expired()
+        })
+    }
+
+    /// Drops the bans that ended.
+    pub fn remove_expired(&mut self) {
+        self.bans.retain(|ban| !ban.is_expired());
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Ban> {
+        self.bans.iter()
+    }
+
+    /// Writes the list to `path` as JSON, expired bans are skipped.
+    ///
+    /// The file can be edited by hand, tokens are written as arrays of bytes.
+    pub fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let mut active = self.clone();
+        active.remove_expired();
+        let json = serde_json::to_string_pretty(&active)?;
+        fs::write(path, json)
+    }
+
+    /// Reads a list written by [`Self::save`].
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        let json = fs::read_to_string(path)?;
+        Ok(serde_json::from_str(&json)?)
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[test]
+fn test_ban_list() {
+    let mut bans = BanList::default();
+    bans.ban(BanTarget::Identity("griefer".into()), None, "griefing");
+    bans.ban(
+        BanTarget::Token(b"cheater".to_vec()),
+        Some(Duration::ZERO),
+        "",
+    );
+
+    let ban = bans.find("griefer", b"").unwrap();
+    assert_eq!(ban.reason, "griefing");
+    assert!(
+        bans.find("", b"").is_none(),
+        "empty credentials shouldn't match"
+    );
+    assert!(
+        bans.find("player", b"cheater").is_none(),
+        "expired ban shouldn't match"
+    );
+
+    bans.remove_expired();
+    assert_eq!(bans.iter().count(), 1);
+    assert!(bans.unban(&BanTarget::Identity("griefer".into())));
+    assert!(bans.find("griefer", b"").is_none());
+}
+
+#[test]
+fn test_save_load() {
+    let mut bans = BanList::default();
+    bans.ban(
+        BanTarget::Token(b"token".to_vec()),
+        Some(Duration::from_secs(60)),
+        "spam",
+    );
+    bans.ban(BanTarget::Identity("griefer".into()), None, "griefing");
+    let path = std::env::temp_dir().join(format!("bans-{}.json", uuid::Uuid::new_v4()));
+    bans.save(&path).unwrap();
+    let json = fs::read_to_string(&path).unwrap();
+    let loaded = BanList::load(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+    assert_eq!(loaded, bans);
+
+    let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+    assert_eq!(value["bans"][1]["target"]["Identity"], "griefer");
+    assert_eq!(value["bans"][1]["expires_at"], serde_json::Value::Null);
+}
@@ -1,26 +1,214 @@
 use bevy::app::{PluginGroup, PluginGroupBuilder};
+use bevy::prelude::Message;
 use bevy_matchbox::MatchboxSocket;
-use bevy_matchbox::matchbox_socket::{ChannelConfig, Packet};
+use bevy_matchbox::matchbox_socket::{ChannelConfig, MessageLoopFuture, Packet, PeerId};
 use bevy_replicon::postcard;
 use bevy_replicon::prelude::{Channel, RepliconChannels};
-use bytes::Bytes;
+use futures::channel::oneshot;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use uuid::Uuid;
+
+mod compression;
+mod conditioner;
+mod config;
+mod error;
+mod fragmentation;
+mod lifecycle;
+mod loopback;
+mod room;
+mod stats;
+mod transport;
+
+pub(crate) use compression::Codec;
+#[cfg(feature = "lz4_flex")]
+pub use compression::Lz4Compressor;
+#[cfg(feature = "zstd")]
+pub use compression::ZstdCompressor;
+pub use compression::{ChannelCompression, PacketCompressor};
+pub(crate) use conditioner::Conditioner;
+pub use conditioner::{DirectionConditions, LinkConditions, NetworkConditions};
+pub(crate) use config::Heartbeat;
+pub use config::{MIN_PACKET_SIZE, MatchboxBackendConfig, TurnCredentials};
+pub use error::MatchboxBackendError;
+pub(crate) use error::validate_room_url;
+#[cfg(feature = "bench")]
+#[doc(hidden)]
+pub use fragmentation::PacketPath;
+pub(crate) use fragmentation::{Fragmenter, Reassembler};
+pub use lifecycle::{
+    HostAssigned, MalformedPacket, MalformedPacketKind, MatchboxConnectionPhase, PeerJoined,
+    PeerLeft, SocketClosed,
+};
+pub(crate) use lifecycle::{LifecycleMessages, add_lifecycle_messages};
+pub use loopback::{LoopbackNetwork, LoopbackSocket};
+pub use room::{RoomAddress, RoomCode, RoomCodeError};
+pub(crate) use stats::ConnectionMonitor;
+pub use stats::{ChannelStats, ChannelTraffic};
+pub use transport::Transport;
 
 //Required to communicate which peer is the host before we start using replicon
 pub(super) const SYSTEM_CHANNEL_ID: usize = 0;
 
+/// Version of the system channel protocol spoken by this crate.
+///
+/// Bumped whenever the messages exchanged on the system channel or the framing of replicon packets
+/// change in an incompatible way.
+pub const PROTOCOL_VERSION: u32 = 4;
+
 #[derive(Debug, Serialize, Deserialize, PartialEq)]
 pub(super) enum SystemChannelMessage {
-    ConnectedToHost,
-    HostRequestsDisconnect,
+    /// Accepts the client with the largest packet both peers allow.
+    ConnectedToHost(u32),
+    HostRequestsDisconnect(DisconnectReason),
     ClientDisconnects,
+    Hello(ClientHello),
+    HandshakeRejected(HandshakeRejection),
+    SessionAssigned(SessionToken),
+    /// Clients in the order they take over if the host leaves, sent by the host whenever it changes.
+    Successors(Vec<PeerId>),
+    /// Sent by the client that took over after the host left.
+    NewHost,
+    /// Answered with a [`Self::Pong`] carrying the same id to measure the round-trip time.
+    Ping(u32),
+    Pong(u32),
+    /// Sent by the host when it promotes a spectator or demotes a player, with the new role.
+    Spectating(bool),
+}
+
+/// First message sent by a client to every peer it connects to, answered by the host only.
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+pub(super) struct ClientHello {
+    pub protocol_version: u32,
+    pub game_version: String,
+    pub channels_hash: u64,
+    pub auth_token: Vec<u8>,
+    pub identity: String,
+    /// Token of the session to resume after a reconnect.
+    pub session: Option<SessionToken>,
+    /// Largest packet the client accepts, the host uses the lower of both limits.
+    pub max_packet_size: u32,
+    /// Joins without a player slot, messages from spectators are dropped by the host.
+    pub spectator: bool,
+}
+
+/// Identifies a client across reconnects, handed out by the host when session resumption is enabled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct SessionToken(Uuid);
+
+impl SessionToken {
+    #[cfg(feature = "server")]
+    pub(super) fn new() -> Self {
+        Self(Uuid::new_v4())
+    }
+}
+
+/// Reason why the host refused a client during the handshake.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub enum HandshakeRejection {
+    /// The client speaks a different [`PROTOCOL_VERSION`].
+    ProtocolVersion { host: u32, client: u32 },
+    /// The game versions passed to `with_game_version` differ.
+    GameVersion { host: String, client: String },
+    /// The peers were built with a different [`RepliconChannels`] layout or channel compression.
+    ChannelLayout,
+    /// The host authenticator refused the client with the given reason.
+    Unauthorized(String),
+    /// The host already has as many clients as allowed by `MatchboxHost::with_max_clients`.
+    ServerFull,
+    /// The client is on the host `BanList`, with the reason of the ban.
+    Banned(String),
+}
+
+impl std::fmt::Display for HandshakeRejection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HandshakeRejection::ProtocolVersion { host, client } => write!(
+                f,
+                "protocol version mismatch (host: {host}, client: {client})"
+            ),
+            HandshakeRejection::GameVersion { host, client } => {
+                write!(f, "game version mismatch (host: {host}, client: {client})")
+            }
+            HandshakeRejection::ChannelLayout => write!(f, "replicon channel layout mismatch"),
+            HandshakeRejection::Unauthorized(reason) => write!(f, "unauthorized: {reason}"),
+            HandshakeRejection::ServerFull => write!(f, "server full"),
+            HandshakeRejection::Banned(reason) if reason.is_empty() => write!(f, "banned"),
+            HandshakeRejection::Banned(reason) => write!(f, "banned: {reason}"),
+        }
+    }
+}
+
+/// Why a client and the host stopped talking to each other.
+///
+/// Sent by the host before it drops a client and reported on both sides.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub enum DisconnectReason {
+    /// Removed by the host, with an optional message for the player.
+    Kicked(String),
+    Banned,
+    ServerFull,
+    /// Protocol, game version or channel layout differ, see [`HandshakeRejection`].
+    VersionMismatch,
+    /// Refused by the host authenticator with the given reason.
+    Unauthorized(String),
+    HostShuttingDown,
+    /// Nothing was received from the peer for too long.
+    Timeout,
+    /// The matchbox socket was closed.
+    SocketError,
+    /// The peer left the room without saying goodbye.
+    ConnectionLost,
+    /// The client disconnected on its own.
+    Left,
+}
+
+impl std::fmt::Display for DisconnectReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DisconnectReason::Kicked(message) if message.is_empty() => write!(f, "kicked"),
+            DisconnectReason::Kicked(message) => write!(f, "kicked: {message}"),
+            DisconnectReason::Banned => write!(f, "banned"),
+            DisconnectReason::ServerFull => write!(f, "server full"),
+            DisconnectReason::VersionMismatch => write!(f, "version mismatch"),
+            DisconnectReason::Unauthorized(reason) => write!(f, "unauthorized: {reason}"),
+            DisconnectReason::HostShuttingDown => write!(f, "host shutting down"),
+            DisconnectReason::Timeout => write!(f, "timed out"),
+            DisconnectReason::SocketError => write!(f, "socket error"),
+            DisconnectReason::ConnectionLost => write!(f, "connection lost"),
+            DisconnectReason::Left => write!(f, "left"),
+        }
+    }
+}
+
+impl From<HandshakeRejection> for DisconnectReason {
+    fn from(rejection: HandshakeRejection) -> Self {
+        match rejection {
+            HandshakeRejection::ProtocolVersion { .. }
+            | HandshakeRejection::GameVersion { .. }
+            | HandshakeRejection::ChannelLayout => DisconnectReason::VersionMismatch,
+            HandshakeRejection::Unauthorized(reason) => DisconnectReason::Unauthorized(reason),
+            HandshakeRejection::ServerFull => DisconnectReason::ServerFull,
+            HandshakeRejection::Banned(_) => DisconnectReason::Banned,
+        }
+    }
+}
+
+/// Sent on both sides when the handshake between a client and the host fails.
+///
+/// On the host `peer_id` is the rejected client, on the client it is the host that rejected us.
+#[derive(Message, Debug, Clone)]
+pub struct HandshakeRejected {
+    pub peer_id: PeerId,
+    pub rejection: HandshakeRejection,
 }
 
 pub struct RepliconMatchboxPlugins;
 
 impl PluginGroup for RepliconMatchboxPlugins {
     fn build(self) -> PluginGroupBuilder {
-        let mut group = PluginGroupBuilder::start::<Self>();
+        let mut group =
+            PluginGroupBuilder::start::<Self>().add(crate::lobby::RepliconMatchboxLobbyPlugin);
 
         #[cfg(feature = "server")]
         {
@@ -34,6 +222,18 @@ impl PluginGroup for RepliconMatchboxPlugins {
             group = group.add(RepliconMatchboxClientPlugin);
         }
 
+        #[cfg(all(feature = "client", feature = "server"))]
+        {
+            use crate::matchmaking::RepliconMatchboxMatchmakingPlugin;
+            group = group.add(RepliconMatchboxMatchmakingPlugin);
+        }
+
+        #[cfg(all(feature = "signaling", not(target_arch = "wasm32")))]
+        {
+            use crate::signaling::RepliconMatchboxSignalingPlugin;
+            group = group.add(RepliconMatchboxSignalingPlugin);
+        }
+
         group
     }
 }
@@ -52,11 +252,82 @@ impl<'a> RepliconChannelsExt<'a> for RepliconChannels {
     }
 }
 
+/// Hashes the layout of the replicon channels and their compression, used to detect peers built
+/// with different channels.
+///
+/// Uses FNV-1a instead of the std hasher to stay stable across compilers and targets.
+pub(super) fn channels_hash(
+    replicon_channels: &RepliconChannels,
+    config: &MatchboxBackendConfig,
+) -> u64 {
+    layout_hash(
+        &channel_layout(
+            replicon_channels.server_channels(),
+            config.server_compression(),
+        ),
+        &channel_layout(
+            replicon_channels.client_channels(),
+            config.client_compression(),
+        ),
+    )
+}
+
+/// Returns the kind of each channel, flagged when its messages are compressed.
+///
+/// The codec itself isn't part of the layout, so peers still need the same one.
+fn channel_layout(
+    channels: &[Channel],
+    compression: &HashMap<usize, ChannelCompression>,
+) -> Vec<u8> {
+    const COMPRESSED: u8 = 0x10;
+
+    channels
+        .iter()
+        .enumerate()
+        .map(|(channel_id, channel)| {
+            if compression.contains_key(&channel_id) {
+                channel_kind(channel) | COMPRESSED
+            } else {
+                channel_kind(channel)
+            }
+        })
+        .collect()
+}
+
+fn layout_hash(server_channels: &[u8], client_channels: &[u8]) -> u64 {
+    const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+    const FNV_PRIME: u64 = 0x0100_0000_01b3;
+
+    let bytes = server_channels
+        .iter()
+        .copied()
+        .chain(std::iter::once(u8::MAX))
+        .chain(client_channels.iter().copied());
+
+    bytes.fold(FNV_OFFSET, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(FNV_PRIME)
+    })
+}
+
+fn channel_kind(channel: &Channel) -> u8 {
+    match channel {
+        Channel::Unreliable => 0,
+        Channel::Unordered => 1,
+        Channel::Ordered => 2,
+    }
+}
+
+/// Opens a socket with the system channel followed by the replicon channels.
+///
+/// The URL should be checked with [`validate_room_url`] first.
 pub(super) fn create_matchbox_socket(
     room_url: impl Into<String>,
     replicon_channels: &RepliconChannels,
-) -> MatchboxSocket {
-    let mut web_rtc_socket = bevy_matchbox::matchbox_socket::WebRtcSocketBuilder::new(room_url);
+    config: &MatchboxBackendConfig,
+) -> Transport {
+    let mut web_rtc_socket = config.apply(
+        bevy_matchbox::matchbox_socket::WebRtcSocketBuilder::new(room_url),
+    );
     //add system channel
     web_rtc_socket = web_rtc_socket.add_reliable_channel();
     for &channel in replicon_channels.all_channels() {
@@ -75,34 +346,30 @@ pub(super) fn create_matchbox_socket(
             }
         };
     }
-    let socket = web_rtc_socket.build();
-    MatchboxSocket::from(socket)
+    let (socket, message_loop) = web_rtc_socket.build();
+    // Keep the reason the message loop stopped, `MatchboxSocket` drops it.
+    let (outcome_tx, outcome) = oneshot::channel();
+    let message_loop: MessageLoopFuture = Box::pin(async move {
+        let result = message_loop.await;
+        let _ = outcome_tx.send(result.as_ref().err().map(MatchboxBackendError::from));
+        result
+    });
+    Transport::with_signaling(MatchboxSocket::from((socket, message_loop)), outcome)
 }
 
-#[cfg(feature = "server")]
-use bevy_matchbox::matchbox_socket::PeerId;
 #[cfg(feature = "server")]
 pub(super) fn uuid_to_u64_truncated(peer_id: PeerId) -> u64 {
     let bytes = peer_id.0.as_bytes();
     u64::from_le_bytes(bytes[0..8].try_into().unwrap())
 }
 
-///Marker added as matchbox seems to drop 0 sized packages
-pub(super) fn add_marker(data: &[u8]) -> Packet {
-    let mut payload = Vec::with_capacity(data.len() + 1);
-    payload.push(0);
-    payload.extend_from_slice(data);
-    payload.into()
-}
-
-///Marker stripped as matchbox seems to drop 0 sized packages
-pub(super) fn strip_marker(packet: &[u8]) -> Bytes {
-    Bytes::copy_from_slice(&packet[1..])
-}
+/// Size of the kind byte appended to every replicon packet, see [`Fragmenter`].
+pub(super) const MARKER_LEN: usize = 1;
 
-pub(super) fn to_packet<'a, T: Serialize>(msg: &T, buf: &'a mut [u8]) -> &'a [u8] {
-    use bevy_replicon::postcard::to_slice;
-    to_slice(msg, buf).expect("serialize failed")
+pub(super) fn to_packet<T: Serialize>(msg: &T) -> Packet {
+    postcard::to_extend(msg, Vec::new())
+        .expect("serialize failed")
+        .into()
 }
 
 pub(super) fn from_packet<'a, T: Deserialize<'a>>(
@@ -114,13 +381,94 @@ pub(super) fn from_packet<'a, T: Deserialize<'a>>(
 #[test]
 fn test_packaging() {
     let messages = [
-        SystemChannelMessage::ConnectedToHost,
-        SystemChannelMessage::HostRequestsDisconnect,
+        SystemChannelMessage::NewHost,
+        SystemChannelMessage::ClientDisconnects,
     ];
     for msg in messages.iter() {
-        let mut buf = [0u8; 1];
-        let p = to_packet(&msg, &mut buf);
-        let deserialized: SystemChannelMessage = from_packet(p).unwrap();
+        let p = to_packet(&msg);
+        assert_eq!(p.len(), 1);
+        let deserialized: SystemChannelMessage = from_packet(&p).unwrap();
         assert_eq!(*msg, deserialized);
     }
 }
+
+#[test]
+fn test_disconnect_packaging() {
+    let reasons = [
+        DisconnectReason::Kicked("afk".into()),
+        DisconnectReason::HostShuttingDown,
+        DisconnectReason::Timeout,
+    ];
+    for reason in reasons {
+        let msg = SystemChannelMessage::HostRequestsDisconnect(reason);
+        let p = to_packet(&msg);
+        let deserialized: SystemChannelMessage = from_packet(&p).unwrap();
+        assert_eq!(msg, deserialized);
+    }
+}
+
+#[test]
+fn test_connected_packaging() {
+    let msg = SystemChannelMessage::ConnectedToHost(1200);
+    let p = to_packet(&msg);
+    let deserialized: SystemChannelMessage = from_packet(&p).unwrap();
+    assert_eq!(msg, deserialized);
+}
+
+#[test]
+fn test_successors_packaging() {
+    let msg = SystemChannelMessage::Successors(vec![
+        PeerId(Uuid::from_u128(1)),
+        PeerId(Uuid::from_u128(2)),
+    ]);
+    let p = to_packet(&msg);
+    let deserialized: SystemChannelMessage = from_packet(&p).unwrap();
+    assert_eq!(msg, deserialized);
+}
+
+#[test]
+fn test_hello_packaging() {
+    let msg = SystemChannelMessage::Hello(ClientHello {
+        protocol_version: PROTOCOL_VERSION,
+        game_version: "1.2.3".into(),
+        channels_hash: channels_hash(
+            &RepliconChannels::default(),
+            &MatchboxBackendConfig::default(),
+        ),
+        auth_token: b"secret".to_vec(),
+        identity: "player".into(),
+        session: Some(SessionToken(Uuid::from_u128(42))),
+        max_packet_size: 1200,
+        spectator: true,
+    });
+    let p = to_packet(&msg);
+    let deserialized: SystemChannelMessage = from_packet(&p).unwrap();
+    assert_eq!(msg, deserialized);
+}
+
+#[test]
+fn test_channels_hash() {
+    let layout = |channels: &[Channel]| channel_layout(channels, &HashMap::new());
+    let server = layout(&[Channel::Ordered, Channel::Unreliable]);
+    let client = layout(&[Channel::Ordered]);
+
+    assert_eq!(layout_hash(&server, &client), layout_hash(&server, &client));
+    assert_ne!(
+        layout_hash(&server, &client),
+        layout_hash(&server, &layout(&[Channel::Unordered]))
+    );
+    assert_ne!(
+        layout_hash(&server, &client),
+        layout_hash(
+            &server[..1],
+            &layout(&[Channel::Unreliable, Channel::Ordered])
+        )
+    );
+
+    let compression = HashMap::from([(1, ChannelCompression::new(compression::RunLength))]);
+    let compressed = channel_layout(&[Channel::Ordered, Channel::Unreliable], &compression);
+    assert_ne!(
+        layout_hash(&server, &client),
+        layout_hash(&compressed, &client)
+    );
+}
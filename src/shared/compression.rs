@@ -0,0 +1,319 @@
+use super::{ChannelStats, MatchboxBackendConfig};
+use bevy::log::error;
+use bytes::Bytes;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+
+/// Codec for the messages of a replicon channel, see [`ChannelCompression`].
+///
+/// The `lz4_flex` and `zstd` features provide [`Lz4Compressor`] and [`ZstdCompressor`], other
+/// libraries can be plugged in by implementing it. Both peers need the same codec for the channel.
+pub trait PacketCompressor: Send + Sync + 'static {
+    /// Returns the compressed `data`.
+    fn compress(&self, data: &[u8]) -> Vec<u8>;
+
+    /// Returns the decompressed `data`, `None` if it's corrupted or would exceed `max_size`.
+    fn decompress(&self, data: &[u8], max_size: usize) -> Option<Vec<u8>>;
+}
+
+/// Compression of a replicon channel, passed to [`MatchboxBackendConfig::with_server_channel_compression`]
+/// or [`MatchboxBackendConfig::with_client_channel_compression`].
+///
+/// Messages are compressed before being split into packets. Messages under the minimum size and the
+/// ones that don't shrink are sent as is, a flag in the kind byte of their packets tells them apart.
+#[derive(Clone)]
+pub struct ChannelCompression {
+    compressor: Arc<dyn PacketCompressor>,
+    min_size: usize,
+}
+
+impl ChannelCompression {
+    /// Compresses messages of at least 64 bytes with `compressor`.
+    pub fn new(compressor: impl PacketCompressor) -> Self {
+        Self {
+            compressor: Arc::new(compressor),
+            min_size: 64,
+        }
+    }
+
+    /// Sets the size under which messages aren't worth compressing.
+    pub fn with_min_size(mut self, bytes: usize) -> Self {
+        self.min_size = bytes;
+        self
+    }
+}
+
+impl fmt::Debug for ChannelCompression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ChannelCompression")
+            .field("min_size", &self.min_size)
+            .finish_non_exhaustive()
+    }
+}
+
+/// LZ4 codec from `lz4_flex`, cheap enough for every message but with a lower ratio than zstd.
+#[cfg(feature = "lz4_flex")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Lz4Compressor;
+
+#[cfg(feature = "lz4_flex")]
+impl PacketCompressor for Lz4Compressor {
+    fn compress(&self, data: &[u8]) -> Vec<u8> {
+        lz4_flex::compress_prepend_size(data)
+    }
+
+    fn decompress(&self, data: &[u8], max_size: usize) -> Option<Vec<u8>> {
+        // Checked before decompressing, the buffer is allocated with the prepended size.
+        let (size, data) = lz4_flex::block::uncompressed_size(data).ok()?;
+        if size > max_size {
+            return None;
+        }
+        lz4_flex::decompress(data, size).ok()
+    }
+}
+
+/// Zstandard codec from `zstd`, optionally with a dictionary.
+#[cfg(feature = "zstd")]
+pub struct ZstdCompressor {
+    level: i32,
+    dictionary: Option<(
+        zstd::dict::EncoderDictionary<'static>,
+        zstd::dict::DecoderDictionary<'static>,
+    )>,
+}
+
+#[cfg(feature = "zstd")]
+impl ZstdCompressor {
+    /// Compresses with `level`, from 1 to 22.
+    pub fn new(level: i32) -> Self {
+        Self {
+            level,
+            dictionary: None,
+        }
+    }
+
+    /// Compresses with `dictionary`, which improves the ratio of small messages.
+    ///
+    /// Train it on typical messages of the channel with `zstd::dict::from_samples`. Both peers need
+    /// the same dictionary.
+    pub fn with_dictionary(mut self, dictionary: &[u8]) -> Self {
+        self.dictionary = Some((
+            zstd::dict::EncoderDictionary::copy(dictionary, self.level),
+            zstd::dict::DecoderDictionary::copy(dictionary),
+        ));
+        self
+    }
+}
+
+#[cfg(feature = "zstd")]
+impl Default for ZstdCompressor {
+    /// Uses the default level of zstd, 3.
+    fn default() -> Self {
+        Self::new(zstd::DEFAULT_COMPRESSION_LEVEL)
+    }
+}
+
+#[cfg(feature = "zstd")]
+impl fmt::Debug for ZstdCompressor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ZstdCompressor")
+            .field("level", &self.level)
+            .field("dictionary", &self.dictionary.is_some())
+            .finish()
+    }
+}
+
+#[cfg(feature = "zstd")]
+impl PacketCompressor for ZstdCompressor {
+    fn compress(&self, data: &[u8]) -> Vec<u8> {
+        let compressed = match &self.dictionary {
+            Some((dictionary, _)) => zstd::bulk::Compressor::with_prepared_dictionary(dictionary)
+                .and_then(|mut compressor| compressor.compress(data)),
+            None => zstd::bulk::compress(data, self.level),
+        };
+        // Not smaller, so the message is sent as is.
+        compressed.unwrap_or_else(|e| {
+            error!("unable to compress message of {} bytes: {e}", data.len());
+            data.to_vec()
+        })
+    }
+
+    fn decompress(&self, data: &[u8], max_size: usize) -> Option<Vec<u8>> {
+        // Fails instead of growing past `max_size`.
+        match &self.dictionary {
+            Some((_, dictionary)) => zstd::bulk::Decompressor::with_prepared_dictionary(dictionary)
+                .and_then(|mut decompressor| decompressor.decompress(data, max_size))
+                .ok(),
+            None => zstd::bulk::decompress(data, max_size).ok(),
+        }
+    }
+}
+
+/// Compresses the messages sent by the local peer and decompresses the received ones.
+pub(crate) struct Codec {
+    outgoing: HashMap<usize, ChannelCompression>,
+    incoming: HashMap<usize, ChannelCompression>,
+    /// Largest decompressed message.
+    max_size: usize,
+}
+
+impl Codec {
+    /// Sends on the server channels and receives on the client channels.
+    #[cfg(feature = "server")]
+    pub(crate) fn host(config: &MatchboxBackendConfig) -> Self {
+        Self {
+            outgoing: config.server_compression().clone(),
+            incoming: config.client_compression().clone(),
+            max_size: config.max_reassembly_bytes(),
+        }
+    }
+
+    /// Sends on the client channels and receives on the server channels.
+    #[cfg(feature = "client")]
+    pub(crate) fn client(config: &MatchboxBackendConfig) -> Self {
+        Self {
+            outgoing: config.client_compression().clone(),
+            incoming: config.server_compression().clone(),
+            max_size: config.max_reassembly_bytes(),
+        }
+    }
+
+    /// Returns the message to send and whether it was compressed.
+    pub(crate) fn compress(
+        &self,
+        channel_id: usize,
+        message: Bytes,
+        stats: &mut ChannelStats,
+    ) -> (Bytes, bool) {
+        let Some(compression) = self.outgoing.get(&channel_id) else {
+            return (message, false);
+        };
+        if message.len() < compression.min_size {
+            return (message, false);
+        }
+
+        let compressed = compression.compressor.compress(&message);
+        if compressed.len() >= message.len() {
+            return (message, false);
+        }
+        stats.record_compressed_sent(channel_id, message.len(), compressed.len());
+        (compressed.into(), true)
+    }
+
+    /// Returns the received message, `None` if it couldn't be decompressed.
+    pub(crate) fn decompress(
+        &self,
+        channel_id: usize,
+        message: Bytes,
+        compressed: bool,
+        stats: &mut ChannelStats,
+    ) -> Option<Bytes> {
+        if !compressed {
+            return Some(message);
+        }
+        let Some(compression) = self.incoming.get(&channel_id) else {
+            error!("received a compressed message on channel {channel_id} without compression");
+            return None;
+        };
+
+        let Some(decompressed) = compression.compressor.decompress(&message, self.max_size) else {
+            error!("unable to decompress message of {} bytes", message.len());
+            return None;
+        };
+        stats.record_compressed_received(channel_id, decompressed.len(), message.len());
+        Some(decompressed.into())
+    }
+}
+
+/// Replaces runs of the same byte with the byte and the run length.
+#[cfg(test)]
+pub(super) struct RunLength;
+
+#[cfg(test)]
+impl PacketCompressor for RunLength {
+    fn compress(&self, data: &[u8]) -> Vec<u8> {
+        let mut output = Vec::new();
+        for chunk in data.chunk_by(|a, b| a == b) {
+            for run in chunk.chunks(u8::MAX as usize) {
+                output.extend([run[0], run.len() as u8]);
+            }
+        }
+        output
+    }
+
+    fn decompress(&self, data: &[u8], max_size: usize) -> Option<Vec<u8>> {
+        let mut output = Vec::new();
+        for pair in data.chunks(2) {
+            let &[byte, len] = pair else {
+                return None;
+            };
+            if output.len() + len as usize > max_size {
+                return None;
+            }
+            output.extend(std::iter::repeat_n(byte, len as usize));
+        }
+        Some(output)
+    }
+}
+
+#[cfg(all(feature = "client", feature = "server"))]
+#[test]
+fn test_compression() {
+    let config = MatchboxBackendConfig::default()
+        .with_server_channel_compression(1, ChannelCompression::new(RunLength).with_min_size(16))
+        .with_max_reassembly_bytes(1000);
+    let host = Codec::host(&config);
+    let client = Codec::client(&config);
+    let mut host_stats = ChannelStats::default();
+    let mut client_stats = ChannelStats::default();
+
+    let message = Bytes::from(vec![7; 500]);
+    let (compressed, is_compressed) = host.compress(1, message.clone(), &mut host_stats);
+    assert!(is_compressed);
+    assert_eq!(compressed.len(), 4);
+    assert_eq!(host_stats.sent[1].compression_ratio(), Some(4.0 / 500.0));
+
+    let received = client.decompress(1, compressed, true, &mut client_stats);
+    assert_eq!(received, Some(message));
+    assert_eq!(client_stats.received[1].uncompressed_bytes, 500);
+
+    for (channel_id, message) in [(0, vec![7; 500]), (1, vec![7; 8]), (1, (0..32).collect())] {
+        let (_, is_compressed) = host.compress(channel_id, message.into(), &mut host_stats);
+        assert!(!is_compressed, "should skip compression");
+    }
+
+    let bomb = Bytes::from([7, u8::MAX].repeat(10));
+    assert_eq!(client.decompress(1, bomb, true, &mut client_stats), None);
+    let unknown = Bytes::from_static(&[7, 2]);
+    assert_eq!(client.decompress(0, unknown, true, &mut client_stats), None);
+}
+
+/// Checks that `compressor` shrinks a repetitive message, restores it and refuses to exceed the limit.
+#[cfg(all(test, any(feature = "lz4_flex", feature = "zstd")))]
+fn check_round_trip(compressor: &impl PacketCompressor) {
+    let message: Vec<u8> = (0..1000).map(|i| (i % 10) as u8).collect();
+    let compressed = compressor.compress(&message);
+    assert!(compressed.len() < message.len());
+    assert_eq!(
+        compressor.decompress(&compressed, message.len()),
+        Some(message.clone())
+    );
+    assert_eq!(compressor.decompress(&compressed, message.len() - 1), None);
+    assert_eq!(compressor.decompress(&[0xFF; 8], message.len()), None);
+}
+
+#[cfg(feature = "lz4_flex")]
+#[test]
+fn test_lz4() {
+    check_round_trip(&Lz4Compressor);
+}
+
+#[cfg(feature = "zstd")]
+#[test]
+fn test_zstd() {
+    check_round_trip(&ZstdCompressor::default());
+
+    let dictionary: Vec<u8> = (0..64).collect();
+    check_round_trip(&ZstdCompressor::new(5).with_dictionary(&dictionary));
+}
@@ -0,0 +1,392 @@
+use bevy::platform::time::Instant;
+use bevy::prelude::*;
+use bevy_matchbox::matchbox_socket::{Packet, PeerId};
+use bevy_replicon::prelude::Channel;
+use std::collections::HashMap;
+use std::time::Duration;
+
+/// Retransmissions simulated for a single lost packet on a reliable channel.
+const MAX_RETRANSMISSIONS: u32 = 8;
+
+/// Degrades the connection of the local host or client to test the netcode under bad conditions.
+///
+/// Insert it as a resource next to `MatchboxHost` or `MatchboxClient`, changes apply on the next frame
+/// and removing it goes back to the real connection. Conditions are applied on every channel, including
+/// the system channel used for the handshake and heartbeats, which counts as [`Channel::Ordered`].
+///
+/// Reliable channels keep their guarantees: lost packets arrive late as if retransmitted, nothing is
+/// duplicated and ordered channels never reorder.
+#[derive(Resource, Debug, Clone, Copy, Default, PartialEq)]
+pub struct NetworkConditions {
+    /// Applied to packets sent by the local peer.
+    pub outgoing: DirectionConditions,
+    /// Applied to packets received by the local peer.
+    pub incoming: DirectionConditions,
+    /// Seed of the random delays, losses and duplications, random if `None`.
+    pub seed: Option<u64>,
+}
+
+impl NetworkConditions {
+    /// Applies `conditions` in both directions on every channel kind.
+    pub fn symmetric(conditions: LinkConditions) -> Self {
+        Self {
+            outgoing: DirectionConditions::uniform(conditions),
+            incoming: DirectionConditions::uniform(conditions),
+            seed: None,
+        }
+    }
+
+    /// Makes the conditions reproducible for tests, the same packets are then delayed and lost each run.
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.seed = Some(seed);
+        self
+    }
+}
+
+/// Conditions of one direction for each [`Channel`] kind, see [`NetworkConditions`].
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct DirectionConditions {
+    pub unreliable: LinkConditions,
+    pub unordered: LinkConditions,
+    pub ordered: LinkConditions,
+}
+
+impl DirectionConditions {
+    /// Applies `conditions` to every channel kind.
+    pub fn uniform(conditions: LinkConditions) -> Self {
+        Self {
+            unreliable: conditions,
+            unordered: conditions,
+            ordered: conditions,
+        }
+    }
+
+    fn get(&self, channel: Channel) -> &LinkConditions {
+        match channel {
+            Channel::Unreliable => &self.unreliable,
+            Channel::Unordered => &self.unordered,
+            Channel::Ordered => &self.ordered,
+        }
+    }
+}
+
+/// Simulated properties of a link, chances range from 0 to 1.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct LinkConditions {
+    /// Delay added to every packet.
+    pub latency: Duration,
+    /// Random extra delay of up to this value, reorders packets on unordered channels.
+    pub jitter: Duration,
+    /// Chance to lose a packet, reliable channels resend it after a round trip instead.
+    pub loss: f32,
+    /// Chance to deliver a packet twice, unreliable channels only.
+    pub duplication: f32,
+    /// Chance to hold a packet back by another latency and jitter, unordered channels only.
+    pub reordering: f32,
+}
+
+/// Delays, drops and duplicates packets according to [`NetworkConditions`].
+pub(crate) struct Conditioner {
+    conditions: NetworkConditions,
+    /// Kind of each socket channel.
+    channels: Vec<Channel>,
+    outgoing: DelayQueue,
+    incoming: DelayQueue,
+}
+
+impl Conditioner {
+    pub(crate) fn new(conditions: NetworkConditions, channels: Vec<Channel>) -> Self {
+        let mut rng = conditions
+            .seed
+            .map_or_else(fastrand::Rng::new, fastrand::Rng::with_seed);
+        Self {
+            conditions,
+            outgoing: DelayQueue::new(channels.len(), rng.fork()),
+            incoming: DelayQueue::new(channels.len(), rng),
+            channels,
+        }
+    }
+
+    pub(crate) fn set_conditions(&mut self, conditions: NetworkConditions) {
+        if let Some(seed) = conditions.seed
+            && conditions.seed != self.conditions.seed
+        {
+            let mut rng = fastrand::Rng::with_seed(seed);
+            self.outgoing.rng = rng.fork();
+            self.incoming.rng = rng;
+        }
+        self.conditions = conditions;
+    }
+
+    pub(crate) fn channel_count(&self) -> usize {
+        self.channels.len()
+    }
+
+    /// Returns `true` if no packet is waiting in either direction.
+    pub(crate) fn is_empty(&self) -> bool {
+        self.outgoing.is_empty() && self.incoming.is_empty()
+    }
+
+    pub(crate) fn push_outgoing(
+        &mut self,
+        channel_id: usize,
+        peer_id: PeerId,
+        packet: Packet,
+        now: Instant,
+    ) {
+        let kind = self.channels[channel_id];
+        let conditions = self.conditions.outgoing.get(kind);
+        self.outgoing
+            .push(conditions, kind, channel_id, peer_id, packet, now);
+    }
+
+    pub(crate) fn push_incoming(
+        &mut self,
+        channel_id: usize,
+        peer_id: PeerId,
+        packet: Packet,
+        now: Instant,
+    ) {
+        let kind = self.channels[channel_id];
+        let conditions = self.conditions.incoming.get(kind);
+        self.incoming
+            .push(conditions, kind, channel_id, peer_id, packet, now);
+    }
+
+    /// Returns the outgoing packets of `channel_id` that should be sent by `now`.
+    pub(crate) fn take_outgoing(
+        &mut self,
+        channel_id: usize,
+        now: Instant,
+    ) -> Vec<(PeerId, Packet)> {
+        self.outgoing.take_due(channel_id, now)
+    }
+
+    /// Returns the incoming packets of `channel_id` that should be received by `now`.
+    pub(crate) fn take_incoming(
+        &mut self,
+        channel_id: usize,
+        now: Instant,
+    ) -> Vec<(PeerId, Packet)> {
+        self.incoming.take_due(channel_id, now)
+    }
+}
+
+/// Packets of one direction waiting for their release time.
+struct DelayQueue {
+    /// Indexed by socket channel.
+    channels: Vec<Vec<DelayedPacket>>,
+    /// Latest release time per ordered channel and peer, later packets can't overtake it.
+    last_release: HashMap<(usize, PeerId), Instant>,
+    rng: fastrand::Rng,
+}
+
+struct DelayedPacket {
+    release_at: Instant,
+    peer_id: PeerId,
+    packet: Packet,
+}
+
+impl DelayQueue {
+    fn new(channel_count: usize, rng: fastrand::Rng) -> Self {
+        Self {
+            channels: (0..channel_count).map(|_| Vec::new()).collect(),
+            last_release: HashMap::new(),
+            rng,
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.channels.iter().all(Vec::is_empty)
+    }
+
+    fn push(
+        &mut self,
+        conditions: &LinkConditions,
+        kind: Channel,
+        channel_id: usize,
+        peer_id: PeerId,
+        packet: Packet,
+        now: Instant,
+    ) {
+        let delay =
+            |rng: &mut fastrand::Rng| conditions.latency + conditions.jitter.mul_f32(rng.f32());
+        let rng = &mut self.rng;
+        let mut release_at = now + delay(rng);
+
+        if kind == Channel::Unreliable {
+            if rng.f32() < conditions.loss {
+                trace!("dropping packet for {peer_id} on channel {channel_id}");
+                return;
+            }
+            if rng.f32() < conditions.duplication {
+                let packet = packet.clone();
+                self.channels[channel_id].push(DelayedPacket {
+                    release_at: now + delay(rng),
+                    peer_id,
+                    packet,
+                });
+            }
+        } else {
+            // Each loss costs a round trip before the retransmission.
+            let mut retransmissions = 0;
+            while retransmissions < MAX_RETRANSMISSIONS && rng.f32() < conditions.loss {
+                release_at += 2 * conditions.latency + conditions.jitter;
+                retransmissions += 1;
+            }
+        }
+
+        if kind == Channel::Ordered {
+            let last_release = self
+                .last_release
+                .entry((channel_id, peer_id))
+                .or_insert(now);
+            release_at = release_at.max(*last_release);
+            *last_release = release_at;
+        } else if rng.f32() < conditions.reordering {
+            release_at += delay(rng);
+        }
+
+        self.channels[channel_id].push(DelayedPacket {
+            release_at,
+            peer_id,
+            packet,
+        });
+    }
+
+    fn take_due(&mut self, channel_id: usize, now: Instant) -> Vec<(PeerId, Packet)> {
+        let packets = &mut self.channels[channel_id];
+        if packets.is_empty() {
+            return Vec::new();
+        }
+
+        // Stable to keep packets with the same release time in the order they were pushed.
+        packets.sort_by_key(|packet| packet.release_at);
+        let due = packets.partition_point(|packet| packet.release_at <= now);
+        let released = packets
+            .drain(..due)
+            .map(|packet| (packet.peer_id, packet.packet))
+            .collect();
+        if packets.is_empty() {
+            self.last_release
+                .retain(|&(channel, _), _| channel != channel_id);
+        }
+        released
+    }
+}
+
+#[cfg(test)]
+const PEER: PeerId = PeerId(uuid::Uuid::nil());
+
+#[cfg(test)]
+fn conditioner(conditions: LinkConditions) -> Conditioner {
+    Conditioner::new(
+        NetworkConditions::symmetric(conditions).with_seed(0),
+        vec![Channel::Unreliable, Channel::Unordered, Channel::Ordered],
+    )
+}
+
+#[test]
+fn test_ideal_conditions() {
+    let mut conditioner = conditioner(LinkConditions::default());
+    let now = Instant::now();
+    for channel_id in 0..conditioner.channel_count() {
+        conditioner.push_outgoing(channel_id, PEER, Box::new([1]), now);
+        conditioner.push_outgoing(channel_id, PEER, Box::new([2]), now);
+        let packets = conditioner.take_outgoing(channel_id, now);
+        assert_eq!(packets, [(PEER, Box::from([1])), (PEER, Box::from([2]))]);
+    }
+    assert!(conditioner.is_empty());
+}
+
+#[test]
+fn test_latency() {
+    let mut conditioner = conditioner(LinkConditions {
+        latency: Duration::from_millis(100),
+        ..Default::default()
+    });
+    let now = Instant::now();
+    conditioner.push_incoming(0, PEER, Box::new([1]), now);
+    assert!(
+        conditioner
+            .take_incoming(0, now + Duration::from_millis(99))
+            .is_empty()
+    );
+    assert_eq!(
+        conditioner.take_incoming(0, now + Duration::from_millis(100)),
+        [(PEER, Box::from([1]))]
+    );
+    assert!(conditioner.is_empty());
+}
+
+#[test]
+fn test_reliable_loss() {
+    let mut conditioner = conditioner(LinkConditions {
+        latency: Duration::from_millis(10),
+        loss: 1.0,
+        duplication: 1.0,
+        ..Default::default()
+    });
+    let now = Instant::now();
+    for channel_id in 0..conditioner.channel_count() {
+        for _ in 0..10 {
+            conditioner.push_outgoing(channel_id, PEER, Box::new([]), now);
+        }
+    }
+
+    let later = now + Duration::from_secs(1);
+    assert!(
+        conditioner.take_outgoing(0, later).is_empty(),
+        "unreliable packets should be lost"
+    );
+    for channel_id in [1, 2] {
+        assert!(
+            conditioner
+                .take_outgoing(channel_id, now + Duration::from_millis(10))
+                .is_empty(),
+            "lost packets should be retransmitted later"
+        );
+        assert_eq!(
+            conditioner.take_outgoing(channel_id, later).len(),
+            10,
+            "reliable packets shouldn't be lost or duplicated"
+        );
+    }
+}
+
+#[test]
+fn test_ordered_jitter() {
+    let mut conditioner = conditioner(LinkConditions {
+        jitter: Duration::from_millis(100),
+        reordering: 1.0,
+        ..Default::default()
+    });
+    let now = Instant::now();
+    for value in 0..50 {
+        conditioner.push_outgoing(2, PEER, Box::new([value]), now);
+    }
+
+    let packets = conditioner.take_outgoing(2, now + Duration::from_secs(1));
+    let values: Vec<_> = packets.iter().map(|(_, packet)| packet[0]).collect();
+    let expected: Vec<_> = (0..50).collect();
+    assert_eq!(values, expected);
+}
+
+#[test]
+fn test_seeded_conditions() {
+    let conditions = LinkConditions {
+        loss: 0.5,
+        ..Default::default()
+    };
+    let now = Instant::now();
+    let delivered = |conditioner: &mut Conditioner| {
+        for index in 0..100 {
+            conditioner.push_outgoing(0, PEER, Box::new([index]), now);
+        }
+        conditioner.take_outgoing(0, now)
+    };
+
+    let first = delivered(&mut conditioner(conditions));
+    assert!(!first.is_empty() && first.len() < 100);
+    assert_eq!(delivered(&mut conditioner(conditions)), first);
+}
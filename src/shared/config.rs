@@ -0,0 +1,293 @@
+use super::ChannelCompression;
+use bevy_matchbox::matchbox_socket::{RtcIceServerConfig, WebRtcSocketBuilder};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Smallest packet size accepted by [`MatchboxBackendConfig::with_max_packet_size`].
+pub const MIN_PACKET_SIZE: usize = 256;
+
+/// Socket settings passed to `MatchboxHost::new` and `MatchboxClient::new`.
+///
+/// Defaults to the public STUN servers used by matchbox, 3 connection attempts, a signaling keep-alive
+/// every 10 seconds and packets of at most 1200 bytes. Up to 8 MiB of fragments are buffered per peer and
+/// incomplete messages on unreliable channels are dropped after 5 seconds. Peers send a heartbeat every
+/// second and are disconnected after 10 seconds of silence.
+#[derive(Clone)]
+pub struct MatchboxBackendConfig {
+    ice_urls: Vec<String>,
+    turn_credentials: Option<CredentialSource>,
+    connection_attempts: Option<u16>,
+    keep_alive_interval: Option<Duration>,
+    max_packet_size: usize,
+    max_reassembly_bytes: usize,
+    fragment_timeout: Duration,
+    heartbeat: Heartbeat,
+    /// Indexed by replicon channel id.
+    server_compression: HashMap<usize, ChannelCompression>,
+    client_compression: HashMap<usize, ChannelCompression>,
+}
+
+/// Keep-alive settings shared by both sides of a connection.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Heartbeat {
+    pub(crate) interval: Duration,
+    pub(crate) timeout: Option<Duration>,
+}
+
+/// Username and password for the TURN servers set with [`MatchboxBackendConfig::with_ice_servers`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TurnCredentials {
+    pub username: String,
+    pub credential: String,
+}
+
+#[derive(Clone)]
+enum CredentialSource {
+    Static(TurnCredentials),
+    Provider(Arc<dyn Fn() -> TurnCredentials + Send + Sync>),
+}
+
+impl Default for MatchboxBackendConfig {
+    fn default() -> Self {
+        Self {
+            ice_urls: RtcIceServerConfig::default().urls,
+            turn_credentials: None,
+            connection_attempts: Some(3),
+            keep_alive_interval: Some(Duration::from_secs(10)),
+            max_packet_size: 1200,
+            max_reassembly_bytes: 8 * 1024 * 1024,
+            fragment_timeout: Duration::from_secs(5),
+            heartbeat: Heartbeat {
+                interval: Duration::from_secs(1),
+                timeout: Some(Duration::from_secs(10)),
+            },
+            server_compression: HashMap::new(),
+            client_compression: HashMap::new(),
+        }
+    }
+}
+
+impl fmt::Debug for MatchboxBackendConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MatchboxBackendConfig")
+            .field("ice_urls", &self.ice_urls)
+            .field("turn_credentials", &self.turn_credentials.is_some())
+            .field("connection_attempts", &self.connection_attempts)
+            .field("keep_alive_interval", &self.keep_alive_interval)
+            .field("max_packet_size", &self.max_packet_size)
+            .field("max_reassembly_bytes", &self.max_reassembly_bytes)
+            .field("fragment_timeout", &self.fragment_timeout)
+            .field("heartbeat", &self.heartbeat)
+            .field("server_compression", &self.server_compression)
+            .field("client_compression", &self.client_compression)
+            .finish()
+    }
+}
+
+impl MatchboxBackendConfig {
+    /// Replaces the default STUN servers, for example with `stun:` and `turn:` URLs of your own servers.
+    pub fn with_ice_servers(mut self, urls: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        self.ice_urls = urls.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Authenticates with the TURN servers using fixed credentials.
+    pub fn with_turn_credentials(
+        mut self,
+        username: impl Into<String>,
+        credential: impl Into<String>,
+    ) -> Self {
+        self.turn_credentials = Some(CredentialSource::Static(TurnCredentials {
+            username: username.into(),
+            credential: credential.into(),
+        }));
+        self
+    }
+
+    /// Authenticates with the TURN servers using credentials returned by `provider`.
+    ///
+    /// Called every time a socket is created, including reconnects, so time-limited credentials
+    /// can be refreshed.
+    pub fn with_turn_credentials_provider(
+        mut self,
+        provider: impl Fn() -> TurnCredentials + Send + Sync + 'static,
+    ) -> Self {
+        self.turn_credentials = Some(CredentialSource::Provider(Arc::new(provider)));
+        self
+    }
+
+    /// Sets how many times matchbox tries to reach the signaling server, `None` retries forever.
+    pub fn with_connection_attempts(mut self, attempts: Option<u16>) -> Self {
+        self.connection_attempts = attempts;
+        self
+    }
+
+    /// Sets the interval of the messages keeping the signaling connection alive, `None` disables them.
+    pub fn with_keep_alive_interval(mut self, interval: Option<Duration>) -> Self {
+        self.keep_alive_interval = interval;
+        self
+    }
+
+    /// Sets the largest packet sent over the data channels, including the backend overhead.
+    ///
+    /// Larger messages are split into fragments and reassembled by the receiver. The host uses the lower
+    /// of its own and each client's value for that client. The default of 1200 bytes avoids IP
+    /// fragmentation on common networks, raise it only if every path supports it.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `size` is below [`MIN_PACKET_SIZE`].
+    pub fn with_max_packet_size(mut self, size: usize) -> Self {
+        assert!(
+            size >= MIN_PACKET_SIZE,
+            "max packet size should be at least {MIN_PACKET_SIZE}"
+        );
+        self.max_packet_size = size;
+        self
+    }
+
+    /// Sets how many bytes of incomplete messages are kept per peer, messages exceeding it are dropped.
+    pub fn with_max_reassembly_bytes(mut self, bytes: usize) -> Self {
+        self.max_reassembly_bytes = bytes;
+        self
+    }
+
+    /// Sets how long an incomplete message on an unreliable channel waits for its missing fragments.
+    ///
+    /// Also applies to messages of reliable channels dropped for exceeding the reassembly limit.
+    pub fn with_fragment_timeout(mut self, timeout: Duration) -> Self {
+        self.fragment_timeout = timeout;
+        self
+    }
+
+    /// Sets how often a heartbeat is sent to the remote peer, also used to measure the round-trip time.
+    pub fn with_heartbeat_interval(mut self, interval: Duration) -> Self {
+        self.heartbeat.interval = interval;
+        self
+    }
+
+    /// Sets how long the remote peer can stay silent before it's disconnected with
+    /// [`DisconnectReason::Timeout`](crate::DisconnectReason::Timeout), `None` waits for matchbox to
+    /// notice.
+    ///
+    /// Should be a few times the heartbeat interval. The host drops timed out clients, or suspends their
+    /// session if `MatchboxHost::with_session_resumption` is set. The client reconnects if
+    /// `MatchboxClient::with_reconnect` is set, or disconnects otherwise.
+    pub fn with_idle_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.heartbeat.timeout = timeout;
+        self
+    }
+
+    /// Compresses the messages of the replicon server channel `channel_id`, like
+    /// `ServerChannel::Updates as usize`.
+    ///
+    /// Both peers need the same codec for the channel, the host rejects clients that compress other
+    /// channels with `HandshakeRejection::ChannelLayout`. Compressed messages count towards
+    /// [`Self::with_max_reassembly_bytes`] once decompressed.
+    pub fn with_server_channel_compression(
+        mut self,
+        channel_id: usize,
+        compression: ChannelCompression,
+    ) -> Self {
+        self.server_compression.insert(channel_id, compression);
+        self
+    }
+
+    /// Compresses the messages of the replicon client channel `channel_id`.
+    ///
+    /// Both peers need the same codec for the channel, like for
+    /// [`Self::with_server_channel_compression`].
+    pub fn with_client_channel_compression(
+        mut self,
+        channel_id: usize,
+        compression: ChannelCompression,
+    ) -> Self {
+        self.client_compression.insert(channel_id, compression);
+        self
+    }
+
+    pub(crate) fn server_compression(&self) -> &HashMap<usize, ChannelCompression> {
+        &self.server_compression
+    }
+
+    pub(crate) fn client_compression(&self) -> &HashMap<usize, ChannelCompression> {
+        &self.client_compression
+    }
+
+    pub(crate) fn max_packet_size(&self) -> usize {
+        self.max_packet_size
+    }
+
+    pub(crate) fn max_reassembly_bytes(&self) -> usize {
+        self.max_reassembly_bytes
+    }
+
+    pub(crate) fn fragment_timeout(&self) -> Duration {
+        self.fragment_timeout
+    }
+
+    pub(crate) fn heartbeat(&self) -> Heartbeat {
+        self.heartbeat
+    }
+
+    pub(super) fn apply(&self, builder: WebRtcSocketBuilder) -> WebRtcSocketBuilder {
+        builder
+            .ice_server(self.ice_server())
+            .reconnect_attempts(self.connection_attempts)
+            .signaling_keep_alive_interval(self.keep_alive_interval)
+    }
+
+    fn ice_server(&self) -> RtcIceServerConfig {
+        let credentials = match &self.turn_credentials {
+            None => None,
+            Some(CredentialSource::Static(credentials)) => Some(credentials.clone()),
+            Some(CredentialSource::Provider(provider)) => Some(provider()),
+        };
+        let (username, credential) = credentials
+            .map(|credentials| (credentials.username, credentials.credential))
+            .unzip();
+
+        RtcIceServerConfig {
+            urls: self.ice_urls.clone(),
+            username,
+            credential,
+        }
+    }
+}
+
+#[test]
+fn test_ice_server() {
+    let config = MatchboxBackendConfig::default();
+    let ice_server = config.ice_server();
+    assert_eq!(ice_server.urls, RtcIceServerConfig::default().urls);
+    assert_eq!(ice_server.username, None);
+
+    let config = MatchboxBackendConfig::default()
+        .with_ice_servers(["turn:turn.example.com:3478"])
+        .with_turn_credentials("user", "pass");
+    let ice_server = config.ice_server();
+    assert_eq!(ice_server.urls, ["turn:turn.example.com:3478"]);
+    assert_eq!(ice_server.username.as_deref(), Some("user"));
+    assert_eq!(ice_server.credential.as_deref(), Some("pass"));
+}
+
+#[test]
+fn test_turn_credentials_provider() {
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    let counter = Arc::new(AtomicU32::new(0));
+    let provider_counter = counter.clone();
+    let config = MatchboxBackendConfig::default().with_turn_credentials_provider(move || {
+        let generation = provider_counter.fetch_add(1, Ordering::Relaxed);
+        TurnCredentials {
+            username: format!("user-{generation}"),
+            credential: "secret".into(),
+        }
+    });
+
+    assert_eq!(config.ice_server().username.as_deref(), Some("user-0"));
+    assert_eq!(config.ice_server().username.as_deref(), Some("user-1"));
+    assert_eq!(counter.load(Ordering::Relaxed), 2);
+}
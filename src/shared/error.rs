@@ -0,0 +1,100 @@
+use super::MatchboxConnectionPhase;
+use bevy::prelude::*;
+use bevy_matchbox::matchbox_socket;
+use std::fmt;
+use url::Url;
+
+/// Failure of `MatchboxHost`, `MatchboxClient` or their socket.
+///
+/// Returned by the constructors and written as a message when the socket fails at runtime.
+#[derive(Message, Debug, Clone, PartialEq, Eq)]
+pub enum MatchboxBackendError {
+    /// The room URL can't be used to reach a signaling server.
+    InvalidUrl { url: String, reason: String },
+    /// The signaling server couldn't be reached, with the underlying error.
+    SignalingConnectionRefused(String),
+    /// The connection to the signaling server was lost, with the underlying error.
+    SignalingDropped(String),
+    /// The host uses a different `RepliconChannels` layout or compresses other channels.
+    ChannelLayoutMismatch,
+    /// The data channels of the socket were closed.
+    DataChannelClosed,
+    /// The client stayed longer in the phase than allowed by `MatchboxClient::with_phase_timeout`.
+    PhaseTimeout(MatchboxConnectionPhase),
+}
+
+impl fmt::Display for MatchboxBackendError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MatchboxBackendError::InvalidUrl { url, reason } => {
+                write!(f, "invalid room URL `{url}`: {reason}")
+            }
+            MatchboxBackendError::SignalingConnectionRefused(e) => {
+                write!(f, "unable to reach the signaling server: {e}")
+            }
+            MatchboxBackendError::SignalingDropped(e) => {
+                write!(f, "lost the signaling server: {e}")
+            }
+            MatchboxBackendError::ChannelLayoutMismatch => {
+                write!(f, "replicon channel layout mismatch")
+            }
+            MatchboxBackendError::DataChannelClosed => write!(f, "data channels closed"),
+            MatchboxBackendError::PhaseTimeout(phase) => {
+                write!(f, "timed out in the {phase:?} connection phase")
+            }
+        }
+    }
+}
+
+impl std::error::Error for MatchboxBackendError {}
+
+impl From<&matchbox_socket::Error> for MatchboxBackendError {
+    fn from(error: &matchbox_socket::Error) -> Self {
+        match error {
+            matchbox_socket::Error::ConnectionFailed(e) => {
+                Self::SignalingConnectionRefused(e.to_string())
+            }
+            matchbox_socket::Error::Disconnected(e) => Self::SignalingDropped(e.to_string()),
+        }
+    }
+}
+
+/// Checks that `url` is a websocket URL with a host and a room.
+pub(crate) fn validate_room_url(url: &str) -> Result<(), MatchboxBackendError> {
+    let invalid = |reason: &str| MatchboxBackendError::InvalidUrl {
+        url: url.to_string(),
+        reason: reason.to_string(),
+    };
+    let parsed = Url::parse(url).map_err(|e| invalid(&e.to_string()))?;
+    if !matches!(parsed.scheme(), "ws" | "wss") {
+        return Err(invalid("the scheme should be `ws` or `wss`"));
+    }
+    if parsed.host_str().is_none_or(str::is_empty) {
+        return Err(invalid("missing host"));
+    }
+    if parsed.path().trim_matches('/').is_empty() {
+        return Err(invalid("missing room"));
+    }
+    Ok(())
+}
+
+#[test]
+fn test_validate_room_url() {
+    assert!(validate_room_url("ws://localhost:3536/room?next=2").is_ok());
+    assert!(validate_room_url("wss://example.com/lobby/room").is_ok());
+    for url in [
+        "localhost:3536/room",
+        "http://localhost:3536/room",
+        "ws://localhost:3536",
+        "ws://localhost:3536/",
+        "ws:///room",
+    ] {
+        assert!(
+            matches!(
+                validate_room_url(url),
+                Err(MatchboxBackendError::InvalidUrl { .. })
+            ),
+            "`{url}` should be rejected"
+        );
+    }
+}
@@ -0,0 +1,543 @@
+use super::{MARKER_LEN, MatchboxBackendConfig};
+use bevy::log::{error, warn};
+use bevy_matchbox::matchbox_socket::{Packet, PeerId};
+use bytes::Bytes;
+use std::collections::{BTreeMap, HashMap};
+use std::time::Duration;
+
+/// Last byte of a packet carrying a whole message that can't be sent as is, see [`needs_marker`].
+///
+/// Appended rather than prepended, so a uniquely owned message buffer with spare capacity can be sent
+/// without copying it.
+/// Its presence also keeps empty messages from being dropped by matchbox.
+const WHOLE: u8 = 0;
+/// Last byte of a packet carrying a part of a message, preceded by a [`FragmentHeader`].
+const FRAGMENT: u8 = 1;
+/// Flag of the kind byte set on every packet of a compressed message, see [`Codec`](super::Codec).
+const COMPRESSED: u8 = 0b1000_0000;
+
+/// Returns `true` if the last byte of a packet is a kind byte.
+///
+/// Packets ending with any other byte carry a whole uncompressed message without a kind byte.
+fn is_kind(byte: u8) -> bool {
+    matches!(byte & !COMPRESSED, WHOLE | FRAGMENT)
+}
+
+/// Returns `true` if a whole message needs the [`WHOLE`] kind byte to be told apart from other packets.
+fn needs_marker(message: &[u8], flags: u8) -> bool {
+    flags != 0 || message.last().is_none_or(|&byte| is_kind(byte))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct FragmentHeader {
+    message_id: u16,
+    index: u16,
+    count: u16,
+}
+
+impl FragmentHeader {
+    /// Length including the kind byte.
+    const LEN: usize = MARKER_LEN + 6;
+
+    fn write(self, packet: &mut Vec<u8>, flags: u8) {
+        packet.extend_from_slice(&self.message_id.to_le_bytes());
+        packet.extend_from_slice(&self.index.to_le_bytes());
+        packet.extend_from_slice(&self.count.to_le_bytes());
+        packet.push(FRAGMENT | flags);
+    }
+
+    /// Reads the header at the end of `data`, which excludes the kind byte.
+    fn read(data: &[u8]) -> Option<Self> {
+        let offset = data.len().checked_sub(Self::LEN - MARKER_LEN)?;
+        let header = &data[offset..];
+        let read_u16 = |offset: usize| u16::from_le_bytes([header[offset], header[offset + 1]]);
+        let header = Self {
+            message_id: read_u16(0),
+            index: read_u16(2),
+            count: read_u16(4),
+        };
+        (header.index < header.count).then_some(header)
+    }
+}
+
+/// Turns `message` into the buffer of a packet, reusing it if nothing else references it and the kind
+/// byte is either not needed or fits in its spare capacity.
+///
+/// Converting the buffer into a [`Packet`] shrinks the allocation to its length, which allocators
+/// usually do in place.
+fn whole_packet(message: Bytes, flags: u8) -> Vec<u8> {
+    let marker = needs_marker(&message, flags);
+    let mut packet = match message.try_into_mut() {
+        Ok(unique) if !marker || unique.capacity() > unique.len() => Vec::from(unique),
+        Ok(unique) => copy_with_capacity(&unique),
+        Err(shared) => copy_with_capacity(&shared),
+    };
+    if marker {
+        packet.push(WHOLE | flags);
+    }
+    packet
+}
+
+fn copy_with_capacity(message: &[u8]) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(message.len() + MARKER_LEN);
+    packet.extend_from_slice(message);
+    packet
+}
+
+/// Splits messages that don't fit in a single packet.
+#[derive(Default)]
+pub(crate) struct Fragmenter {
+    /// Id of the next fragmented message for each peer and channel, like the keys of [`Reassembler`].
+    ///
+    /// Separate counters take longer to wrap around, so a new message doesn't reuse the id of one
+    /// that is still being reassembled.
+    next_message_ids: HashMap<(PeerId, usize), u16>,
+}
+
+impl Fragmenter {
+    /// Passes the packets carrying `message` to `send`, as fragments if it exceeds `max_packet_size`.
+    ///
+    /// `compressed` is carried in the kind byte of each packet.
+    pub(crate) fn split(
+        &mut self,
+        peer_id: PeerId,
+        channel_id: usize,
+        message: Bytes,
+        compressed: bool,
+        max_packet_size: usize,
+        mut send: impl FnMut(Packet),
+    ) {
+        let flags = if compressed { COMPRESSED } else { 0 };
+        if message.len() + MARKER_LEN <= max_packet_size {
+            send(whole_packet(message, flags).into_boxed_slice());
+            return;
+        }
+
+        let chunk_size = max_packet_size - FragmentHeader::LEN;
+        let Ok(count) = u16::try_from(message.len().div_ceil(chunk_size)) else {
+            error!(
+                "dropping message of {} bytes, too large to be fragmented",
+                message.len()
+            );
+            return;
+        };
+        let next_message_id = self
+            .next_message_ids
+            .entry((peer_id, channel_id))
+            .or_default();
+        let message_id = *next_message_id;
+        *next_message_id = next_message_id.wrapping_add(1);
+
+        for (index, chunk) in message.chunks(chunk_size).enumerate() {
+            let header = FragmentHeader {
+                message_id,
+                index: index as u16,
+                count,
+            };
+            let mut packet = Vec::with_capacity(chunk.len() + FragmentHeader::LEN);
+            packet.extend_from_slice(chunk);
+            header.write(&mut packet, flags);
+            send(packet.into_boxed_slice());
+        }
+    }
+
+    pub(crate) fn remove_peer(&mut self, peer_id: PeerId) {
+        self.next_message_ids
+            .retain(|&(peer, _), _| peer != peer_id);
+    }
+}
+
+/// Puts fragmented messages back together, per peer and channel.
+pub(crate) struct Reassembler {
+    partials: HashMap<(PeerId, usize, u16), PartialMessage>,
+    /// Bytes held in incomplete messages for each peer.
+    buffered: HashMap<PeerId, usize>,
+    max_buffered_bytes: usize,
+    timeout: Duration,
+}
+
+struct PartialMessage {
+    fragments: BTreeMap<u16, Vec<u8>>,
+    count: u16,
+    /// Fragments seen so far, including the ones discarded after the message was dropped.
+    received: u16,
+    /// Set once the message exceeded the memory limit, its remaining fragments are ignored.
+    dropped: bool,
+    bytes: usize,
+    reliable: bool,
+    last_received: Duration,
+}
+
+impl Reassembler {
+    pub(crate) fn new(config: &MatchboxBackendConfig) -> Self {
+        Self {
+            partials: HashMap::new(),
+            buffered: HashMap::new(),
+            max_buffered_bytes: config.max_reassembly_bytes(),
+            timeout: config.fragment_timeout(),
+        }
+    }
+
+    /// Returns the message carried by `packet` and whether it's compressed, or `None` if it was a
+    /// fragment of an incomplete message.
+    ///
+    /// Whole messages reuse the buffer of `packet` without copying.
+    pub(crate) fn receive(
+        &mut self,
+        peer_id: PeerId,
+        channel_id: usize,
+        reliable: bool,
+        packet: Packet,
+        now: Duration,
+    ) -> Option<(Bytes, bool)> {
+        // Converting the boxed slice into a vector doesn't copy it, unlike slicing `Bytes` built from it.
+        let mut data = Vec::from(packet);
+        let Some(&last) = data.last() else {
+            error!("received empty packet from {peer_id}");
+            return None;
+        };
+        if !is_kind(last) {
+            return Some((data.into(), false));
+        }
+
+        data.pop();
+        let compressed = last & COMPRESSED != 0;
+        let message = if last & !COMPRESSED == WHOLE {
+            Some(data.into())
+        } else {
+            self.receive_fragment(peer_id, channel_id, reliable, data, now)
+        };
+        message.map(|message| (message, compressed))
+    }
+
+    fn receive_fragment(
+        &mut self,
+        peer_id: PeerId,
+        channel_id: usize,
+        reliable: bool,
+        mut fragment: Vec<u8>,
+        now: Duration,
+    ) -> Option<Bytes> {
+        let Some(header) = FragmentHeader::read(&fragment) else {
+            error!("received malformed fragment from {peer_id}");
+            return None;
+        };
+        fragment.truncate(fragment.len() - (FragmentHeader::LEN - MARKER_LEN));
+        let key = (peer_id, channel_id, header.message_id);
+
+        let partial = self.partials.entry(key).or_insert_with(|| PartialMessage {
+            fragments: BTreeMap::new(),
+            count: header.count,
+            received: 0,
+            dropped: false,
+            bytes: 0,
+            reliable,
+            last_received: now,
+        });
+        if partial.count != header.count {
+            error!(
+                "fragment count of message {} from {peer_id} changed",
+                header.message_id
+            );
+            return None;
+        }
+        if partial.fragments.contains_key(&header.index) {
+            return None;
+        }
+        partial.received += 1;
+        partial.last_received = now;
+
+        // Headers are counted too, otherwise empty fragments could be buffered without limit.
+        let size = FragmentHeader::LEN + fragment.len();
+        let buffered = self.buffered.entry(peer_id).or_default();
+        if !partial.dropped && *buffered + size > self.max_buffered_bytes {
+            warn!(
+                "fragments from {peer_id} exceed {} bytes, dropping message {}",
+                self.max_buffered_bytes, header.message_id
+            );
+            *buffered -= partial.bytes;
+            partial.fragments.clear();
+            partial.bytes = 0;
+            partial.dropped = true;
+        }
+        if !partial.dropped {
+            partial.fragments.insert(header.index, fragment);
+            partial.bytes += size;
+            *buffered += size;
+        }
+        if partial.received < partial.count {
+            return None;
+        }
+
+        let partial = self.partials.remove(&key)?;
+        *buffered -= partial.bytes;
+        if partial.dropped {
+            return None;
+        }
+        let mut message = Vec::with_capacity(partial.bytes);
+        for fragment in partial.fragments.into_values() {
+            message.extend_from_slice(&fragment);
+        }
+        Some(message.into())
+    }
+
+    /// Drops incomplete messages that stopped receiving fragments.
+    ///
+    /// Messages of reliable channels are kept unless they were dropped, since their missing fragments
+    /// are still on the way. The remaining fragments of a dropped message may never come if the peer
+    /// gave up on it.
+    pub(crate) fn expire(&mut self, now: Duration) {
+        self.partials.retain(|(peer_id, ..), partial| {
+            let pending = partial.reliable && !partial.dropped;
+            if pending || now - partial.last_received < self.timeout {
+                return true;
+            }
+            if let Some(buffered) = self.buffered.get_mut(peer_id) {
+                *buffered -= partial.bytes;
+            }
+            false
+        });
+    }
+
+    pub(crate) fn remove_peer(&mut self, peer_id: PeerId) {
+        self.partials.retain(|&(peer, ..), _| peer != peer_id);
+        self.buffered.remove(&peer_id);
+    }
+}
+
+/// Sends messages through [`Fragmenter`] and [`Reassembler`], for the `packet_path` benchmark.
+#[cfg(feature = "bench")]
+#[doc(hidden)]
+pub struct PacketPath {
+    fragmenter: Fragmenter,
+    reassembler: Reassembler,
+}
+
+#[cfg(feature = "bench")]
+impl Default for PacketPath {
+    fn default() -> Self {
+        Self {
+            fragmenter: Fragmenter::default(),
+            reassembler: Reassembler::new(&MatchboxBackendConfig::default()),
+        }
+    }
+}
+
+#[cfg(feature = "bench")]
+impl PacketPath {
+    /// Returns `message` as received by the other peer once all its packets arrived.
+    pub fn send(&mut self, message: Bytes, max_packet_size: usize) -> Option<Bytes> {
+        let peer_id = PeerId(uuid::Uuid::nil());
+        let mut received = None;
+        self.fragmenter
+            .split(peer_id, 0, message, false, max_packet_size, |packet| {
+                received = self
+                    .reassembler
+                    .receive(peer_id, 0, true, packet, Duration::ZERO);
+            });
+        received.map(|(message, _)| message)
+    }
+}
+
+#[cfg(test)]
+const PEER: PeerId = PeerId(uuid::Uuid::from_u128(1));
+
+#[cfg(test)]
+fn split(message: &[u8], max_packet_size: usize) -> Vec<Packet> {
+    let mut packets = Vec::new();
+    Fragmenter::default().split(
+        PEER,
+        0,
+        Bytes::copy_from_slice(message),
+        false,
+        max_packet_size,
+        |packet| packets.push(packet),
+    );
+    packets
+}
+
+#[test]
+fn test_whole_message() {
+    let mut reassembler = Reassembler::new(&MatchboxBackendConfig::default());
+    for (message, marker) in [
+        (b"hello".as_slice(), false),
+        (b"", true),
+        (&[1, WHOLE], true),
+        (&[1, FRAGMENT | COMPRESSED], true),
+        (&[1, 2], false),
+    ] {
+        let mut packets = split(message, 256);
+        assert_eq!(packets.len(), 1);
+        let expected_len = message.len() + if marker { MARKER_LEN } else { 0 };
+        assert_eq!(packets[0].len(), expected_len, "{message:?}");
+
+        let received = reassembler.receive(PEER, 0, true, packets.remove(0), Duration::ZERO);
+        assert_eq!(received, Some((Bytes::copy_from_slice(message), false)));
+    }
+}
+
+#[test]
+fn test_whole_packet_reuse() {
+    let mut buffer = Vec::with_capacity(64);
+    buffer.extend_from_slice(b"hello");
+    let ptr = buffer.as_ptr();
+    let packet = whole_packet(buffer.into(), 0);
+    assert_eq!(
+        packet.as_ptr(),
+        ptr,
+        "buffer without marker should be reused"
+    );
+    assert_eq!(packet, b"hello");
+
+    let mut buffer = Vec::with_capacity(64);
+    buffer.extend_from_slice(b"hello\0");
+    let ptr = buffer.as_ptr();
+    let packet = whole_packet(buffer.into(), COMPRESSED);
+    assert_eq!(
+        packet.as_ptr(),
+        ptr,
+        "marker should be written in the spare capacity"
+    );
+    assert_eq!(packet, b"hello\0\x80");
+
+    let message = Bytes::from_static(b"hello");
+    let packet = whole_packet(message.clone(), 0);
+    assert_ne!(
+        packet.as_ptr(),
+        message.as_ptr(),
+        "shared buffer should be copied"
+    );
+    assert_eq!(packet, b"hello");
+}
+
+#[test]
+fn test_fragmented_message() {
+    let message: Vec<u8> = (0..1000).map(|i| i as u8).collect();
+    let mut packets = split(&message, 256);
+    assert_eq!(packets.len(), 5);
+    assert!(packets.iter().all(|packet| packet.len() <= 256));
+
+    packets.reverse();
+    let mut reassembler = Reassembler::new(&MatchboxBackendConfig::default());
+    let mut received = Vec::new();
+    for packet in packets {
+        received.extend(reassembler.receive(PEER, 0, true, packet, Duration::ZERO));
+    }
+    assert_eq!(received, [(message.into(), false)]);
+    assert!(reassembler.partials.is_empty());
+    assert_eq!(reassembler.buffered[&PEER], 0);
+}
+
+#[test]
+fn test_message_ids() {
+    let other_peer = PeerId(uuid::Uuid::from_u128(2));
+    let mut fragmenter = Fragmenter::default();
+    let mut message_ids = Vec::new();
+    for (peer_id, channel_id) in [(PEER, 0), (PEER, 0), (PEER, 1), (other_peer, 0)] {
+        let message = Bytes::from_static(&[0; 300]);
+        let mut ids = Vec::new();
+        fragmenter.split(peer_id, channel_id, message, false, 256, |packet| {
+            let header = FragmentHeader::read(&packet[..packet.len() - MARKER_LEN]).unwrap();
+            ids.push(header.message_id);
+        });
+        ids.dedup();
+        message_ids.extend(ids);
+    }
+    assert_eq!(
+        message_ids,
+        [0, 1, 0, 0],
+        "each peer and channel should count separately"
+    );
+
+    fragmenter.remove_peer(PEER);
+    assert_eq!(fragmenter.next_message_ids.len(), 1);
+}
+
+#[test]
+fn test_compressed_flag() {
+    let mut fragmenter = Fragmenter::default();
+    let mut reassembler = Reassembler::new(&MatchboxBackendConfig::default());
+    for size in [100, 1000] {
+        let message: Bytes = vec![1; size].into();
+        let mut received = Vec::new();
+        fragmenter.split(PEER, 0, message.clone(), true, 256, |packet| {
+            assert_eq!(packet.last().unwrap() & COMPRESSED, COMPRESSED);
+            received.extend(reassembler.receive(PEER, 0, true, packet, Duration::ZERO));
+        });
+        assert_eq!(received, [(message, true)]);
+    }
+}
+
+#[test]
+fn test_reassembly_memory_limit() {
+    let config = MatchboxBackendConfig::default().with_max_reassembly_bytes(600);
+    let mut reassembler = Reassembler::new(&config);
+    for packet in split(&[0; 1000], 256) {
+        assert_eq!(
+            reassembler.receive(PEER, 0, true, packet, Duration::ZERO),
+            None
+        );
+    }
+    assert!(reassembler.partials.is_empty());
+    assert_eq!(reassembler.buffered[&PEER], 0);
+}
+
+#[test]
+fn test_unreliable_fragment_expiration() {
+    let timeout = Duration::from_secs(1);
+    let config = MatchboxBackendConfig::default().with_fragment_timeout(timeout);
+    let mut reassembler = Reassembler::new(&config);
+    let packets = split(&[0; 1000], 256);
+    reassembler.receive(PEER, 0, true, packets[0].clone(), Duration::ZERO);
+    reassembler.receive(PEER, 1, false, packets[0].clone(), Duration::ZERO);
+
+    reassembler.expire(timeout);
+    assert_eq!(
+        reassembler.partials.len(),
+        1,
+        "reliable messages shouldn't expire"
+    );
+    assert!(reassembler.partials.contains_key(&(PEER, 0, 0)));
+}
+
+#[test]
+fn test_dropped_fragment_expiration() {
+    let timeout = Duration::from_secs(1);
+    let config = MatchboxBackendConfig::default()
+        .with_fragment_timeout(timeout)
+        .with_max_reassembly_bytes(600);
+    let mut reassembler = Reassembler::new(&config);
+    let packets = split(&[0; 1000], 256);
+    for packet in &packets[..4] {
+        reassembler.receive(PEER, 0, true, packet.clone(), Duration::ZERO);
+    }
+    assert!(reassembler.partials[&(PEER, 0, 0)].dropped);
+
+    reassembler.expire(timeout);
+    assert!(
+        reassembler.partials.is_empty(),
+        "dropped messages should expire even if reliable"
+    );
+    assert_eq!(reassembler.buffered[&PEER], 0);
+}
+
+#[test]
+fn test_malformed_fragment() {
+    let mut reassembler = Reassembler::new(&MatchboxBackendConfig::default());
+    let mut packet = Vec::new();
+    FragmentHeader {
+        message_id: 0,
+        index: 2,
+        count: 2,
+    }
+    .write(&mut packet, 0);
+    assert_eq!(
+        reassembler.receive(PEER, 0, true, packet.into(), Duration::ZERO),
+        None
+    );
+    assert_eq!(
+        reassembler.receive(PEER, 0, true, Box::new([0, FRAGMENT]), Duration::ZERO),
+        None
+    );
+    assert!(reassembler.partials.is_empty());
+}
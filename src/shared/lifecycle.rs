@@ -0,0 +1,96 @@
+use bevy::ecs::system::SystemParam;
+use bevy::prelude::*;
+use bevy_matchbox::matchbox_socket::PeerId;
+
+use super::MatchboxBackendError;
+
+/// Registers the messages below, shared by the host and client plugins.
+pub(crate) fn add_lifecycle_messages(app: &mut App) {
+    app.add_message::<PeerJoined>()
+        .add_message::<PeerLeft>()
+        .add_message::<SocketClosed>()
+        .add_message::<MalformedPacket>()
+        .add_message::<HostAssigned>()
+        .add_message::<MatchboxBackendError>();
+}
+
+/// Writers for all lifecycle messages, to keep system signatures short.
+#[derive(SystemParam)]
+pub(crate) struct LifecycleMessages<'w> {
+    pub(crate) joined: MessageWriter<'w, PeerJoined>,
+    pub(crate) left: MessageWriter<'w, PeerLeft>,
+    pub(crate) closed: MessageWriter<'w, SocketClosed>,
+    pub(crate) malformed: MessageWriter<'w, MalformedPacket>,
+    pub(crate) errors: MessageWriter<'w, MatchboxBackendError>,
+    #[cfg(feature = "client")]
+    pub(crate) host_assigned: MessageWriter<'w, HostAssigned>,
+}
+
+/// A peer of the room connected to the local socket, before any handshake.
+#[derive(Message, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PeerJoined {
+    pub peer_id: PeerId,
+}
+
+/// A peer of the room disconnected from the local socket.
+#[derive(Message, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PeerLeft {
+    pub peer_id: PeerId,
+    /// On the host, the entity of the client if the peer was accepted.
+    pub client: Option<Entity>,
+}
+
+/// The socket of the host or client was closed, for example because the signaling server went away.
+///
+/// The client writes it for every closed socket, including the ones it reconnects from.
+#[derive(Message, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SocketClosed;
+
+/// A packet was dropped because it couldn't be handled.
+#[derive(Message, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MalformedPacket {
+    pub peer_id: PeerId,
+    /// On the host, the entity of the client if the peer was accepted.
+    pub client: Option<Entity>,
+    /// Socket channel, 0 being the system channel.
+    pub channel: usize,
+    pub len: usize,
+    pub kind: MalformedPacketKind,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MalformedPacketKind {
+    /// The system message couldn't be deserialized.
+    Deserialization,
+    /// The system message isn't expected from this peer.
+    UnexpectedMessage,
+    /// The peer didn't complete the handshake.
+    UnknownPeer,
+    /// The message couldn't be decompressed, see `ChannelCompression`.
+    Decompression,
+}
+
+/// A host accepted the client, written again after reconnects and host migrations.
+#[derive(Message, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HostAssigned {
+    pub peer_id: PeerId,
+}
+
+/// Progress of a `MatchboxClient` towards its host, inserted as a resource while the client exists.
+///
+/// Goes back to [`Self::Signaling`] when the client reconnects. Each phase can be limited with
+/// `MatchboxClient::with_phase_timeout`.
+#[derive(Resource, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum MatchboxConnectionPhase {
+    /// Reaching the signaling server, until it assigns a peer id.
+    Signaling,
+    /// In the room until the data channels to the host open.
+    ///
+    /// Covers both a host that didn't join yet and a stuck ICE negotiation, matchbox only reports
+    /// peers once their data channels are open.
+    WaitingForHost,
+    /// The data channels are open and the hello was sent, waiting for the host to accept it.
+    Handshake,
+    /// The host accepted the client.
+    Connected,
+}
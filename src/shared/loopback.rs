@@ -0,0 +1,193 @@
+use super::RepliconChannelsExt;
+use bevy::log::{error, trace};
+use bevy_matchbox::matchbox_socket::{ChannelError, Packet, PeerId, PeerState};
+use bevy_replicon::prelude::RepliconChannels;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, MutexGuard};
+use uuid::Uuid;
+
+/// In-memory room connecting hosts and clients of the same process without a signaling server.
+///
+/// Pass it to `MatchboxHost::loopback` and `MatchboxClient::loopback` instead of a room URL. Every peer
+/// that joins is connected to all the others, like in a full mesh room. Packets are delivered in order
+/// and without loss on every channel, so tests using it are deterministic and can run in parallel.
+#[derive(Clone, Default)]
+pub struct LoopbackNetwork(Arc<Mutex<Vec<Mailbox>>>);
+
+/// Packets and peer changes waiting for a peer, in the order they were sent.
+struct Mailbox {
+    peer_id: PeerId,
+    channels: Vec<VecDeque<(PeerId, Packet)>>,
+    peer_changes: Vec<(PeerId, PeerState)>,
+}
+
+impl LoopbackNetwork {
+    /// Adds a new peer with the channel layout used by the backend.
+    pub(crate) fn join(&self, replicon_channels: &RepliconChannels) -> LoopbackSocket {
+        let peer_id = PeerId(Uuid::new_v4());
+        let mut mailboxes = self.lock();
+        let mut peer_changes = Vec::new();
+        for mailbox in mailboxes.iter_mut() {
+            mailbox.peer_changes.push((peer_id, PeerState::Connected));
+            peer_changes.push((mailbox.peer_id, PeerState::Connected));
+        }
+        // The system channel comes first.
+        let channel_count = 1 + replicon_channels.all_channels().count();
+        mailboxes.push(Mailbox {
+            peer_id,
+            channels: vec![VecDeque::new(); channel_count],
+            peer_changes,
+        });
+        trace!("peer {peer_id} joined the loopback network");
+
+        LoopbackSocket {
+            peer_id,
+            network: self.clone(),
+            peers: Vec::new(),
+            closed: false,
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Vec<Mailbox>> {
+        self.0
+            .lock()
+            .expect("loopback network shouldn't be poisoned")
+    }
+}
+
+/// A peer of a [`LoopbackNetwork`], leaves it when closed or dropped.
+pub struct LoopbackSocket {
+    peer_id: PeerId,
+    network: LoopbackNetwork,
+    /// Connected peers as of the last [`Self::try_update_peers`].
+    peers: Vec<PeerId>,
+    closed: bool,
+}
+
+impl LoopbackSocket {
+    pub fn id(&self) -> PeerId {
+        self.peer_id
+    }
+
+    pub fn connected_peers(&self) -> impl Iterator<Item = PeerId> + '_ {
+        self.peers.iter().copied()
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.closed
+    }
+
+    /// Returns the peers that joined or left since the last call.
+    pub fn try_update_peers(&mut self) -> Result<Vec<(PeerId, PeerState)>, ChannelError> {
+        if self.closed {
+            return Err(ChannelError::Closed);
+        }
+        let mut mailboxes = self.network.lock();
+        let mailbox = mailbox_mut(&mut mailboxes, self.peer_id).ok_or(ChannelError::Closed)?;
+        let changes = std::mem::take(&mut mailbox.peer_changes);
+        for &(peer_id, state) in &changes {
+            match state {
+                PeerState::Connected => self.peers.push(peer_id),
+                PeerState::Disconnected => self.peers.retain(|&peer| peer != peer_id),
+            }
+        }
+        Ok(changes)
+    }
+
+    /// Queues `packet` for `peer_id`, dropped if the peer left or `channel` doesn't exist.
+    pub fn send(&mut self, channel: usize, packet: Packet, peer_id: PeerId) {
+        if self.closed {
+            return;
+        }
+        let mut mailboxes = self.network.lock();
+        let Some(mailbox) = mailbox_mut(&mut mailboxes, peer_id) else {
+            trace!("dropping packet for peer {peer_id} that left the loopback network");
+            return;
+        };
+        let Some(queue) = mailbox.channels.get_mut(channel) else {
+            error!("dropping packet for peer {peer_id} on nonexistent channel {channel}");
+            return;
+        };
+        queue.push_back((self.peer_id, packet));
+    }
+
+    /// Takes the packets received on `channel`, none if it doesn't exist.
+    pub fn receive(&mut self, channel: usize) -> Vec<(PeerId, Packet)> {
+        if self.closed {
+            return Vec::new();
+        }
+        let mut mailboxes = self.network.lock();
+        let Some(mailbox) = mailbox_mut(&mut mailboxes, self.peer_id) else {
+            return Vec::new();
+        };
+        let Some(queue) = mailbox.channels.get_mut(channel) else {
+            error!("receiving on nonexistent channel {channel}");
+            return Vec::new();
+        };
+        queue.drain(..).collect()
+    }
+
+    /// Leaves the network, the other peers see it as disconnected.
+    pub fn close(&mut self) {
+        if self.closed {
+            return;
+        }
+        self.closed = true;
+        let mut mailboxes = self.network.lock();
+        mailboxes.retain(|mailbox| mailbox.peer_id != self.peer_id);
+        for mailbox in mailboxes.iter_mut() {
+            mailbox
+                .peer_changes
+                .push((self.peer_id, PeerState::Disconnected));
+        }
+        trace!("peer {} left the loopback network", self.peer_id);
+    }
+}
+
+impl Drop for LoopbackSocket {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+fn mailbox_mut(mailboxes: &mut [Mailbox], peer_id: PeerId) -> Option<&mut Mailbox> {
+    mailboxes
+        .iter_mut()
+        .find(|mailbox| mailbox.peer_id == peer_id)
+}
+
+#[test]
+fn test_loopback() {
+    let network = LoopbackNetwork::default();
+    let channels = RepliconChannels::default();
+    let mut host = network.join(&channels);
+    let mut client = network.join(&channels);
+
+    assert_eq!(
+        host.try_update_peers().unwrap(),
+        [(client.id(), PeerState::Connected)]
+    );
+    assert_eq!(
+        client.try_update_peers().unwrap(),
+        [(host.id(), PeerState::Connected)]
+    );
+
+    host.send(1, Box::new([1]), client.id());
+    host.send(1, Box::new([2]), client.id());
+    host.send(usize::MAX, Box::new([3]), client.id());
+    assert!(client.receive(0).is_empty());
+    assert!(client.receive(usize::MAX).is_empty());
+    let received: Vec<_> = client.receive(1).into_iter().collect();
+    assert_eq!(
+        received,
+        [(host.id(), Box::from([1])), (host.id(), Box::from([2]))]
+    );
+
+    let client_id = client.id();
+    drop(client);
+    assert_eq!(
+        host.try_update_peers().unwrap(),
+        [(client_id, PeerState::Disconnected)]
+    );
+    assert_eq!(host.connected_peers().count(), 0);
+}
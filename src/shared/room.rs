@@ -0,0 +1,208 @@
+use std::fmt;
+use url::Url;
+
+/// Letters and digits that can't be confused with each other when read aloud or typed.
+const CODE_ALPHABET: &[u8] = b"ABCDEFGHJKMNPQRSTUVWXYZ23456789";
+
+/// Location of a matchbox room, accepted by `MatchboxHost::new` and `MatchboxClient::new` in place of a URL.
+///
+/// ```
+/// # use bevy_replicon_matchbox::{RoomAddress, RoomCode};
+/// let code = RoomCode::parse("abc-234").unwrap();
+/// let address = RoomAddress::new("ws://localhost:3536/", code).with_next(2);
+/// assert_eq!(address.url(), "ws://localhost:3536/ABC234?next=2");
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RoomAddress {
+    base_url: String,
+    room: String,
+    query: Vec<(String, String)>,
+}
+
+impl RoomAddress {
+    /// Creates the address of `room` on the signaling server at `base_url`.
+    pub fn new(base_url: impl Into<String>, room: impl Into<String>) -> Self {
+        let mut base_url = base_url.into();
+        while base_url.ends_with('/') {
+            base_url.pop();
+        }
+        Self {
+            base_url,
+            room: room.into(),
+            query: Vec::new(),
+        }
+    }
+
+    /// Creates the address of a room with a new [`RoomCode`] to share with other players.
+    pub fn generate(base_url: impl Into<String>) -> Self {
+        Self::new(base_url, RoomCode::generate())
+    }
+
+    /// Appends a query parameter, replacing a previous one with the same key.
+    pub fn with_query(mut self, key: impl Into<String>, value: impl ToString) -> Self {
+        let key = key.into();
+        self.query.retain(|(existing, _)| *existing != key);
+        self.query.push((key, value.to_string()));
+        self
+    }
+
+    /// Makes the signaling server split the room into groups of `peers`, see matchbox's `next` parameter.
+    pub fn with_next(self, peers: usize) -> Self {
+        self.with_query("next", peers)
+    }
+
+    pub fn base_url(&self) -> &str {
+        &self.base_url
+    }
+
+    pub fn room(&self) -> &str {
+        &self.room
+    }
+
+    pub fn query(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.query
+            .iter()
+            .map(|(key, value)| (key.as_str(), value.as_str()))
+    }
+
+    /// Returns the URL of the room, with the room and the query parameters percent-encoded.
+    pub fn url(&self) -> String {
+        self.to_string()
+    }
+}
+
+impl fmt::Display for RoomAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // An invalid base is written as is, so `MatchboxHost::new` reports it.
+        let Ok(mut url) = Url::parse(&self.base_url) else {
+            return write!(f, "{}/{}", self.base_url, self.room);
+        };
+        if let Ok(mut segments) = url.path_segments_mut() {
+            segments.pop_if_empty().push(&self.room);
+        }
+        if !self.query.is_empty() {
+            url.query_pairs_mut().extend_pairs(&self.query);
+        }
+        write!(f, "{url}")
+    }
+}
+
+impl From<RoomAddress> for String {
+    fn from(address: RoomAddress) -> Self {
+        address.url()
+    }
+}
+
+impl From<&RoomAddress> for String {
+    fn from(address: &RoomAddress) -> Self {
+        address.url()
+    }
+}
+
+/// Short lobby code players can read to each other, like `K7QX4M`.
+///
+/// Uses uppercase letters and digits without the ambiguous `0`, `O`, `1`, `I` and `L`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct RoomCode(String);
+
+impl RoomCode {
+    /// Number of characters in a code.
+    pub const LEN: usize = 6;
+
+    pub fn generate() -> Self {
+        let code = (0..Self::LEN)
+            .map(|_| char::from(CODE_ALPHABET[fastrand::usize(..CODE_ALPHABET.len())]))
+            .collect();
+        Self(code)
+    }
+
+    /// Validates a code entered by a player, ignoring case, spaces and dashes.
+    pub fn parse(input: &str) -> Result<Self, RoomCodeError> {
+        let code: String = input
+            .chars()
+            .filter(|c| !c.is_whitespace() && *c != '-')
+            .map(|c| c.to_ascii_uppercase())
+            .collect();
+        if let Some(c) = code
+            .chars()
+            .find(|&c| !c.is_ascii() || !CODE_ALPHABET.contains(&(c as u8)))
+        {
+            return Err(RoomCodeError::InvalidCharacter(c));
+        }
+        if code.len() != Self::LEN {
+            return Err(RoomCodeError::Length(code.len()));
+        }
+        Ok(Self(code))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for RoomCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl From<RoomCode> for String {
+    fn from(code: RoomCode) -> Self {
+        code.0
+    }
+}
+
+/// Why [`RoomCode::parse`] refused an input.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RoomCodeError {
+    /// The code doesn't have [`RoomCode::LEN`] characters, with the actual count.
+    Length(usize),
+    /// The character is not used in codes.
+    InvalidCharacter(char),
+}
+
+impl fmt::Display for RoomCodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RoomCodeError::Length(len) => {
+                write!(f, "room codes have {} characters, got {len}", RoomCode::LEN)
+            }
+            RoomCodeError::InvalidCharacter(c) => write!(f, "`{c}` can't be part of a room code"),
+        }
+    }
+}
+
+impl std::error::Error for RoomCodeError {}
+
+#[test]
+fn test_room_code() {
+    for _ in 0..100 {
+        let code = RoomCode::generate();
+        assert_eq!(RoomCode::parse(code.as_str()), Ok(code));
+    }
+    assert_eq!(RoomCode::parse(" k7q-x4m "), Ok(RoomCode("K7QX4M".into())));
+    assert_eq!(
+        RoomCode::parse("K7QX4O"),
+        Err(RoomCodeError::InvalidCharacter('O'))
+    );
+    assert_eq!(RoomCode::parse("K7QX"), Err(RoomCodeError::Length(4)));
+}
+
+#[test]
+fn test_room_address() {
+    let address = RoomAddress::new("wss://example.com/", "lobby")
+        .with_next(4)
+        .with_query("region", "eu")
+        .with_next(2);
+    assert_eq!(address.url(), "wss://example.com/lobby?region=eu&next=2");
+    assert_eq!(
+        String::from(RoomAddress::new("ws://localhost:3536", "room")),
+        "ws://localhost:3536/room"
+    );
+    assert_eq!(
+        RoomAddress::new("ws://localhost:3536/game", "a/b?c&d e")
+            .with_query("name", "x&y=z/? w")
+            .url(),
+        "ws://localhost:3536/game/a%2Fb%3Fc&d%20e?name=x%26y%3Dz%2F%3F+w"
+    );
+}
@@ -0,0 +1,309 @@
+use super::{Heartbeat, SystemChannelMessage};
+use bevy::prelude::*;
+use bevy_replicon::prelude::ClientStats;
+use std::collections::VecDeque;
+use std::time::Duration;
+
+/// Unanswered pings kept around, older ones are considered lost.
+const MAX_PENDING_PINGS: usize = 8;
+/// Interval over which the bytes per second are averaged.
+const BANDWIDTH_WINDOW: Duration = Duration::from_secs(1);
+
+/// Packets and bytes exchanged on each replicon channel.
+///
+/// Inserted on every client entity on the host and available as a resource on the client, alongside the
+/// [`ClientStats`] filled by this backend. Counts packets as they go over the wire, so fragments and
+/// the kind byte added to each packet are included.
+#[derive(Resource, Component, Debug, Clone, Default)]
+pub struct ChannelStats {
+    /// Indexed by the channel id the local peer sends on, server channels on the host.
+    pub sent: Vec<ChannelTraffic>,
+    /// Indexed by the channel id the local peer receives on, client channels on the host.
+    pub received: Vec<ChannelTraffic>,
+}
+
+/// Totals of a single channel in one direction, see [`ChannelStats`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ChannelTraffic {
+    pub packets: u64,
+    pub bytes: u64,
+    /// Size of the compressed messages before compression, see `ChannelCompression`.
+    pub uncompressed_bytes: u64,
+    /// Size of the compressed messages after compression, without packet overhead.
+    pub compressed_bytes: u64,
+}
+
+impl ChannelTraffic {
+    /// Returns the compressed size of the compressed messages relative to their original size.
+    ///
+    /// `None` if no message was compressed.
+    pub fn compression_ratio(&self) -> Option<f64> {
+        (self.uncompressed_bytes > 0)
+            .then(|| self.compressed_bytes as f64 / self.uncompressed_bytes as f64)
+    }
+}
+
+impl ChannelStats {
+    /// Returns the bytes sent over all channels.
+    pub fn sent_bytes(&self) -> u64 {
+        self.sent.iter().map(|traffic| traffic.bytes).sum()
+    }
+
+    /// Returns the bytes received over all channels.
+    pub fn received_bytes(&self) -> u64 {
+        self.received.iter().map(|traffic| traffic.bytes).sum()
+    }
+
+    pub(crate) fn record_sent(&mut self, channel_id: usize, bytes: usize) {
+        record(&mut self.sent, channel_id, bytes);
+    }
+
+    pub(crate) fn record_received(&mut self, channel_id: usize, bytes: usize) {
+        record(&mut self.received, channel_id, bytes);
+    }
+
+    pub(crate) fn record_compressed_sent(
+        &mut self,
+        channel_id: usize,
+        uncompressed: usize,
+        compressed: usize,
+    ) {
+        let traffic = channel_traffic(&mut self.sent, channel_id);
+        traffic.uncompressed_bytes += uncompressed as u64;
+        traffic.compressed_bytes += compressed as u64;
+    }
+
+    pub(crate) fn record_compressed_received(
+        &mut self,
+        channel_id: usize,
+        uncompressed: usize,
+        compressed: usize,
+    ) {
+        let traffic = channel_traffic(&mut self.received, channel_id);
+        traffic.uncompressed_bytes += uncompressed as u64;
+        traffic.compressed_bytes += compressed as u64;
+    }
+}
+
+fn record(traffic: &mut Vec<ChannelTraffic>, channel_id: usize, bytes: usize) {
+    let traffic = channel_traffic(traffic, channel_id);
+    traffic.packets += 1;
+    traffic.bytes += bytes as u64;
+}
+
+fn channel_traffic(traffic: &mut Vec<ChannelTraffic>, channel_id: usize) -> &mut ChannelTraffic {
+    if traffic.len() <= channel_id {
+        traffic.resize(channel_id + 1, Default::default());
+    }
+    &mut traffic[channel_id]
+}
+
+/// Sends pings as heartbeats, detects silent peers and turns [`ChannelStats`] into [`ClientStats`].
+///
+/// The data channels don't report losses, so [`ClientStats::packet_loss`] stays at zero.
+#[derive(Component)]
+pub(crate) struct ConnectionMonitor {
+    heartbeat: Heartbeat,
+    /// Time of the last packet from the remote peer, set on the first timeout check.
+    last_received: Option<Duration>,
+    next_ping_id: u32,
+    last_ping_at: Option<Duration>,
+    pending_pings: VecDeque<(u32, Duration)>,
+    /// Smoothed round-trip time, `None` until the first pong.
+    rtt: Option<Duration>,
+    window_start: Option<Duration>,
+    window_sent_bytes: u64,
+    window_received_bytes: u64,
+}
+
+impl ConnectionMonitor {
+    pub(crate) fn new(heartbeat: Heartbeat) -> Self {
+        Self {
+            heartbeat,
+            last_received: None,
+            next_ping_id: 0,
+            last_ping_at: None,
+            pending_pings: VecDeque::new(),
+            rtt: None,
+            window_start: None,
+            window_sent_bytes: 0,
+            window_received_bytes: 0,
+        }
+    }
+
+    /// Records that the remote peer is still alive.
+    pub(crate) fn received(&mut self, now: Duration) {
+        self.last_received = Some(now);
+    }
+
+    /// Returns `true` if nothing was received from the remote peer for longer than the timeout.
+    pub(crate) fn timed_out(&mut self, now: Duration) -> bool {
+        let Some(timeout) = self.heartbeat.timeout else {
+            return false;
+        };
+        let last_received = *self.last_received.get_or_insert(now);
+        now - last_received > timeout
+    }
+
+    /// Returns the ping to send if the previous one is older than the heartbeat interval.
+    pub(crate) fn ping(&mut self, now: Duration) -> Option<SystemChannelMessage> {
+        if self
+            .last_ping_at
+            .is_some_and(|last_ping_at| now - last_ping_at < self.heartbeat.interval)
+        {
+            return None;
+        }
+
+        let id = self.next_ping_id;
+        self.next_ping_id = self.next_ping_id.wrapping_add(1);
+        self.last_ping_at = Some(now);
+        if self.pending_pings.len() == MAX_PENDING_PINGS {
+            self.pending_pings.pop_front();
+        }
+        self.pending_pings.push_back((id, now));
+        Some(SystemChannelMessage::Ping(id))
+    }
+
+    pub(crate) fn pong(&mut self, id: u32, now: Duration) {
+        let Some(index) = self
+            .pending_pings
+            .iter()
+            .position(|&(pending_id, _)| pending_id == id)
+        else {
+            trace!("ignoring pong {id} without a pending ping");
+            return;
+        };
+        let (_, sent_at) = self.pending_pings[index];
+        // Pongs arrive in order on the system channel, so earlier pings won't be answered anymore.
+        self.pending_pings.drain(..=index);
+
+        let sample = now - sent_at;
+        self.rtt = Some(match self.rtt {
+            // Same smoothing factor as TCP.
+            Some(rtt) => rtt.mul_f64(7.0 / 8.0) + sample.mul_f64(1.0 / 8.0),
+            None => sample,
+        });
+    }
+
+    /// Writes the measured values into `stats`.
+    pub(crate) fn update(
+        &mut self,
+        now: Duration,
+        channel_stats: &ChannelStats,
+        stats: &mut ClientStats,
+    ) {
+        if let Some(rtt) = self.rtt {
+            stats.rtt = rtt.as_secs_f64();
+        }
+
+        let sent_bytes = channel_stats.sent_bytes();
+        let received_bytes = channel_stats.received_bytes();
+        let Some(window_start) = self.window_start else {
+            self.start_window(now, sent_bytes, received_bytes);
+            return;
+        };
+        let elapsed = now - window_start;
+        if elapsed < BANDWIDTH_WINDOW {
+            return;
+        }
+
+        let secs = elapsed.as_secs_f64();
+        stats.sent_bps = (sent_bytes - self.window_sent_bytes) as f64 / secs;
+        stats.received_bps = (received_bytes - self.window_received_bytes) as f64 / secs;
+        self.start_window(now, sent_bytes, received_bytes);
+    }
+
+    fn start_window(&mut self, now: Duration, sent_bytes: u64, received_bytes: u64) {
+        self.window_start = Some(now);
+        self.window_sent_bytes = sent_bytes;
+        self.window_received_bytes = received_bytes;
+    }
+}
+
+#[cfg(test)]
+fn monitor() -> ConnectionMonitor {
+    ConnectionMonitor::new(Heartbeat {
+        interval: Duration::from_secs(1),
+        timeout: Some(Duration::from_secs(5)),
+    })
+}
+
+#[test]
+fn test_ping_interval() {
+    let mut monitor = monitor();
+    assert!(matches!(
+        monitor.ping(Duration::ZERO),
+        Some(SystemChannelMessage::Ping(0))
+    ));
+    assert!(monitor.ping(Duration::from_millis(500)).is_none());
+    assert!(matches!(
+        monitor.ping(Duration::from_secs(1)),
+        Some(SystemChannelMessage::Ping(1))
+    ));
+}
+
+#[test]
+fn test_rtt() {
+    let mut monitor = monitor();
+    let mut stats = ClientStats::default();
+    monitor.ping(Duration::ZERO);
+    monitor.pong(0, Duration::from_millis(80));
+    monitor.update(Duration::from_millis(80), &Default::default(), &mut stats);
+    assert_eq!(stats.rtt, 0.08);
+
+    monitor.ping(Duration::from_secs(1));
+    monitor.pong(1, Duration::from_millis(1160));
+    monitor.update(Duration::from_millis(1160), &Default::default(), &mut stats);
+    assert!((stats.rtt - 0.09).abs() < 1e-9, "should be smoothed");
+
+    monitor.pong(1, Duration::from_secs(2));
+    monitor.update(Duration::from_secs(2), &Default::default(), &mut stats);
+    assert!(
+        (stats.rtt - 0.09).abs() < 1e-9,
+        "repeated pong should be ignored"
+    );
+}
+
+#[test]
+fn test_bandwidth() {
+    let mut monitor = monitor();
+    let mut channel_stats = ChannelStats::default();
+    let mut stats = ClientStats::default();
+    monitor.update(Duration::ZERO, &channel_stats, &mut stats);
+
+    channel_stats.record_sent(1, 300);
+    channel_stats.record_sent(1, 200);
+    channel_stats.record_received(0, 1000);
+    monitor.update(Duration::from_secs(2), &channel_stats, &mut stats);
+    assert_eq!(stats.sent_bps, 250.0);
+    assert_eq!(stats.received_bps, 500.0);
+    assert_eq!(
+        channel_stats.sent,
+        [
+            ChannelTraffic::default(),
+            ChannelTraffic {
+                packets: 2,
+                bytes: 500,
+                ..Default::default()
+            }
+        ]
+    );
+}
+
+#[test]
+fn test_idle_timeout() {
+    let mut monitor = monitor();
+    assert!(!monitor.timed_out(Duration::from_secs(1)));
+    assert!(!monitor.timed_out(Duration::from_secs(6)));
+    assert!(monitor.timed_out(Duration::from_secs(7)));
+
+    monitor.received(Duration::from_secs(7));
+    assert!(!monitor.timed_out(Duration::from_secs(8)));
+
+    let mut monitor = ConnectionMonitor::new(Heartbeat {
+        interval: Duration::from_secs(1),
+        timeout: None,
+    });
+    assert!(!monitor.timed_out(Duration::ZERO));
+    assert!(!monitor.timed_out(Duration::MAX));
+}
@@ -0,0 +1,218 @@
+use super::{
+    Conditioner, LoopbackSocket, MatchboxBackendError, NetworkConditions, RepliconChannelsExt,
+};
+use bevy::platform::time::Instant;
+use bevy_matchbox::MatchboxSocket;
+use bevy_matchbox::matchbox_socket::{Packet, PeerId, PeerState};
+use bevy_replicon::prelude::{Channel, RepliconChannels};
+use futures::channel::oneshot;
+
+/// Connection to the other peers used by `MatchboxHost` and `MatchboxClient`.
+///
+/// Channel 0 is the system channel, followed by the replicon server channels and then the client
+/// channels.
+pub struct Transport {
+    socket: Socket,
+    /// Present while [`NetworkConditions`] are set or delayed packets are still waiting.
+    conditioner: Option<Conditioner>,
+    /// Receives why the signaling message loop stopped, `None` if it stopped without error.
+    signaling: Option<oneshot::Receiver<Option<MatchboxBackendError>>>,
+    signaling_error: Option<MatchboxBackendError>,
+}
+
+enum Socket {
+    /// WebRTC data channels negotiated through a signaling server.
+    Matchbox(MatchboxSocket),
+    /// In-process queues, see [`LoopbackNetwork`](super::LoopbackNetwork).
+    Loopback(LoopbackSocket),
+}
+
+impl From<MatchboxSocket> for Transport {
+    fn from(socket: MatchboxSocket) -> Self {
+        Self {
+            socket: Socket::Matchbox(socket),
+            conditioner: None,
+            signaling: None,
+            signaling_error: None,
+        }
+    }
+}
+
+impl From<LoopbackSocket> for Transport {
+    fn from(socket: LoopbackSocket) -> Self {
+        Self {
+            socket: Socket::Loopback(socket),
+            conditioner: None,
+            signaling: None,
+            signaling_error: None,
+        }
+    }
+}
+
+impl Transport {
+    /// Creates a transport that reports signaling failures from `try_update_peers`.
+    pub(crate) fn with_signaling(
+        socket: MatchboxSocket,
+        signaling: oneshot::Receiver<Option<MatchboxBackendError>>,
+    ) -> Self {
+        Self {
+            signaling: Some(signaling),
+            ..socket.into()
+        }
+    }
+
+    /// Returns the id of the local peer, `None` until the signaling server assigned one.
+    pub fn id(&mut self) -> Option<PeerId> {
+        match &mut self.socket {
+            Socket::Matchbox(socket) => socket.id(),
+            Socket::Loopback(socket) => Some(socket.id()),
+        }
+    }
+
+    /// Returns the WebRTC socket, `None` for a loopback connection.
+    pub fn matchbox_socket(&mut self) -> Option<&mut MatchboxSocket> {
+        match &mut self.socket {
+            Socket::Matchbox(socket) => Some(socket),
+            Socket::Loopback(_) => None,
+        }
+    }
+
+    pub fn connected_peers(&self) -> Vec<PeerId> {
+        match &self.socket {
+            Socket::Matchbox(socket) => socket.connected_peers().collect(),
+            Socket::Loopback(socket) => socket.connected_peers().collect(),
+        }
+    }
+
+    /// Returns the peers that connected or disconnected since the last call, fails once closed.
+    ///
+    /// Tells apart a signaling server that refused or dropped the connection from closed data channels.
+    pub fn try_update_peers(&mut self) -> Result<Vec<(PeerId, PeerState)>, MatchboxBackendError> {
+        let result = match &mut self.socket {
+            Socket::Matchbox(socket) => socket.try_update_peers(),
+            Socket::Loopback(socket) => socket.try_update_peers(),
+        };
+        if let Ok(changes) = result {
+            return Ok(changes);
+        }
+        if let Some(signaling) = &mut self.signaling {
+            match signaling.try_recv() {
+                // The message loop reports right after closing the channels.
+                Ok(None) => return Ok(Vec::new()),
+                Ok(Some(error)) => self.signaling_error = error,
+                Err(oneshot::Canceled) => (),
+            }
+            self.signaling = None;
+        }
+        Err(self
+            .signaling_error
+            .clone()
+            .unwrap_or(MatchboxBackendError::DataChannelClosed))
+    }
+
+    pub fn send(&mut self, channel: usize, packet: Packet, peer_id: PeerId) {
+        let Some(conditioner) = &mut self.conditioner else {
+            self.socket.send(channel, packet, peer_id);
+            return;
+        };
+
+        let now = Instant::now();
+        conditioner.push_outgoing(channel, peer_id, packet, now);
+        for (peer_id, packet) in conditioner.take_outgoing(channel, now) {
+            self.socket.send(channel, packet, peer_id);
+        }
+    }
+
+    pub fn receive(&mut self, channel: usize) -> Vec<(PeerId, Packet)> {
+        let packets = self.socket.receive(channel);
+        let Some(conditioner) = &mut self.conditioner else {
+            return packets;
+        };
+
+        let now = Instant::now();
+        for (peer_id, packet) in packets {
+            conditioner.push_incoming(channel, peer_id, packet, now);
+        }
+        conditioner.take_incoming(channel, now)
+    }
+
+    pub fn any_channel_closed(&self) -> bool {
+        match &self.socket {
+            Socket::Matchbox(socket) => socket.any_channel_closed(),
+            Socket::Loopback(socket) => socket.is_closed(),
+        }
+    }
+
+    pub fn all_channels_closed(&self) -> bool {
+        match &self.socket {
+            Socket::Matchbox(socket) => socket.all_channels_closed(),
+            Socket::Loopback(socket) => socket.is_closed(),
+        }
+    }
+
+    pub fn close(&mut self) {
+        match &mut self.socket {
+            Socket::Matchbox(socket) => socket.close(),
+            Socket::Loopback(socket) => socket.close(),
+        }
+    }
+
+    /// Applies the current conditions and sends the delayed packets that are due.
+    ///
+    /// Packets still waiting when the conditions are removed are released as usual.
+    pub(crate) fn condition(
+        &mut self,
+        conditions: Option<&NetworkConditions>,
+        replicon_channels: &RepliconChannels,
+    ) {
+        let conditioner = match (conditions, &mut self.conditioner) {
+            (Some(&conditions), Some(conditioner)) => {
+                conditioner.set_conditions(conditions);
+                conditioner
+            }
+            (Some(&conditions), None) => {
+                // The system channel is reliable and ordered.
+                let channels = [Channel::Ordered]
+                    .into_iter()
+                    .chain(replicon_channels.all_channels().copied())
+                    .collect();
+                self.conditioner
+                    .insert(Conditioner::new(conditions, channels))
+            }
+            (None, Some(conditioner)) => {
+                conditioner.set_conditions(Default::default());
+                conditioner
+            }
+            (None, None) => return,
+        };
+
+        let now = Instant::now();
+        for channel in 0..conditioner.channel_count() {
+            for (peer_id, packet) in conditioner.take_outgoing(channel, now) {
+                self.socket.send(channel, packet, peer_id);
+            }
+        }
+        if conditions.is_none() && conditioner.is_empty() {
+            self.conditioner = None;
+        }
+    }
+}
+
+impl Socket {
+    fn send(&mut self, channel: usize, packet: Packet, peer_id: PeerId) {
+        match self {
+            Self::Matchbox(socket) => socket.channel_mut(channel).send(packet, peer_id),
+            Self::Loopback(socket) => socket.send(channel, packet, peer_id),
+        }
+    }
+
+    fn receive(&mut self, channel: usize) -> Vec<(PeerId, Packet)> {
+        match self {
+            Self::Matchbox(socket) => socket
+                .get_channel_mut(channel)
+                .map(|channel| channel.receive())
+                .unwrap_or_default(),
+            Self::Loopback(socket) => socket.receive(channel),
+        }
+    }
+}
@@ -0,0 +1,307 @@
+use crate::RoomAddress;
+use crate::server::MatchboxHost;
+use bevy::prelude::*;
+use bevy_matchbox::MatchboxServer;
+use bevy_matchbox::matchbox_signaling::SignalingServer;
+use bevy_matchbox::prelude::PeerId;
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+
+mod registry;
+
+pub use registry::LobbyRegistry;
+
+/// Starts a [`MatchboxSignalingServer`] when a [`SignalingConfig`] resource is inserted, stops it
+/// together with the [`MatchboxHost`] and turns the join and leave callbacks of the server into Bevy
+/// messages.
+///
+/// Part of [`RepliconMatchboxPlugins`](crate::RepliconMatchboxPlugins) when the `signaling` feature is
+/// enabled.
+pub struct RepliconMatchboxSignalingPlugin;
+
+impl Plugin for RepliconMatchboxSignalingPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_message::<SignalingPeerJoined>()
+            .add_message::<SignalingPeerLeft>()
+            .add_systems(
+                PreUpdate,
+                (
+                    stop_signaling_server.run_if(resource_removed::<MatchboxHost>),
+                    start_signaling_server.run_if(resource_added::<SignalingConfig>),
+                    receive_signaling_changes,
+                )
+                    .chain(),
+            );
+    }
+}
+
+fn start_signaling_server(mut commands: Commands, config: Res<SignalingConfig>) {
+    match MatchboxSignalingServer::start(config.clone()) {
+        Ok(server) => commands.insert_resource(server),
+        Err(e) => error!("unable to start the signaling server: {e}"),
+    }
+}
+
+/// Removes the config too, so inserting it again starts a new server.
+fn stop_signaling_server(mut commands: Commands, server: Option<Res<MatchboxSignalingServer>>) {
+    if server.is_some() {
+        info!("stopping the signaling server together with the host");
+        commands.remove_resource::<MatchboxSignalingServer>();
+        commands.remove_resource::<SignalingConfig>();
+    }
+}
+
+fn receive_signaling_changes(
+    server: Option<Res<MatchboxSignalingServer>>,
+    host: Option<Res<MatchboxHost>>,
+    mut joined: MessageWriter<SignalingPeerJoined>,
+    mut left: MessageWriter<SignalingPeerLeft>,
+) {
+    let servers = server
+        .as_deref()
+        .into_iter()
+        .chain(host.as_deref().and_then(MatchboxHost::signaling_server));
+    for server in servers {
+        for change in server.drain_changes() {
+            trace!("signaling peer change: {change:?}");
+            match change {
+                PeerChange::Joined(peer_id, role) => {
+                    joined.write(SignalingPeerJoined { peer_id, role });
+                }
+                PeerChange::Left(peer_id, role) => {
+                    left.write(SignalingPeerLeft { peer_id, role });
+                }
+            }
+        }
+    }
+}
+
+/// A peer connected to the signaling server.
+#[derive(Message, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SignalingPeerJoined {
+    pub peer_id: PeerId,
+    pub role: SignalingRole,
+}
+
+/// A peer disconnected from the signaling server.
+#[derive(Message, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SignalingPeerLeft {
+    pub peer_id: PeerId,
+    pub role: SignalingRole,
+}
+
+/// Role of a peer in a room, the first peer to join becomes the host.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SignalingRole {
+    Host,
+    Client,
+}
+
+#[derive(Debug)]
+enum PeerChange {
+    Joined(PeerId, SignalingRole),
+    Left(PeerId, SignalingRole),
+}
+
+/// Settings for [`MatchboxSignalingServer::start`].
+///
+/// Insert it as a resource to let [`RepliconMatchboxSignalingPlugin`] start the server on the next
+/// update, it's stopped once a [`MatchboxHost`] is removed. Defaults to listening on port 3536 of the
+/// loopback interface, without CORS and accepting every connection. Bind to
+/// [`Ipv4Addr::UNSPECIFIED`] with [`Self::with_bind_address`] to accept other machines.
+#[derive(Resource, Clone)]
+pub struct SignalingConfig {
+    bind_address: SocketAddr,
+    cors: bool,
+    lobby: Option<LobbyRegistry>,
+    connection_filter: Option<ConnectionFilter>,
+}
+
+type ConnectionFilter = Arc<dyn Fn(&SignalingRequest) -> bool + Send + Sync>;
+
+impl Default for SignalingConfig {
+    fn default() -> Self {
+        Self {
+            bind_address: (Ipv4Addr::LOCALHOST, 3536).into(),
+            cors: false,
+            lobby: None,
+            connection_filter: None,
+        }
+    }
+}
+
+impl fmt::Debug for SignalingConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SignalingConfig")
+            .field("bind_address", &self.bind_address)
+            .field("cors", &self.cors)
+            .field("lobby", &self.lobby.is_some())
+            .field("connection_filter", &self.connection_filter.is_some())
+            .finish()
+    }
+}
+
+impl SignalingConfig {
+    /// Sets the address to listen on, port 0 picks a free port reported by
+    /// [`MatchboxSignalingServer::local_addr`].
+    pub fn with_bind_address(mut self, address: impl Into<SocketAddr>) -> Self {
+        self.bind_address = address.into();
+        self
+    }
+
+    /// Allows requests from any origin, needed when the game is served from another domain in a browser.
+    pub fn with_cors(mut self, cors: bool) -> Self {
+        self.cors = cors;
+        self
+    }
+
+    /// Serves a [`LobbyRegistry`] where hosts advertise their rooms for `LobbyBrowser`.
+    pub fn with_lobby(mut self, lobby: bool) -> Self {
+        self.lobby = lobby.then(LobbyRegistry::default);
+        self
+    }
+
+    /// Like [`Self::with_lobby`], but serves `registry` to change its limits.
+    pub fn with_lobby_registry(mut self, registry: LobbyRegistry) -> Self {
+        self.lobby = Some(registry);
+        self
+    }
+
+    /// Accepts only the connections for which `filter` returns `true`.
+    ///
+    /// Called from the server task before the peer gets an id, so it shouldn't block.
+    pub fn with_connection_filter(
+        mut self,
+        filter: impl Fn(&SignalingRequest) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        self.connection_filter = Some(Arc::new(filter));
+        self
+    }
+}
+
+/// Connection request passed to the filter of [`SignalingConfig::with_connection_filter`].
+#[derive(Debug, Clone)]
+pub struct SignalingRequest {
+    /// Address of the peer.
+    pub origin: SocketAddr,
+    /// Room requested by the peer.
+    pub room: Option<String>,
+    /// Query parameters of the room URL.
+    pub query: HashMap<String, String>,
+}
+
+/// Signaling server running inside the app, with a client-server topology.
+///
+/// Inserted as a resource by [`RepliconMatchboxSignalingPlugin`] for a [`SignalingConfig`] resource
+/// and removed together with the [`MatchboxHost`].
+/// It can also be started manually and attached to the host with
+/// `MatchboxHost::with_signaling_server` to stop it together with the host.
+#[derive(Resource)]
+pub struct MatchboxSignalingServer {
+    local_addr: SocketAddr,
+    lobby: Option<LobbyRegistry>,
+    changes: Mutex<Receiver<PeerChange>>,
+    // Dropping the task stops the server.
+    _server: MatchboxServer,
+}
+
+impl MatchboxSignalingServer {
+    /// Binds the server and starts serving on the IO task pool.
+    ///
+    /// The pool is initialized by Bevy's `TaskPoolPlugin`, so call it after adding the default plugins.
+    pub fn start(config: SignalingConfig) -> io::Result<Self> {
+        let (sender, receiver) = mpsc::channel();
+        let mut builder = SignalingServer::client_server_builder(config.bind_address)
+            .on_host_connected(change_callback(&sender, |id| {
+                PeerChange::Joined(id, SignalingRole::Host)
+            }))
+            .on_host_disconnected(change_callback(&sender, |id| {
+                PeerChange::Left(id, SignalingRole::Host)
+            }))
+            .on_client_connected(change_callback(&sender, |id| {
+                PeerChange::Joined(id, SignalingRole::Client)
+            }))
+            .on_client_disconnected(change_callback(&sender, |id| {
+                PeerChange::Left(id, SignalingRole::Client)
+            }));
+        #[allow(
+            clippy::result_large_err,
+            reason = "the rejection response type is defined by matchbox"
+        )]
+        if let Some(filter) = config.connection_filter {
+            builder = builder.on_connection_request(move |meta| {
+                let request = SignalingRequest {
+                    origin: meta.origin,
+                    room: meta.path,
+                    query: meta.query_params,
+                };
+                let allowed = filter(&request);
+                if !allowed {
+                    debug!("rejecting signaling connection from {}", request.origin);
+                }
+                Ok(allowed)
+            });
+        }
+        let lobby = config.lobby;
+        if let Some(lobby) = &lobby {
+            builder = builder.mutate_router(|router| lobby.route(router));
+        }
+        // Applied last to cover the lobby route too.
+        if config.cors {
+            builder = builder.cors();
+        }
+
+        let mut server = builder.build();
+        let local_addr = server.bind().map_err(io::Error::other)?;
+        info!("signaling server listening on {local_addr}");
+
+        Ok(Self {
+            local_addr,
+            lobby,
+            changes: Mutex::new(receiver),
+            _server: server.into(),
+        })
+    }
+
+    /// Returns the address the server listens on, with the actual port if port 0 was requested.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Returns the registry enabled with [`SignalingConfig::with_lobby`].
+    pub fn lobby(&self) -> Option<&LobbyRegistry> {
+        self.lobby.as_ref()
+    }
+
+    /// Returns the address of `room` on this server, reachable from the local machine.
+    pub fn room_address(&self, room: impl Into<String>) -> RoomAddress {
+        let mut addr = self.local_addr;
+        if addr.ip().is_unspecified() {
+            addr.set_ip(Ipv4Addr::LOCALHOST.into());
+        }
+        RoomAddress::new(format!("ws://{addr}"), room)
+    }
+
+    fn drain_changes(&self) -> Vec<PeerChange> {
+        let changes = self
+            .changes
+            .lock()
+            .expect("signaling changes shouldn't be poisoned");
+        changes.try_iter().collect()
+    }
+}
+
+fn change_callback(
+    sender: &Sender<PeerChange>,
+    change: impl Fn(PeerId) -> PeerChange + Send + Sync + 'static,
+) -> impl Fn(PeerId) + Send + Sync + 'static {
+    let sender = sender.clone();
+    move |peer_id| {
+        // The receiver is gone only once the server is dropped.
+        let _ = sender.send(change(peer_id));
+    }
+}
@@ -0,0 +1,175 @@
+use crate::lobby::{LOBBY_PATH, LobbyRequest, LobbyResponse, RoomInfo};
+use axum::Router;
+use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
+use axum::routing::get;
+use bevy::prelude::*;
+use futures::channel::mpsc::{self, UnboundedSender};
+use futures::{SinkExt, StreamExt, select};
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+/// Rooms advertised by hosts, served to lobby browsers by the signaling server.
+///
+/// Enabled with [`SignalingConfig::with_lobby`](super::SignalingConfig::with_lobby), or added to a
+/// custom matchbox server with [`Self::route`].
+///
+/// Defaults to at most 1024 rooms and messages of 16 KiB, connections sending larger messages are
+/// closed.
+#[derive(Clone)]
+pub struct LobbyRegistry {
+    state: Arc<Mutex<RegistryState>>,
+    max_rooms: usize,
+    max_message_size: usize,
+}
+
+impl Default for LobbyRegistry {
+    fn default() -> Self {
+        Self {
+            state: Default::default(),
+            max_rooms: 1024,
+            max_message_size: 16 * 1024,
+        }
+    }
+}
+
+#[derive(Default)]
+struct RegistryState {
+    next_id: u64,
+    /// Advertised rooms by connection.
+    rooms: BTreeMap<u64, RoomInfo>,
+    subscribers: Vec<UnboundedSender<Vec<RoomInfo>>>,
+}
+
+impl LobbyRegistry {
+    /// Sets the number of rooms after which new advertisements are ignored until a room closes.
+    pub fn with_max_rooms(mut self, max_rooms: usize) -> Self {
+        self.max_rooms = max_rooms;
+        self
+    }
+
+    /// Sets the size of the largest message accepted from a lobby connection, in bytes.
+    pub fn with_max_message_size(mut self, max_message_size: usize) -> Self {
+        self.max_message_size = max_message_size;
+        self
+    }
+
+    /// Adds the registry route to `router`, pass it to `SignalingServerBuilder::mutate_router`.
+    pub fn route(&self, router: Router) -> Router {
+        let registry = self.clone();
+        router.route(
+            &format!("/{LOBBY_PATH}"),
+            get(move |upgrade: WebSocketUpgrade| {
+                let registry = registry.clone();
+                async move {
+                    upgrade
+                        .max_message_size(registry.max_message_size)
+                        .on_upgrade(move |socket| registry.serve(socket))
+                }
+            }),
+        )
+    }
+
+    /// Returns the rooms currently advertised.
+    pub fn rooms(&self) -> Vec<RoomInfo> {
+        self.lock().rooms.values().cloned().collect()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, RegistryState> {
+        self.state
+            .lock()
+            .expect("lobby registry shouldn't be poisoned")
+    }
+
+    async fn serve(self, socket: WebSocket) {
+        let id = {
+            let mut state = self.lock();
+            state.next_id += 1;
+            state.next_id
+        };
+        let (updates_tx, mut updates) = mpsc::unbounded();
+        let (mut sink, stream) = socket.split();
+        let mut stream = stream.fuse();
+        loop {
+            select! {
+                message = stream.next() => {
+                    let Some(Ok(message)) = message else {
+                        break;
+                    };
+                    let Message::Text(text) = message else {
+                        continue;
+                    };
+                    match serde_json::from_str(&text) {
+                        Ok(LobbyRequest::Advertise(info)) => self.advertise(id, info),
+                        Ok(LobbyRequest::Subscribe) => {
+                            let mut state = self.lock();
+                            let rooms = state.rooms.values().cloned().collect();
+                            let _ = updates_tx.unbounded_send(rooms);
+                            state.subscribers.push(updates_tx.clone());
+                        }
+                        Err(e) => debug!("ignoring invalid lobby request: {e}"),
+                    }
+                }
+                rooms = updates.next() => {
+                    let Some(rooms) = rooms else {
+                        break;
+                    };
+                    let text = serde_json::to_string(&LobbyResponse::Rooms(rooms))
+                        .expect("lobby responses should serialize");
+                    if sink.send(Message::Text(text.into())).await.is_err() {
+                        break;
+                    }
+                }
+            }
+        }
+
+        trace!("lobby connection {id} closed");
+        self.change(|state| {
+            state.rooms.remove(&id);
+        });
+    }
+
+    /// Publishes the room of connection `id`, unless it's a new room and the registry is full.
+    fn advertise(&self, id: u64, info: RoomInfo) {
+        let full = {
+            let state = self.lock();
+            !state.rooms.contains_key(&id) && state.rooms.len() >= self.max_rooms
+        };
+        if full {
+            warn!(
+                "ignoring room {:?} from lobby connection {id}, the lobby is limited to {} rooms",
+                info.room, self.max_rooms
+            );
+            return;
+        }
+
+        trace!("lobby connection {id} advertised {info:?}");
+        self.change(|state| {
+            state.rooms.insert(id, info);
+        });
+    }
+
+    /// Applies `change` and sends the new list to subscribers.
+    fn change(&self, change: impl FnOnce(&mut RegistryState)) {
+        let mut state = self.lock();
+        change(&mut state);
+        let rooms: Vec<_> = state.rooms.values().cloned().collect();
+        state
+            .subscribers
+            .retain(|subscriber| subscriber.unbounded_send(rooms.clone()).is_ok());
+    }
+}
+
+#[test]
+fn test_max_rooms() {
+    let registry = LobbyRegistry::default().with_max_rooms(1);
+    registry.advertise(1, RoomInfo::new("first"));
+    registry.advertise(2, RoomInfo::new("second"));
+    assert_eq!(registry.rooms(), [RoomInfo::new("first")]);
+
+    registry.advertise(1, RoomInfo::new("renamed"));
+    assert_eq!(
+        registry.rooms(),
+        [RoomInfo::new("renamed")],
+        "existing rooms should still be updated"
+    );
+}
@@ -1,15 +1,25 @@
 use std::{
     net::{Ipv4Addr, SocketAddrV4},
     sync::atomic::{AtomicU16, Ordering},
+    time::{Duration, Instant},
 };
 
 use bevy::{prelude::*, state::app::StatesPlugin};
 use bevy_replicon::prelude::*;
-use bevy_replicon_matchbox::{MatchboxClient, MatchboxHost, RepliconMatchboxPlugins};
+use bevy_replicon::shared::backend::connected_client::NetworkId;
+use bevy_replicon_matchbox::{
+    BanTarget, ChannelCompression, ChannelStats, ClientDisconnectReason, ClientDisconnected,
+    DirectionConditions, DisconnectReason, HandshakeRejected, HandshakeRejection, HostAssigned,
+    HostMigrated, LinkConditions, LoopbackNetwork, MalformedPacket, MalformedPacketKind,
+    MatchFound, MatchRole, MatchboxBackendConfig, MatchboxBackendError, MatchboxClient,
+    MatchboxClientConnection, MatchboxConnectionPhase, MatchboxHost, MatchmakingQueue,
+    NetworkConditions, PacketCompressor, PeerJoined, PeerLeft, ReconnectPolicy,
+    RepliconMatchboxPlugins, RoomAddress, SignalingConfig,
+};
 use serde::{Deserialize, Serialize};
 use test_log::test;
 
-//run the tests with cargo test -- --test-threads=1
+//tests using a signaling server need cargo test -- --test-threads=1, loopback ones can run in parallel
 
 static PORT_COUNTER: AtomicU16 = AtomicU16::new(30000);
 fn next_test_port() -> u16 {
@@ -58,6 +68,56 @@ fn connect_disconnect() {
     assert_eq!(*client_state, ClientState::Disconnected);
 }
 
+#[test]
+fn peer_lookup() {
+    let mut server_app = App::new();
+    let mut client_app = App::new();
+    for app in [&mut server_app, &mut client_app] {
+        app.add_plugins((
+            MinimalPlugins,
+            StatesPlugin,
+            RepliconPlugins.set(ServerPlugin::new(PostUpdate)),
+            RepliconMatchboxPlugins,
+        ))
+        .finish();
+    }
+
+    setup_loopback(&mut server_app, &mut client_app);
+
+    let peer_id = client_app
+        .world_mut()
+        .resource_mut::<MatchboxClient>()
+        .socket
+        .id()
+        .unwrap();
+    let (client_entity, connection, &network_id) = server_app
+        .world_mut()
+        .query::<(Entity, &MatchboxClientConnection, &NetworkId)>()
+        .single(server_app.world())
+        .unwrap();
+    assert_eq!(connection.peer_id(), peer_id);
+
+    let host = server_app.world().resource::<MatchboxHost>();
+    assert_eq!(
+        host.clients().collect::<Vec<_>>(),
+        [(peer_id, client_entity)]
+    );
+    assert_eq!(host.client_entity(peer_id), Some(client_entity));
+    assert_eq!(host.client_peer(client_entity), Some(peer_id));
+    assert_eq!(host.network_id(client_entity), Some(network_id));
+    assert_eq!(host.network_client(network_id), Some(client_entity));
+    assert_eq!(host.peer_network_id(peer_id), Some(network_id));
+    assert_eq!(host.network_peer(network_id), Some(peer_id));
+
+    client_app.world_mut().remove_resource::<MatchboxClient>();
+    client_app.update();
+    server_app.update();
+
+    let host = server_app.world().resource::<MatchboxHost>();
+    assert_eq!(host.client_entity(peer_id), None);
+    assert_eq!(host.network_client(network_id), None);
+}
+
 #[test]
 fn disconnect_request() {
     let port = next_test_port();
@@ -83,6 +143,59 @@ fn disconnect_request() {
         message: Test,
     });
 
+    let mut clients = server_app
+        .world_mut()
+        .query_filtered::<Entity, With<ConnectedClient>>();
+    let client = clients.single(server_app.world()).unwrap();
+    server_app
+        .world_mut()
+        .write_message(DisconnectRequest { client });
+
+    server_app.update();
+    client_app.update();
+    update_until(&mut client_app, |app| {
+        *app.world().resource::<State<ClientState>>() == ClientState::Disconnected
+    });
+
+    assert_eq!(clients.iter(server_app.world()).len(), 0);
+
+    let client_state = client_app.world().resource::<State<ClientState>>();
+    assert_eq!(*client_state, ClientState::Disconnected);
+
+    let messages = client_app.world().resource::<Messages<Test>>();
+    assert_eq!(messages.len(), 1, "last message should be received");
+
+    let mut replicated = client_app.world_mut().query::<&Replicated>();
+    assert_eq!(
+        replicated.iter(client_app.world()).len(),
+        1,
+        "last replication should be received"
+    );
+}
+
+#[test]
+fn loopback_disconnect_request() {
+    let mut server_app = App::new();
+    let mut client_app = App::new();
+    for app in [&mut server_app, &mut client_app] {
+        app.add_plugins((
+            MinimalPlugins,
+            StatesPlugin,
+            RepliconPlugins.set(ServerPlugin::new(PostUpdate)),
+            RepliconMatchboxPlugins,
+        ))
+        .add_server_message::<Test>(Channel::Ordered)
+        .finish();
+    }
+
+    setup_loopback(&mut server_app, &mut client_app);
+
+    server_app.world_mut().spawn(Replicated);
+    server_app.world_mut().write_message(ToClients {
+        mode: SendMode::Broadcast,
+        message: Test,
+    });
+
     let mut clients = server_app
         .world_mut()
         .query_filtered::<Entity, With<ConnectedClient>>();
@@ -110,6 +223,116 @@ fn disconnect_request() {
     );
 }
 
+#[test]
+fn lifecycle_messages() {
+    let mut server_app = App::new();
+    let mut client_app = App::new();
+    for app in [&mut server_app, &mut client_app] {
+        app.add_plugins((
+            MinimalPlugins,
+            StatesPlugin,
+            RepliconPlugins.set(ServerPlugin::new(PostUpdate)),
+            RepliconMatchboxPlugins,
+        ))
+        .finish();
+    }
+
+    setup_loopback(&mut server_app, &mut client_app);
+
+    let client_peer = drain_messages::<PeerJoined>(&mut server_app)[0].peer_id;
+    let host_peer = drain_messages::<PeerJoined>(&mut client_app)[0].peer_id;
+    assert_eq!(
+        drain_messages::<HostAssigned>(&mut client_app),
+        [HostAssigned { peer_id: host_peer }]
+    );
+
+    client_app
+        .world_mut()
+        .resource_mut::<MatchboxClient>()
+        .socket
+        .send(0, vec![u8::MAX; 4].into(), host_peer);
+    client_app.update();
+    server_app.update();
+
+    let mut clients = server_app
+        .world_mut()
+        .query_filtered::<Entity, With<ConnectedClient>>();
+    let client = clients.single(server_app.world()).unwrap();
+    assert_eq!(
+        drain_messages::<MalformedPacket>(&mut server_app),
+        [MalformedPacket {
+            peer_id: client_peer,
+            client: Some(client),
+            channel: 0,
+            len: 4,
+            kind: MalformedPacketKind::Deserialization,
+        }]
+    );
+
+    client_app.world_mut().remove_resource::<MatchboxClient>();
+    client_app.update();
+    server_app.update();
+    assert_eq!(
+        drain_messages::<PeerLeft>(&mut server_app),
+        [PeerLeft {
+            peer_id: client_peer,
+            client: Some(client),
+        }]
+    );
+}
+
+#[test]
+fn disconnect_reason() {
+    let port = next_test_port();
+
+    let mut server_app = App::new();
+    let mut client_app = App::new();
+    for app in [&mut server_app, &mut client_app] {
+        app.add_plugins((
+            MinimalPlugins,
+            StatesPlugin,
+            RepliconPlugins.set(ServerPlugin::new(PostUpdate)),
+            RepliconMatchboxPlugins,
+        ))
+        .finish();
+    }
+
+    setup(&mut server_app, &mut client_app, port);
+
+    let mut clients = server_app
+        .world_mut()
+        .query_filtered::<Entity, With<ConnectedClient>>();
+    let client = clients.single(server_app.world()).unwrap();
+    let reason = DisconnectReason::Kicked("afk".into());
+    server_app
+        .world_mut()
+        .resource_mut::<MatchboxHost>()
+        .disconnect_with_reason(client, reason.clone());
+
+    server_app.update();
+
+    let mut disconnected = server_app
+        .world_mut()
+        .resource_mut::<Messages<ClientDisconnected>>();
+    let disconnected: Vec<_> = disconnected.drain().collect();
+    assert_eq!(disconnected.len(), 1);
+    assert_eq!(disconnected[0].client, client);
+    assert_eq!(disconnected[0].reason, reason);
+
+    while !client_app
+        .world()
+        .contains_resource::<ClientDisconnectReason>()
+    {
+        client_app.update();
+    }
+
+    let client_reason = client_app.world().resource::<ClientDisconnectReason>();
+    assert_eq!(**client_reason, reason);
+
+    let client_state = client_app.world().resource::<State<ClientState>>();
+    assert_eq!(*client_state, ClientState::Disconnected);
+}
+
 #[test]
 fn server_stop() {
     let port = next_test_port();
@@ -162,6 +385,65 @@ fn server_stop() {
     );
 }
 
+#[test]
+fn other_peer_disconnect() {
+    let mut server_app = App::new();
+    let mut client_apps = [App::new(), App::new()];
+    for app in [&mut server_app].into_iter().chain(client_apps.iter_mut()) {
+        app.add_plugins((
+            MinimalPlugins,
+            StatesPlugin,
+            RepliconPlugins.set(ServerPlugin::new(PostUpdate)),
+            RepliconMatchboxPlugins,
+        ))
+        .finish();
+    }
+
+    // Loopback peers all see each other, like in a full mesh room.
+    let network = LoopbackNetwork::default();
+    let channels = server_app.world().resource::<RepliconChannels>();
+    let server = MatchboxHost::loopback(&network, channels, MatchboxBackendConfig::default());
+    server_app.insert_resource(server);
+    for app in &mut client_apps {
+        let channels = app.world().resource::<RepliconChannels>();
+        let client = MatchboxClient::loopback(&network, channels, MatchboxBackendConfig::default());
+        app.insert_resource(client);
+    }
+    while server_app
+        .world()
+        .resource::<MatchboxHost>()
+        .connected_clients()
+        < 2
+    {
+        for app in &mut client_apps {
+            app.update();
+        }
+        server_app.update();
+    }
+
+    let [client_app, other_app] = &mut client_apps;
+    client_app.update();
+    other_app.world_mut().remove_resource::<MatchboxClient>();
+    other_app.update();
+    client_app.update();
+
+    let client_state = client_app.world().resource::<State<ClientState>>();
+    assert_eq!(
+        *client_state,
+        ClientState::Connected,
+        "only the host leaving should disconnect the client"
+    );
+
+    server_app.world_mut().remove_resource::<MatchboxHost>();
+    server_app.update();
+    client_app.update();
+
+    let client_reason = client_app.world().resource::<ClientDisconnectReason>();
+    assert_eq!(**client_reason, DisconnectReason::ConnectionLost);
+    let client_state = client_app.world().resource::<State<ClientState>>();
+    assert_eq!(*client_state, ClientState::Disconnected);
+}
+
 #[test]
 fn replication() {
     let port = next_test_port();
@@ -182,6 +464,32 @@ fn replication() {
 
     server_app.world_mut().spawn(Replicated);
 
+    server_app.update();
+    client_app.update();
+    update_until(&mut client_app, |app| replicated_count(app) > 0);
+
+    let mut replicated = client_app.world_mut().query::<&Replicated>();
+    assert_eq!(replicated.iter(client_app.world()).len(), 1);
+}
+
+#[test]
+fn loopback_replication() {
+    let mut server_app = App::new();
+    let mut client_app = App::new();
+    for app in [&mut server_app, &mut client_app] {
+        app.add_plugins((
+            MinimalPlugins,
+            StatesPlugin,
+            RepliconPlugins.set(ServerPlugin::new(PostUpdate)),
+            RepliconMatchboxPlugins,
+        ))
+        .finish();
+    }
+
+    setup_loopback(&mut server_app, &mut client_app);
+
+    server_app.world_mut().spawn(Replicated);
+
     server_app.update();
     client_app.update();
 
@@ -213,6 +521,38 @@ fn server_message() {
         message: Test,
     });
 
+    server_app.update();
+    client_app.update();
+    update_until(&mut client_app, |app| {
+        !app.world().resource::<Messages<Test>>().is_empty()
+    });
+
+    let messages = client_app.world().resource::<Messages<Test>>();
+    assert_eq!(messages.len(), 1);
+}
+
+#[test]
+fn loopback_server_message() {
+    let mut server_app = App::new();
+    let mut client_app = App::new();
+    for app in [&mut server_app, &mut client_app] {
+        app.add_plugins((
+            MinimalPlugins,
+            StatesPlugin,
+            RepliconPlugins.set(ServerPlugin::new(PostUpdate)),
+            RepliconMatchboxPlugins,
+        ))
+        .add_server_message::<Test>(Channel::Ordered)
+        .finish();
+    }
+
+    setup_loopback(&mut server_app, &mut client_app);
+
+    server_app.world_mut().write_message(ToClients {
+        mode: SendMode::Broadcast,
+        message: Test,
+    });
+
     server_app.update();
     client_app.update();
 
@@ -243,45 +583,1473 @@ fn client_message() {
 
     client_app.update();
     server_app.update();
+    update_until(&mut server_app, |app| {
+        !app.world()
+            .resource::<Messages<FromClient<Test>>>()
+            .is_empty()
+    });
 
     let messages = server_app.world().resource::<Messages<FromClient<Test>>>();
     assert_eq!(messages.len(), 1);
 }
-fn setup(server_app: &mut App, client_app: &mut App, port: u16) {
-    start_signaling_server(server_app, port);
-    setup_server(server_app, port);
-    setup_client(client_app, port);
-    wait_for_connection(server_app, client_app);
-}
 
-use bevy_matchbox::matchbox_signaling::SignalingServer;
+#[test]
+fn loopback_client_message() {
+    let mut server_app = App::new();
+    let mut client_app = App::new();
+    for app in [&mut server_app, &mut client_app] {
+        app.add_plugins((
+            MinimalPlugins,
+            StatesPlugin,
+            RepliconPlugins.set(ServerPlugin::new(PostUpdate)),
+            RepliconMatchboxPlugins,
+        ))
+        .add_client_message::<Test>(Channel::Ordered)
+        .finish();
+    }
 
-fn start_signaling_server(server_app: &mut App, port: u16) {
-    info!("Starting signaling server");
-    let addr = SocketAddrV4::new(Ipv4Addr::LOCALHOST, port);
-    let signaling_server = bevy_matchbox::MatchboxServer::from(
-        SignalingServer::client_server_builder(addr)
-            .on_connection_request(|connection| {
-                info!("Connecting: {connection:?}");
-                Ok(true) // Allow all connections
-            })
-            .on_id_assignment(|(socket, id)| info!("{socket} received {id}"))
-            .on_host_connected(|id| info!("Host joined: {id}"))
-            .on_host_disconnected(|id| info!("Host left: {id}"))
-            .on_client_connected(|id| info!("Client joined: {id}"))
-            .on_client_disconnected(|id| info!("Client left: {id}"))
-            .cors()
-            // .trace()
-            .build(),
-    );
-    server_app.insert_resource(signaling_server);
+    setup_loopback(&mut server_app, &mut client_app);
+
+    client_app.world_mut().write_message(Test);
+
+    client_app.update();
+    server_app.update();
+
+    let messages = server_app.world().resource::<Messages<FromClient<Test>>>();
+    assert_eq!(messages.len(), 1);
+}
+#[test]
+fn game_version_mismatch() {
+    let port = next_test_port();
+
+    let mut server_app = App::new();
+    let mut client_app = App::new();
+    for app in [&mut server_app, &mut client_app] {
+        app.add_plugins((
+            MinimalPlugins,
+            StatesPlugin,
+            RepliconPlugins.set(ServerPlugin::new(PostUpdate)),
+            RepliconMatchboxPlugins,
+        ))
+        .finish();
+    }
+
+    start_signaling_server(&mut server_app, port);
+    let room_url = format!("ws://localhost:{port}/TestRoom");
+    let channels = server_app.world().resource::<RepliconChannels>();
+    let server = MatchboxHost::new(room_url.clone(), channels, MatchboxBackendConfig::default())
+        .unwrap()
+        .with_game_version("1.0");
+    server_app.insert_resource(server);
+    let channels = client_app.world().resource::<RepliconChannels>();
+    let client = MatchboxClient::new(room_url, channels, MatchboxBackendConfig::default())
+        .unwrap()
+        .with_game_version("2.0");
+    client_app.insert_resource(client);
+
+    let (server_rejections, client_rejections) =
+        wait_for_rejection(&mut server_app, &mut client_app);
+
+    let expected = HandshakeRejection::GameVersion {
+        host: "1.0".into(),
+        client: "2.0".into(),
+    };
+    assert_eq!(server_rejections, client_rejections);
+    assert_eq!(client_rejections, [expected]);
+
+    let mut clients = server_app.world_mut().query::<&ConnectedClient>();
+    assert_eq!(clients.iter(server_app.world()).len(), 0);
+
+    let client_state = client_app.world().resource::<State<ClientState>>();
+    assert_eq!(*client_state, ClientState::Disconnected);
+}
+
+#[test]
+fn authentication_rejected() {
+    let port = next_test_port();
+
+    let mut server_app = App::new();
+    let mut client_app = App::new();
+    for app in [&mut server_app, &mut client_app] {
+        app.add_plugins((
+            MinimalPlugins,
+            StatesPlugin,
+            RepliconPlugins.set(ServerPlugin::new(PostUpdate)),
+            RepliconMatchboxPlugins,
+        ))
+        .finish();
+    }
+
+    start_signaling_server(&mut server_app, port);
+    let room_url = format!("ws://localhost:{port}/TestRoom");
+    let channels = server_app.world().resource::<RepliconChannels>();
+    let server = MatchboxHost::new(room_url.clone(), channels, MatchboxBackendConfig::default())
+        .unwrap()
+        .with_authenticator(|request| {
+            if request.token == b"secret" {
+                Ok(())
+            } else {
+                Err("wrong password".into())
+            }
+        });
+    server_app.insert_resource(server);
+    let channels = client_app.world().resource::<RepliconChannels>();
+    let client = MatchboxClient::new(room_url, channels, MatchboxBackendConfig::default())
+        .unwrap()
+        .with_auth_token(b"guess".as_slice());
+    client_app.insert_resource(client);
+
+    let (server_rejections, client_rejections) =
+        wait_for_rejection(&mut server_app, &mut client_app);
+
+    let expected = HandshakeRejection::Unauthorized("wrong password".into());
+    assert_eq!(server_rejections, client_rejections);
+    assert_eq!(client_rejections, [expected]);
+
+    let mut clients = server_app.world_mut().query::<&ConnectedClient>();
+    assert_eq!(clients.iter(server_app.world()).len(), 0);
+}
+
+#[test]
+fn async_authentication() {
+    let port = next_test_port();
+
+    let mut server_app = App::new();
+    let mut client_app = App::new();
+    for app in [&mut server_app, &mut client_app] {
+        app.add_plugins((
+            MinimalPlugins,
+            StatesPlugin,
+            RepliconPlugins.set(ServerPlugin::new(PostUpdate)),
+            RepliconMatchboxPlugins,
+        ))
+        .finish();
+    }
+
+    start_signaling_server(&mut server_app, port);
+    let room_url = format!("ws://localhost:{port}/TestRoom");
+    let channels = server_app.world().resource::<RepliconChannels>();
+    let server = MatchboxHost::new(room_url.clone(), channels, MatchboxBackendConfig::default())
+        .unwrap()
+        .with_async_authenticator(|request| async move {
+            if request.token == b"secret" {
+                Ok(())
+            } else {
+                Err("wrong password".into())
+            }
+        });
+    server_app.insert_resource(server);
+    let channels = client_app.world().resource::<RepliconChannels>();
+    let client = MatchboxClient::new(room_url, channels, MatchboxBackendConfig::default())
+        .unwrap()
+        .with_auth_token(b"secret".as_slice());
+    client_app.insert_resource(client);
+
+    wait_for_connection(&mut server_app, &mut client_app);
+
+    let mut clients = server_app.world_mut().query::<&ConnectedClient>();
+    assert_eq!(clients.iter(server_app.world()).len(), 1);
+}
+
+#[test]
+fn max_clients() {
+    let mut server_app = App::new();
+    let mut first_client = App::new();
+    let mut second_client = App::new();
+    for app in [&mut server_app, &mut first_client, &mut second_client] {
+        app.add_plugins((
+            MinimalPlugins,
+            StatesPlugin,
+            RepliconPlugins.set(ServerPlugin::new(PostUpdate)),
+            RepliconMatchboxPlugins,
+        ))
+        .finish();
+    }
+
+    let network = LoopbackNetwork::default();
+    let channels = server_app.world().resource::<RepliconChannels>();
+    let server = MatchboxHost::loopback(&network, channels, MatchboxBackendConfig::default())
+        .with_max_clients(1);
+    server_app.insert_resource(server);
+    for client_app in [&mut first_client, &mut second_client] {
+        let channels = client_app.world().resource::<RepliconChannels>();
+        let client = MatchboxClient::loopback(&network, channels, MatchboxBackendConfig::default());
+        client_app.insert_resource(client);
+    }
+    wait_for_connection(&mut server_app, &mut first_client);
+
+    let (server_rejections, client_rejections) =
+        wait_for_rejection(&mut server_app, &mut second_client);
+    assert_eq!(server_rejections, [HandshakeRejection::ServerFull]);
+    assert_eq!(client_rejections, [HandshakeRejection::ServerFull]);
+    let reason = second_client.world().resource::<ClientDisconnectReason>();
+    assert_eq!(reason.0, DisconnectReason::ServerFull);
+
+    let mut clients = server_app.world_mut().query::<&ConnectedClient>();
+    assert_eq!(clients.iter(server_app.world()).len(), 1);
+
+    server_app
+        .world_mut()
+        .resource_mut::<MatchboxHost>()
+        .set_max_clients(None);
+    let channels = second_client.world().resource::<RepliconChannels>();
+    let client = MatchboxClient::loopback(&network, channels, MatchboxBackendConfig::default());
+    second_client.insert_resource(client);
+    wait_for_connection(&mut server_app, &mut second_client);
+    assert_eq!(clients.iter(server_app.world()).len(), 2);
+}
+
+#[test]
+fn spectators() {
+    let mut server_app = App::new();
+    let mut player = App::new();
+    let mut spectator = App::new();
+    for app in [&mut server_app, &mut player, &mut spectator] {
+        app.add_plugins((
+            MinimalPlugins,
+            StatesPlugin,
+            RepliconPlugins.set(ServerPlugin::new(PostUpdate)),
+            RepliconMatchboxPlugins,
+        ))
+        .add_client_message::<Test>(Channel::Ordered)
+        .finish();
+    }
+
+    let network = LoopbackNetwork::default();
+    let channels = server_app.world().resource::<RepliconChannels>();
+    let server = MatchboxHost::loopback(&network, channels, MatchboxBackendConfig::default())
+        .with_max_clients(1);
+    server_app.insert_resource(server);
+    for (client_app, is_spectator) in [(&mut player, false), (&mut spectator, true)] {
+        let channels = client_app.world().resource::<RepliconChannels>();
+        let client = MatchboxClient::loopback(&network, channels, MatchboxBackendConfig::default())
+            .with_spectator(is_spectator);
+        client_app.insert_resource(client);
+        wait_for_connection(&mut server_app, client_app);
+    }
+
+    let host = server_app.world().resource::<MatchboxHost>();
+    assert_eq!(host.connected_clients(), 2);
+    assert_eq!(host.players(), 1);
+    assert_eq!(received_messages(&mut server_app, &mut spectator), 0);
+    assert_eq!(received_messages(&mut server_app, &mut player), 1);
+
+    let mut clients = server_app
+        .world_mut()
+        .query_filtered::<Entity, With<ConnectedClient>>();
+    let entities: Vec<_> = clients.iter(server_app.world()).collect();
+    let mut host = server_app.world_mut().resource_mut::<MatchboxHost>();
+    let (spectator_entity, player_entity) = if host.is_spectator(entities[0]) {
+        (entities[0], entities[1])
+    } else {
+        (entities[1], entities[0])
+    };
+    assert!(host.demote(player_entity));
+    assert!(host.promote(spectator_entity));
+    assert!(!host.promote(spectator_entity), "already a player");
+
+    server_app.update();
+    player.update();
+    spectator.update();
+    assert!(player.world().resource::<MatchboxClient>().is_spectator());
+    assert!(
+        !spectator
+            .world()
+            .resource::<MatchboxClient>()
+            .is_spectator()
+    );
+    assert_eq!(received_messages(&mut server_app, &mut player), 0);
+    assert_eq!(received_messages(&mut server_app, &mut spectator), 1);
+}
+
+#[test]
+fn ban() {
+    let mut server_app = App::new();
+    let mut client_app = App::new();
+    for app in [&mut server_app, &mut client_app] {
+        app.add_plugins((
+            MinimalPlugins,
+            StatesPlugin,
+            RepliconPlugins.set(ServerPlugin::new(PostUpdate)),
+            RepliconMatchboxPlugins,
+        ))
+        .finish();
+    }
+
+    let network = LoopbackNetwork::default();
+    let channels = server_app.world().resource::<RepliconChannels>();
+    let server = MatchboxHost::loopback(&network, channels, MatchboxBackendConfig::default());
+    server_app.insert_resource(server);
+    let connect = |client_app: &mut App| {
+        let channels = client_app.world().resource::<RepliconChannels>();
+        let client = MatchboxClient::loopback(&network, channels, MatchboxBackendConfig::default())
+            .with_identity("griefer");
+        client_app.insert_resource(client);
+    };
+    connect(&mut client_app);
+    wait_for_connection(&mut server_app, &mut client_app);
+
+    let mut clients = server_app
+        .world_mut()
+        .query_filtered::<Entity, With<ConnectedClient>>();
+    let client_entity = clients.single(server_app.world()).unwrap();
+    server_app
+        .world_mut()
+        .resource_mut::<MatchboxHost>()
+        .ban(client_entity, None, "griefing");
+    while !client_app
+        .world()
+        .contains_resource::<ClientDisconnectReason>()
+    {
+        server_app.update();
+        client_app.update();
+    }
+    let reason = client_app.world().resource::<ClientDisconnectReason>();
+    assert_eq!(reason.0, DisconnectReason::Banned);
+
+    connect(&mut client_app);
+    let (server_rejections, client_rejections) =
+        wait_for_rejection(&mut server_app, &mut client_app);
+    let expected = HandshakeRejection::Banned("griefing".into());
+    assert_eq!(server_rejections, client_rejections);
+    assert_eq!(client_rejections, [expected]);
+    assert_eq!(clients.iter(server_app.world()).len(), 0);
+
+    let host = server_app.world().resource::<MatchboxHost>();
+    let ban = host.ban_list().find("griefer", b"").unwrap();
+    assert_eq!(ban.target, BanTarget::Identity("griefer".into()));
+}
+
+#[test]
+fn max_packet_size() {
+    let port = next_test_port();
+
+    let mut server_app = App::new();
+    let mut client_app = App::new();
+    for app in [&mut server_app, &mut client_app] {
+        app.add_plugins((
+            MinimalPlugins,
+            StatesPlugin,
+            RepliconPlugins.set(ServerPlugin::new(PostUpdate)),
+            RepliconMatchboxPlugins,
+        ))
+        .finish();
+    }
+
+    start_signaling_server(&mut server_app, port);
+    let room_url = format!("ws://localhost:{port}/TestRoom");
+    let channels = server_app.world().resource::<RepliconChannels>();
+    let server = MatchboxHost::new(
+        room_url.clone(),
+        channels,
+        MatchboxBackendConfig::default().with_max_packet_size(1000),
+    )
+    .unwrap();
+    server_app.insert_resource(server);
+    let channels = client_app.world().resource::<RepliconChannels>();
+    let client = MatchboxClient::new(
+        room_url,
+        channels,
+        MatchboxBackendConfig::default().with_max_packet_size(600),
+    )
+    .unwrap();
+    client_app.insert_resource(client);
+
+    wait_for_connection(&mut server_app, &mut client_app);
+
+    let mut clients = server_app.world_mut().query::<&ConnectedClient>();
+    let client = clients.single(server_app.world()).unwrap();
+    assert_eq!(client.max_size, 599, "should leave room for the marker");
+}
+
+#[test]
+fn negotiated_packet_size() {
+    let mut server_app = App::new();
+    let mut client_app = App::new();
+    for app in [&mut server_app, &mut client_app] {
+        app.add_plugins((
+            MinimalPlugins,
+            StatesPlugin,
+            RepliconPlugins.set(ServerPlugin::new(PostUpdate)),
+            RepliconMatchboxPlugins,
+        ))
+        .add_client_message::<LargeUpload>(Channel::Ordered)
+        .finish();
+    }
+
+    let network = LoopbackNetwork::default();
+    let channels = server_app.world().resource::<RepliconChannels>();
+    let config = MatchboxBackendConfig::default().with_max_packet_size(600);
+    let server = MatchboxHost::loopback(&network, channels, config);
+    server_app.insert_resource(server);
+    let channels = client_app.world().resource::<RepliconChannels>();
+    let config = MatchboxBackendConfig::default().with_max_packet_size(1000);
+    let client = MatchboxClient::loopback(&network, channels, config);
+    client_app.insert_resource(client);
+    wait_for_connection(&mut server_app, &mut client_app);
+
+    let payload = vec![1; 800];
+    client_app
+        .world_mut()
+        .write_message(LargeUpload(payload.clone()));
+    client_app.update();
+    server_app.update();
+
+    let channel_id = client_app
+        .world()
+        .resource::<RepliconChannels>()
+        .client_channels()
+        .len()
+        - 1;
+    let stats = client_app.world().resource::<ChannelStats>();
+    assert_eq!(
+        stats.sent[channel_id].packets, 2,
+        "should split with the host limit"
+    );
+    let received: Vec<_> = server_app
+        .world_mut()
+        .resource_mut::<Messages<FromClient<LargeUpload>>>()
+        .drain()
+        .map(|from_client| from_client.message.0)
+        .collect();
+    assert_eq!(received, [payload]);
+}
+
+#[test]
+fn fragmented_messages() {
+    let mut server_app = App::new();
+    let mut client_app = App::new();
+    for app in [&mut server_app, &mut client_app] {
+        app.add_plugins((
+            MinimalPlugins,
+            StatesPlugin,
+            RepliconPlugins.set(ServerPlugin::new(PostUpdate)),
+            RepliconMatchboxPlugins,
+        ))
+        .add_server_message::<Large>(Channel::Ordered)
+        .add_client_message::<LargeUpload>(Channel::Ordered)
+        .finish();
+    }
+
+    setup_loopback(&mut server_app, &mut client_app);
+
+    let payload: Vec<u8> = (0..100_000).map(|i| i as u8).collect();
+    server_app.world_mut().write_message(ToClients {
+        mode: SendMode::Broadcast,
+        message: Large(payload.clone()),
+    });
+    client_app
+        .world_mut()
+        .write_message(LargeUpload(payload.clone()));
+
+    let mut server_received = Vec::new();
+    let mut client_received = Vec::new();
+    for _ in 0..100 {
+        server_app.update();
+        client_app.update();
+        server_received.extend(
+            server_app
+                .world_mut()
+                .resource_mut::<Messages<FromClient<LargeUpload>>>()
+                .drain()
+                .map(|from_client| from_client.message.0),
+        );
+        client_received.extend(
+            client_app
+                .world_mut()
+                .resource_mut::<Messages<Large>>()
+                .drain()
+                .map(|message| message.0),
+        );
+        if !server_received.is_empty() && !client_received.is_empty() {
+            break;
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    }
+
+    assert_eq!(server_received, std::slice::from_ref(&payload));
+    assert_eq!(client_received, [payload]);
+}
+
+#[test]
+fn compression() {
+    let mut server_app = App::new();
+    let mut client_app = App::new();
+    for app in [&mut server_app, &mut client_app] {
+        app.add_plugins((
+            MinimalPlugins,
+            StatesPlugin,
+            RepliconPlugins.set(ServerPlugin::new(PostUpdate)),
+            RepliconMatchboxPlugins,
+        ))
+        .add_server_message::<Large>(Channel::Ordered)
+        .add_client_message::<LargeUpload>(Channel::Ordered)
+        .finish();
+    }
+
+    let channels = server_app.world().resource::<RepliconChannels>();
+    let mut config = MatchboxBackendConfig::default();
+    for channel_id in 0..channels.server_channels().len() {
+        config =
+            config.with_server_channel_compression(channel_id, ChannelCompression::new(RunLength));
+    }
+    for channel_id in 0..channels.client_channels().len() {
+        config =
+            config.with_client_channel_compression(channel_id, ChannelCompression::new(RunLength));
+    }
+    let network = LoopbackNetwork::default();
+    let server = MatchboxHost::loopback(&network, channels, config.clone());
+    server_app.insert_resource(server);
+    let channels = client_app.world().resource::<RepliconChannels>();
+    let client = MatchboxClient::loopback(&network, channels, config);
+    client_app.insert_resource(client);
+    wait_for_connection(&mut server_app, &mut client_app);
+
+    let payload = vec![7; 100_000];
+    server_app.world_mut().write_message(ToClients {
+        mode: SendMode::Broadcast,
+        message: Large(payload.clone()),
+    });
+    client_app
+        .world_mut()
+        .write_message(LargeUpload(payload.clone()));
+
+    let mut server_received = Vec::new();
+    let mut client_received = Vec::new();
+    for _ in 0..100 {
+        server_app.update();
+        client_app.update();
+        server_received.extend(
+            server_app
+                .world_mut()
+                .resource_mut::<Messages<FromClient<LargeUpload>>>()
+                .drain()
+                .map(|from_client| from_client.message.0),
+        );
+        client_received.extend(
+            client_app
+                .world_mut()
+                .resource_mut::<Messages<Large>>()
+                .drain()
+                .map(|message| message.0),
+        );
+        if !server_received.is_empty() && !client_received.is_empty() {
+            break;
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    }
+
+    assert_eq!(server_received, std::slice::from_ref(&payload));
+    assert_eq!(client_received, [payload]);
+
+    // Sent as a single packet instead of about a hundred fragments.
+    let client_stats = client_app.world().resource::<ChannelStats>();
+    let upload = client_stats
+        .sent
+        .iter()
+        .find(|traffic| traffic.uncompressed_bytes > 0)
+        .unwrap();
+    assert_eq!(upload.packets, 1);
+    assert!(upload.compression_ratio().unwrap() < 0.01);
+    let download = client_stats
+        .received
+        .iter()
+        .find(|traffic| traffic.uncompressed_bytes >= 100_000)
+        .unwrap();
+    assert!(download.compression_ratio().unwrap() < 0.01);
+
+    let host_stats = server_app
+        .world_mut()
+        .query::<&ChannelStats>()
+        .single(server_app.world())
+        .unwrap();
+    assert!(
+        host_stats
+            .received
+            .iter()
+            .any(|traffic| traffic.uncompressed_bytes >= 100_000)
+    );
+}
+
+#[test]
+fn compression_mismatch() {
+    let mut server_app = App::new();
+    let mut client_app = App::new();
+    for app in [&mut server_app, &mut client_app] {
+        app.add_plugins((
+            MinimalPlugins,
+            StatesPlugin,
+            RepliconPlugins.set(ServerPlugin::new(PostUpdate)),
+            RepliconMatchboxPlugins,
+        ))
+        .finish();
+    }
+
+    let network = LoopbackNetwork::default();
+    let channels = server_app.world().resource::<RepliconChannels>();
+    let config = MatchboxBackendConfig::default()
+        .with_server_channel_compression(0, ChannelCompression::new(RunLength));
+    let server = MatchboxHost::loopback(&network, channels, config);
+    server_app.insert_resource(server);
+    let channels = client_app.world().resource::<RepliconChannels>();
+    let client = MatchboxClient::loopback(&network, channels, MatchboxBackendConfig::default());
+    client_app.insert_resource(client);
+
+    let (server_rejections, client_rejections) =
+        wait_for_rejection(&mut server_app, &mut client_app);
+    assert_eq!(server_rejections, client_rejections);
+    assert_eq!(client_rejections, [HandshakeRejection::ChannelLayout]);
+}
+
+#[test]
+fn network_conditions() {
+    let mut server_app = App::new();
+    let mut client_app = App::new();
+    for app in [&mut server_app, &mut client_app] {
+        app.add_plugins((
+            MinimalPlugins,
+            StatesPlugin,
+            RepliconPlugins.set(ServerPlugin::new(PostUpdate)),
+            RepliconMatchboxPlugins,
+        ))
+        .add_server_message::<Test>(Channel::Unreliable)
+        .finish();
+    }
+
+    setup_loopback(&mut server_app, &mut client_app);
+
+    let latency = Duration::from_millis(100);
+    client_app.insert_resource(NetworkConditions {
+        incoming: DirectionConditions::uniform(LinkConditions {
+            latency,
+            ..Default::default()
+        }),
+        ..Default::default()
+    });
+    server_app.world_mut().write_message(ToClients {
+        mode: SendMode::Broadcast,
+        message: Test,
+    });
+    let sent_at = Instant::now();
+
+    server_app.update();
+    client_app.update();
+    assert!(client_app.world().resource::<Messages<Test>>().is_empty());
+
+    while client_app.world().resource::<Messages<Test>>().is_empty() {
+        std::thread::sleep(Duration::from_millis(10));
+        client_app.update();
+    }
+    assert!(sent_at.elapsed() >= latency);
+
+    client_app
+        .world_mut()
+        .remove_resource::<NetworkConditions>();
+    server_app.world_mut().write_message(ToClients {
+        mode: SendMode::Broadcast,
+        message: Test,
+    });
+
+    server_app.update();
+    client_app.update();
+    let messages = client_app.world().resource::<Messages<Test>>();
+    assert_eq!(messages.len(), 2, "should be delivered without delay");
+}
+
+#[test]
+fn connection_stats() {
+    let port = next_test_port();
+
+    let mut server_app = App::new();
+    let mut client_app = App::new();
+    for app in [&mut server_app, &mut client_app] {
+        app.add_plugins((
+            MinimalPlugins,
+            StatesPlugin,
+            RepliconPlugins.set(ServerPlugin::new(PostUpdate)),
+            RepliconMatchboxPlugins,
+        ))
+        .add_client_message::<Test>(Channel::Ordered)
+        .finish();
+    }
+
+    setup(&mut server_app, &mut client_app, port);
+
+    client_app.world_mut().write_message(Test);
+    let mut clients = server_app
+        .world_mut()
+        .query::<(&ClientStats, &ChannelStats)>();
+    for _ in 0..100 {
+        client_app.update();
+        server_app.update();
+        let (host_stats, _) = clients.single(server_app.world()).unwrap();
+        let client_stats = client_app.world().resource::<ClientStats>();
+        if host_stats.rtt > 0.0 && client_stats.rtt > 0.0 {
+            break;
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    }
+
+    let (host_stats, host_channel_stats) = clients.single(server_app.world()).unwrap();
+    assert!(host_stats.rtt > 0.0);
+    assert!(client_app.world().resource::<ClientStats>().rtt > 0.0);
+
+    let client_channel_stats = client_app.world().resource::<ChannelStats>();
+    let channels = client_app.world().resource::<RepliconChannels>();
+    let message_channel = channels.client_channels().len() - 1;
+    assert_eq!(
+        client_channel_stats.sent[message_channel], host_channel_stats.received[message_channel],
+        "both sides should count the message"
+    );
+    assert_eq!(client_channel_stats.sent[message_channel].packets, 1);
+}
+
+#[test]
+fn idle_timeout() {
+    let port = next_test_port();
+
+    let mut server_app = App::new();
+    let mut client_app = App::new();
+    for app in [&mut server_app, &mut client_app] {
+        app.add_plugins((
+            MinimalPlugins,
+            StatesPlugin,
+            RepliconPlugins.set(ServerPlugin::new(PostUpdate)),
+            RepliconMatchboxPlugins,
+        ))
+        .finish();
+    }
+
+    start_signaling_server(&mut server_app, port);
+    let room_url = format!("ws://localhost:{port}/TestRoom");
+    let config = MatchboxBackendConfig::default()
+        .with_heartbeat_interval(Duration::from_millis(50))
+        .with_idle_timeout(Some(Duration::from_millis(500)));
+    let channels = server_app.world().resource::<RepliconChannels>();
+    let server = MatchboxHost::new(room_url.clone(), channels, config.clone()).unwrap();
+    server_app.insert_resource(server);
+    let channels = client_app.world().resource::<RepliconChannels>();
+    let client = MatchboxClient::new(room_url, channels, config).unwrap();
+    client_app.insert_resource(client);
+
+    wait_for_connection(&mut server_app, &mut client_app);
+
+    for _ in 0..15 {
+        client_app.update();
+        server_app.update();
+        std::thread::sleep(Duration::from_millis(50));
+    }
+    let host = server_app.world().resource::<MatchboxHost>();
+    assert_eq!(
+        host.connected_clients(),
+        1,
+        "heartbeats should keep it alive"
+    );
+
+    // Only the host keeps running, as if the client froze.
+    for _ in 0..20 {
+        server_app.update();
+        if server_app
+            .world()
+            .resource::<MatchboxHost>()
+            .connected_clients()
+            == 0
+        {
+            break;
+        }
+        std::thread::sleep(Duration::from_millis(50));
+    }
+
+    let mut disconnected = server_app
+        .world_mut()
+        .resource_mut::<Messages<ClientDisconnected>>();
+    let disconnected: Vec<_> = disconnected.drain().collect();
+    assert_eq!(disconnected.len(), 1);
+    assert_eq!(disconnected[0].reason, DisconnectReason::Timeout);
+}
+
+#[test]
+fn idle_session_suspension() {
+    let mut server_app = App::new();
+    let mut client_app = App::new();
+    for app in [&mut server_app, &mut client_app] {
+        app.add_plugins((
+            MinimalPlugins,
+            StatesPlugin,
+            RepliconPlugins.set(ServerPlugin::new(PostUpdate)),
+            RepliconMatchboxPlugins,
+        ))
+        .finish();
+    }
+
+    let network = LoopbackNetwork::default();
+    let config = MatchboxBackendConfig::default()
+        .with_heartbeat_interval(Duration::from_millis(50))
+        .with_idle_timeout(Some(Duration::from_millis(300)));
+    let channels = server_app.world().resource::<RepliconChannels>();
+    let server = MatchboxHost::loopback(&network, channels, config.clone())
+        .with_session_resumption(Duration::from_secs(30));
+    server_app.insert_resource(server);
+    let channels = client_app.world().resource::<RepliconChannels>();
+    let client =
+        MatchboxClient::loopback(&network, channels, config).with_reconnect(ReconnectPolicy {
+            initial_delay: Duration::ZERO,
+            ..Default::default()
+        });
+    client_app.insert_resource(client);
+
+    wait_for_connection(&mut server_app, &mut client_app);
+
+    let mut clients = server_app
+        .world_mut()
+        .query_filtered::<Entity, With<ConnectedClient>>();
+    let client_entity = clients.single(server_app.world()).unwrap();
+
+    // Only the host keeps running, as if the client froze.
+    let start = Instant::now();
+    while server_app
+        .world()
+        .resource::<MatchboxHost>()
+        .connected_clients()
+        > 0
+    {
+        assert!(start.elapsed() < Duration::from_secs(5), "should time out");
+        server_app.update();
+        std::thread::sleep(Duration::from_millis(50));
+    }
+    assert!(drain_messages::<ClientDisconnected>(&mut server_app).is_empty());
+    let clients_after: Vec<_> = clients.iter(server_app.world()).collect();
+    assert_eq!(clients_after, [client_entity], "session should be kept");
+
+    // Without heartbeats from the host, the client reconnects and resumes the session.
+    let start = Instant::now();
+    loop {
+        assert!(start.elapsed() < Duration::from_secs(5), "should resume");
+        client_app.update();
+        server_app.update();
+        let host = server_app.world().resource::<MatchboxHost>();
+        let client = client_app.world().resource::<MatchboxClient>();
+        if host.connected_clients() == 1 && client.is_connected() && !client.is_reconnecting() {
+            break;
+        }
+        std::thread::sleep(Duration::from_millis(50));
+    }
+    let clients_after: Vec<_> = clients.iter(server_app.world()).collect();
+    assert_eq!(clients_after, [client_entity]);
+}
+
+#[test]
+fn host_timeout() {
+    let port = next_test_port();
+
+    let mut server_app = App::new();
+    let mut client_app = App::new();
+    for app in [&mut server_app, &mut client_app] {
+        app.add_plugins((
+            MinimalPlugins,
+            StatesPlugin,
+            RepliconPlugins.set(ServerPlugin::new(PostUpdate)),
+            RepliconMatchboxPlugins,
+        ))
+        .finish();
+    }
+
+    start_signaling_server(&mut server_app, port);
+    let room_url = format!("ws://localhost:{port}/TestRoom");
+    let channels = server_app.world().resource::<RepliconChannels>();
+    let server =
+        MatchboxHost::new(room_url.clone(), channels, MatchboxBackendConfig::default()).unwrap();
+    server_app.insert_resource(server);
+    let channels = client_app.world().resource::<RepliconChannels>();
+    let config = MatchboxBackendConfig::default()
+        .with_heartbeat_interval(Duration::from_millis(50))
+        .with_idle_timeout(Some(Duration::from_millis(500)));
+    let client = MatchboxClient::new(room_url, channels, config).unwrap();
+    client_app.insert_resource(client);
+
+    wait_for_connection(&mut server_app, &mut client_app);
+
+    // Only the client keeps running, as if the host froze.
+    for _ in 0..20 {
+        client_app.update();
+        if client_app
+            .world()
+            .contains_resource::<ClientDisconnectReason>()
+        {
+            break;
+        }
+        std::thread::sleep(Duration::from_millis(50));
+    }
+
+    let client_reason = client_app.world().resource::<ClientDisconnectReason>();
+    assert_eq!(**client_reason, DisconnectReason::Timeout);
+
+    client_app.update();
+    let client_state = client_app.world().resource::<State<ClientState>>();
+    assert_eq!(*client_state, ClientState::Disconnected);
+}
+
+#[test]
+fn session_resumption() {
+    let mut server_app = App::new();
+    let mut client_app = App::new();
+    for app in [&mut server_app, &mut client_app] {
+        app.add_plugins((
+            MinimalPlugins,
+            StatesPlugin,
+            RepliconPlugins.set(ServerPlugin::new(PostUpdate)),
+            RepliconMatchboxPlugins,
+        ))
+        .finish();
+    }
+
+    let network = LoopbackNetwork::default();
+    let channels = server_app.world().resource::<RepliconChannels>();
+    let server = MatchboxHost::loopback(&network, channels, MatchboxBackendConfig::default())
+        .with_session_resumption(Duration::from_secs(30));
+    server_app.insert_resource(server);
+    let channels = client_app.world().resource::<RepliconChannels>();
+    let client = MatchboxClient::loopback(&network, channels, MatchboxBackendConfig::default())
+        .with_reconnect(ReconnectPolicy {
+            initial_delay: Duration::ZERO,
+            ..Default::default()
+        });
+    client_app.insert_resource(client);
+
+    wait_for_connection(&mut server_app, &mut client_app);
+
+    let mut clients = server_app
+        .world_mut()
+        .query_filtered::<Entity, With<ConnectedClient>>();
+    let client_entity = clients.single(server_app.world()).unwrap();
+
+    let mut client = client_app.world_mut().resource_mut::<MatchboxClient>();
+    client.set_max_packet_size(600);
+    client.socket.close();
+
+    client_app.update();
+    assert!(
+        client_app
+            .world()
+            .resource::<MatchboxClient>()
+            .is_reconnecting()
+    );
+
+    loop {
+        client_app.update();
+        server_app.update();
+
+        let client_state = client_app.world().resource::<State<ClientState>>();
+        assert_eq!(*client_state, ClientState::Connected);
+
+        let client = client_app.world().resource::<MatchboxClient>();
+        if client.is_connected() && !client.is_reconnecting() {
+            break;
+        }
+    }
+
+    let clients: Vec<_> = clients.iter(server_app.world()).collect();
+    assert_eq!(clients, [client_entity]);
+    let connected = server_app.world().get::<ConnectedClient>(client_entity);
+    assert_eq!(
+        connected.unwrap().max_size,
+        599,
+        "should use the new size minus the marker"
+    );
+}
+
+#[test]
+fn signaling_connection_refused() {
+    let port = next_test_port();
+    let mut client_app = App::new();
+    client_app
+        .add_plugins((
+            MinimalPlugins,
+            StatesPlugin,
+            RepliconPlugins.set(ServerPlugin::new(PostUpdate)),
+            RepliconMatchboxPlugins,
+        ))
+        .finish();
+
+    // Nothing listens on the port.
+    let room_url = format!("ws://localhost:{port}/TestRoom");
+    let channels = client_app.world().resource::<RepliconChannels>();
+    let config = MatchboxBackendConfig::default().with_connection_attempts(Some(1));
+    let client = MatchboxClient::new(room_url, channels, config).unwrap();
+    client_app.insert_resource(client);
+
+    let errors = loop {
+        client_app.update();
+        let errors = drain_messages::<MatchboxBackendError>(&mut client_app);
+        if !errors.is_empty() {
+            break errors;
+        }
+    };
+    assert!(
+        matches!(
+            errors[..],
+            [MatchboxBackendError::SignalingConnectionRefused(_)]
+        ),
+        "unexpected errors {errors:?}"
+    );
+
+    client_app.update();
+    assert!(!client_app.world().contains_resource::<MatchboxClient>());
+    let reason = client_app.world().resource::<ClientDisconnectReason>();
+    assert_eq!(reason.0, DisconnectReason::SocketError);
+
+    let channels = client_app.world().resource::<RepliconChannels>();
+    let result = MatchboxHost::new(
+        "http://localhost/TestRoom",
+        channels,
+        MatchboxBackendConfig::default(),
+    );
+    assert!(matches!(
+        result,
+        Err(MatchboxBackendError::InvalidUrl { .. })
+    ));
+}
+
+#[test]
+fn connection_phases() {
+    let port = next_test_port();
+    let mut server_app = App::new();
+    let mut client_app = App::new();
+    for app in [&mut server_app, &mut client_app] {
+        app.add_plugins((
+            MinimalPlugins,
+            StatesPlugin,
+            RepliconPlugins.set(ServerPlugin::new(PostUpdate)),
+            RepliconMatchboxPlugins,
+        ))
+        .finish();
+    }
+    start_signaling_server(&mut server_app, port);
+
+    // Nobody hosts this room.
+    let room_url = format!("ws://localhost:{port}/EmptyRoom");
+    let channels = client_app.world().resource::<RepliconChannels>();
+    let client = MatchboxClient::new(room_url, channels, MatchboxBackendConfig::default())
+        .unwrap()
+        .with_phase_timeout(
+            MatchboxConnectionPhase::WaitingForHost,
+            Duration::from_millis(200),
+        );
+    client_app.insert_resource(client);
+
+    let errors = loop {
+        server_app.update();
+        client_app.update();
+        let errors = drain_messages::<MatchboxBackendError>(&mut client_app);
+        if !errors.is_empty() {
+            break errors;
+        }
+    };
+    assert_eq!(
+        errors,
+        [MatchboxBackendError::PhaseTimeout(
+            MatchboxConnectionPhase::WaitingForHost
+        )]
+    );
+    client_app.update();
+    assert!(!client_app.world().contains_resource::<MatchboxClient>());
+    assert!(
+        !client_app
+            .world()
+            .contains_resource::<MatchboxConnectionPhase>()
+    );
+    let reason = client_app.world().resource::<ClientDisconnectReason>();
+    assert_eq!(reason.0, DisconnectReason::Timeout);
+
+    // The client joins first, so it waits for the host.
+    setup_client(&mut client_app, port);
+    let mut phases = Vec::new();
+    let mut host_inserted = false;
+    loop {
+        server_app.update();
+        client_app.update();
+        let Some(&phase) = client_app.world().get_resource::<MatchboxConnectionPhase>() else {
+            continue;
+        };
+        if phases.last() != Some(&phase) {
+            phases.push(phase);
+        }
+        if phase == MatchboxConnectionPhase::WaitingForHost && !host_inserted {
+            setup_server(&mut server_app, port);
+            host_inserted = true;
+        }
+        if phase == MatchboxConnectionPhase::Connected {
+            break;
+        }
+    }
+    // A local signaling server may assign the id before the first update.
+    if phases[0] == MatchboxConnectionPhase::Signaling {
+        phases.remove(0);
+    }
+    assert_eq!(
+        phases,
+        [
+            MatchboxConnectionPhase::WaitingForHost,
+            MatchboxConnectionPhase::Handshake,
+            MatchboxConnectionPhase::Connected,
+        ]
+    );
+    let client = client_app.world().resource::<MatchboxClient>();
+    assert_eq!(client.phase(), MatchboxConnectionPhase::Connected);
+}
+
+#[test]
+fn host_migration() {
+    let port = next_test_port();
+
+    let mut signaling_app = App::new();
+    signaling_app.add_plugins(MinimalPlugins);
+    start_full_mesh_signaling_server(&mut signaling_app, port);
+
+    let mut host_app = App::new();
+    let mut client_apps = [App::new(), App::new()];
+    for app in [&mut host_app].into_iter().chain(client_apps.iter_mut()) {
+        app.add_plugins((
+            MinimalPlugins,
+            StatesPlugin,
+            RepliconPlugins.set(ServerPlugin::new(PostUpdate)),
+            RepliconMatchboxPlugins,
+        ))
+        .finish();
+    }
+
+    setup_server(&mut host_app, port);
+    for app in &mut client_apps {
+        let room_url = format!("ws://localhost:{port}/TestRoom");
+        let channels = app.world().resource::<RepliconChannels>();
+        let client = MatchboxClient::new(room_url, channels, MatchboxBackendConfig::default())
+            .unwrap()
+            .with_host_migration(Duration::from_secs(10))
+            .with_migration_host(|host| host.with_max_clients(3));
+        app.insert_resource(client);
+    }
+
+    host_app.world_mut().spawn(Replicated);
+    loop {
+        signaling_app.update();
+        host_app.update();
+        for app in &mut client_apps {
+            app.update();
+        }
+        // Clients must see each other to agree on the successor.
+        let meshed = client_apps.iter().all(|app| {
+            let client = app.world().resource::<MatchboxClient>();
+            client.socket.connected_peers().len() == 2
+        });
+        if meshed && client_apps.iter_mut().all(|app| replicated_count(app) == 1) {
+            break;
+        }
+    }
+
+    host_app.world_mut().remove_resource::<MatchboxHost>();
+    host_app.update();
+
+    let mut migrations = Vec::new();
+    let (new_host, client) = loop {
+        signaling_app.update();
+        for app in &mut client_apps {
+            app.update();
+            migrations.extend(
+                app.world_mut()
+                    .resource_mut::<Messages<HostMigrated>>()
+                    .drain(),
+            );
+        }
+        let [first, second] = &mut client_apps;
+        let (new_host, client) = if first.world().contains_resource::<MatchboxHost>() {
+            (first, second)
+        } else {
+            (second, first)
+        };
+        let Some(host) = new_host.world().get_resource::<MatchboxHost>() else {
+            continue;
+        };
+        let connected = client.world().resource::<MatchboxClient>().is_connected();
+        if host.connected_clients() == 1 && connected && replicated_count(client) == 1 {
+            break (new_host, client);
+        }
+    };
+
+    assert_eq!(migrations.len(), 2);
+    assert_eq!(
+        migrations
+            .iter()
+            .filter(|migration| migration.is_new_host)
+            .count(),
+        1
+    );
+    assert_eq!(replicated_count(new_host), 1);
+    let host = new_host.world().resource::<MatchboxHost>();
+    assert_eq!(host.max_clients(), Some(3));
+
+    let server_state = new_host.world().resource::<State<ServerState>>();
+    assert_eq!(*server_state, ServerState::Running);
+    let client_state = client.world().resource::<State<ClientState>>();
+    assert_eq!(*client_state, ClientState::Connected);
+}
+
+#[test]
+fn matchmaking() {
+    let port = next_test_port();
+
+    let mut signaling_app = App::new();
+    signaling_app.add_plugins(MinimalPlugins);
+    start_full_mesh_signaling_server(&mut signaling_app, port);
+
+    let mut apps = [App::new(), App::new(), App::new()];
+    for app in &mut apps {
+        app.add_plugins((
+            MinimalPlugins,
+            StatesPlugin,
+            RepliconPlugins.set(ServerPlugin::new(PostUpdate)),
+            RepliconMatchboxPlugins,
+        ))
+        .finish();
+
+        let address = RoomAddress::new(format!("ws://localhost:{port}"), "Quickplay");
+        let channels = app.world().resource::<RepliconChannels>();
+        let queue =
+            MatchmakingQueue::new(address, 3, channels, MatchboxBackendConfig::default()).unwrap();
+        app.insert_resource(queue);
+    }
+
+    let mut matches = Vec::new();
+    loop {
+        signaling_app.update();
+        for app in &mut apps {
+            app.update();
+            matches.extend(
+                app.world_mut()
+                    .resource_mut::<Messages<MatchFound>>()
+                    .drain(),
+            );
+        }
+        let hosts: Vec<_> = apps
+            .iter()
+            .filter_map(|app| app.world().get_resource::<MatchboxHost>())
+            .collect();
+        let connected_clients = apps
+            .iter()
+            .filter_map(|app| app.world().get_resource::<MatchboxClient>())
+            .filter(|client| client.is_connected())
+            .count();
+        if let [host] = *hosts
+            && host.connected_clients() == 2
+            && connected_clients == 2
+        {
+            break;
+        }
+    }
+
+    assert_eq!(
+        matches
+            .iter()
+            .filter(|found| found.role == MatchRole::Host)
+            .count(),
+        1
+    );
+    assert_eq!(matches.len(), 3);
+    assert!(matches.iter().all(|found| found.room == matches[0].room));
+    assert_ne!(matches[0].room.room(), "Quickplay");
+
+    for app in &mut apps {
+        for _ in 0..10 {
+            app.update();
+        }
+        assert!(!app.world().contains_resource::<MatchmakingQueue>());
+    }
+}
+
+#[test]
+fn matchmaking_extra_peer() {
+    let port = next_test_port();
+
+    let mut signaling_app = App::new();
+    signaling_app.add_plugins(MinimalPlugins);
+    start_full_mesh_signaling_server(&mut signaling_app, port);
+
+    let mut apps = [App::new(), App::new(), App::new(), App::new()];
+    for app in &mut apps {
+        app.add_plugins((
+            MinimalPlugins,
+            StatesPlugin,
+            RepliconPlugins.set(ServerPlugin::new(PostUpdate)),
+            RepliconMatchboxPlugins,
+        ))
+        .finish();
+
+        let address = RoomAddress::new(format!("ws://localhost:{port}"), "Quickplay");
+        let channels = app.world().resource::<RepliconChannels>();
+        let queue =
+            MatchmakingQueue::new(address, 3, channels, MatchboxBackendConfig::default()).unwrap();
+        app.insert_resource(queue);
+    }
+
+    let start = Instant::now();
+    let mut matches = Vec::new();
+    loop {
+        assert!(
+            start.elapsed() < Duration::from_secs(20),
+            "match should be found in time"
+        );
+        signaling_app.update();
+        for app in &mut apps {
+            app.update();
+            matches.extend(
+                app.world_mut()
+                    .resource_mut::<Messages<MatchFound>>()
+                    .drain(),
+            );
+        }
+        let hosts: Vec<_> = apps
+            .iter()
+            .filter_map(|app| app.world().get_resource::<MatchboxHost>())
+            .collect();
+        let connected_clients = apps
+            .iter()
+            .filter_map(|app| app.world().get_resource::<MatchboxClient>())
+            .filter(|client| client.is_connected())
+            .count();
+        if let [host] = *hosts
+            && host.connected_clients() == 2
+            && connected_clients == 2
+        {
+            break;
+        }
+    }
+
+    assert_eq!(matches.len(), 3, "only the match members should find it");
+    assert!(matches.iter().all(|found| found.room == matches[0].room));
+    let waiting: Vec<_> = apps
+        .iter()
+        .filter(|app| {
+            !app.world().contains_resource::<MatchboxHost>()
+                && !app.world().contains_resource::<MatchboxClient>()
+        })
+        .collect();
+    assert_eq!(waiting.len(), 1);
+    assert!(
+        waiting[0].world().contains_resource::<MatchmakingQueue>(),
+        "the extra peer should stay in the queue"
+    );
+}
+
+/// Updates `app` until `done`, packets sent over WebRTC can take a few frames to arrive.
+fn update_until(app: &mut App, mut done: impl FnMut(&mut App) -> bool) {
+    let start = Instant::now();
+    while !done(app) {
+        assert!(
+            start.elapsed() < Duration::from_secs(5),
+            "should be delivered in time"
+        );
+        std::thread::sleep(Duration::from_millis(1));
+        app.update();
+    }
+}
+
+fn replicated_count(app: &mut App) -> usize {
+    app.world_mut()
+        .query::<&Replicated>()
+        .iter(app.world())
+        .len()
+}
+
+fn wait_for_rejection(
+    server_app: &mut App,
+    client_app: &mut App,
+) -> (Vec<HandshakeRejection>, Vec<HandshakeRejection>) {
+    let mut server_rejections = Vec::new();
+    let mut client_rejections = Vec::new();
+    while client_app.world().contains_resource::<MatchboxClient>() {
+        client_app.update();
+        server_app.update();
+        server_rejections.extend(drain_rejections(server_app));
+        client_rejections.extend(drain_rejections(client_app));
+    }
+    client_app.update();
+
+    (server_rejections, client_rejections)
+}
+
+fn drain_rejections(app: &mut App) -> Vec<HandshakeRejection> {
+    app.world_mut()
+        .resource_mut::<Messages<HandshakeRejected>>()
+        .drain()
+        .map(|rejected| rejected.rejection)
+        .collect()
+}
+
+/// Sends a [`Test`] message from `client_app` and returns how many the host received.
+fn received_messages(server_app: &mut App, client_app: &mut App) -> usize {
+    client_app.world_mut().write_message(Test);
+    client_app.update();
+    server_app.update();
+    server_app
+        .world_mut()
+        .resource_mut::<Messages<FromClient<Test>>>()
+        .drain()
+        .count()
+}
+
+fn drain_messages<M: Message>(app: &mut App) -> Vec<M> {
+    app.world_mut()
+        .resource_mut::<Messages<M>>()
+        .drain()
+        .collect()
+}
+
+/// Connects the apps through an in-memory network, no signaling server needed.
+fn setup_loopback(server_app: &mut App, client_app: &mut App) {
+    let network = LoopbackNetwork::default();
+    let channels = server_app.world().resource::<RepliconChannels>();
+    let server = MatchboxHost::loopback(&network, channels, MatchboxBackendConfig::default());
+    server_app.insert_resource(server);
+    let channels = client_app.world().resource::<RepliconChannels>();
+    let client = MatchboxClient::loopback(&network, channels, MatchboxBackendConfig::default());
+    client_app.insert_resource(client);
+    wait_for_connection(server_app, client_app);
+}
+
+fn setup(server_app: &mut App, client_app: &mut App, port: u16) {
+    start_signaling_server(server_app, port);
+    setup_server(server_app, port);
+    setup_client(client_app, port);
+    wait_for_connection(server_app, client_app);
+}
+
+/// Lets the plugin start the signaling server before the host connects to it.
+fn start_signaling_server(server_app: &mut App, port: u16) {
+    server_app
+        .insert_resource(SignalingConfig::default().with_bind_address((Ipv4Addr::LOCALHOST, port)));
+    server_app.update();
+}
+
+use bevy_matchbox::matchbox_signaling::SignalingServer;
+
+/// The plugin only starts client-server signaling servers, but host migration needs clients
+/// connected to each other too.
+fn start_full_mesh_signaling_server(app: &mut App, port: u16) {
+    let addr = SocketAddrV4::new(Ipv4Addr::LOCALHOST, port);
+    let signaling_server = bevy_matchbox::MatchboxServer::from(
+        SignalingServer::full_mesh_builder(addr)
+            .on_peer_connected(|id| info!("Peer joined: {id}"))
+            .on_peer_disconnected(|id| info!("Peer left: {id}"))
+            .cors()
+            .build(),
+    );
+    app.insert_resource(signaling_server);
 }
 
 fn setup_server(app: &mut App, port: u16) {
     let room_url = format!("ws://localhost:{port}/TestRoom");
     let channels = app.world().resource::<RepliconChannels>();
 
-    let server = MatchboxHost::new(room_url, channels).unwrap();
+    let server = MatchboxHost::new(room_url, channels, MatchboxBackendConfig::default()).unwrap();
 
     app.insert_resource(server);
 }
@@ -289,7 +2057,7 @@ fn setup_server(app: &mut App, port: u16) {
 fn setup_client(app: &mut App, port: u16) {
     let room_url = format!("ws://localhost:{port}/TestRoom");
     let channels = app.world().resource::<RepliconChannels>();
-    let client = MatchboxClient::new(room_url, channels).unwrap();
+    let client = MatchboxClient::new(room_url, channels, MatchboxBackendConfig::default()).unwrap();
     app.insert_resource(client);
 }
 
@@ -307,3 +2075,39 @@ fn wait_for_connection(server_app: &mut App, client_app: &mut App) {
 
 #[derive(Message, Serialize, Deserialize)]
 struct Test;
+
+#[derive(Message, Serialize, Deserialize)]
+struct Large(Vec<u8>);
+
+/// Replaces runs of the same byte with the byte and the run length.
+struct RunLength;
+
+impl PacketCompressor for RunLength {
+    fn compress(&self, data: &[u8]) -> Vec<u8> {
+        let mut output = Vec::new();
+        for chunk in data.chunk_by(|a, b| a == b) {
+            for run in chunk.chunks(u8::MAX as usize) {
+                output.extend([run[0], run.len() as u8]);
+            }
+        }
+        output
+    }
+
+    fn decompress(&self, data: &[u8], max_size: usize) -> Option<Vec<u8>> {
+        let mut output = Vec::new();
+        for pair in data.chunks(2) {
+            let &[byte, len] = pair else {
+                return None;
+            };
+            if output.len() + len as usize > max_size {
+                return None;
+            }
+            output.extend(std::iter::repeat_n(byte, len as usize));
+        }
+        Some(output)
+    }
+}
+
+/// Separate from [`Large`] so the client doesn't send received messages back to the host.
+#[derive(Message, Serialize, Deserialize)]
+struct LargeUpload(Vec<u8>);
@@ -0,0 +1,319 @@
+use std::{
+    net::{Ipv4Addr, TcpListener, TcpStream},
+    time::Duration,
+};
+
+use bevy::{
+    prelude::*,
+    state::app::StatesPlugin,
+    tasks::{IoTaskPool, TaskPool},
+};
+use bevy_replicon::prelude::*;
+use bevy_replicon_matchbox::{
+    LobbyBrowser, LobbyRooms, MatchboxBackendConfig, MatchboxClient, MatchboxHost,
+    MatchboxSignalingServer, RepliconMatchboxPlugins, RoomAddress, RoomInfo, SignalingConfig,
+    SignalingPeerJoined, SignalingPeerLeft, SignalingRole,
+};
+use test_log::test;
+
+#[test]
+fn bound_address() {
+    IoTaskPool::get_or_init(TaskPool::new);
+    let server = MatchboxSignalingServer::start(
+        SignalingConfig::default().with_bind_address((Ipv4Addr::LOCALHOST, 0)),
+    )
+    .unwrap();
+    let addr = server.local_addr();
+    assert_ne!(addr.port(), 0);
+    assert_eq!(
+        server.room_address("room").url(),
+        format!("ws://127.0.0.1:{}/room", addr.port())
+    );
+}
+
+#[test]
+fn listen_server() {
+    let mut server_app = App::new();
+    let mut client_app = App::new();
+    for app in [&mut server_app, &mut client_app] {
+        app.add_plugins((
+            MinimalPlugins,
+            StatesPlugin,
+            RepliconPlugins.set(ServerPlugin::new(PostUpdate)),
+            RepliconMatchboxPlugins,
+        ))
+        .finish();
+    }
+
+    let signaling = MatchboxSignalingServer::start(
+        SignalingConfig::default().with_bind_address((Ipv4Addr::LOCALHOST, 0)),
+    )
+    .unwrap();
+    let addr = signaling.local_addr();
+    let room_url = signaling.room_address("TestRoom");
+    let channels = server_app.world().resource::<RepliconChannels>();
+    let host = MatchboxHost::new(room_url.clone(), channels, MatchboxBackendConfig::default())
+        .unwrap()
+        .with_signaling_server(signaling);
+    server_app.insert_resource(host);
+    let channels = client_app.world().resource::<RepliconChannels>();
+    let client = MatchboxClient::new(room_url, channels, MatchboxBackendConfig::default()).unwrap();
+    client_app.insert_resource(client);
+
+    let mut joined = Vec::new();
+    loop {
+        client_app.update();
+        server_app.update();
+        joined.extend(drain_roles::<SignalingPeerJoined>(&mut server_app));
+        let host = server_app.world().resource::<MatchboxHost>();
+        let client = client_app.world().resource::<MatchboxClient>();
+        if host.connected_clients() > 0 && client.is_connected() {
+            break;
+        }
+    }
+    assert_eq!(joined, [SignalingRole::Host, SignalingRole::Client]);
+
+    client_app.world_mut().remove_resource::<MatchboxClient>();
+    let mut left = Vec::new();
+    while left.is_empty() {
+        client_app.update();
+        server_app.update();
+        left.extend(drain_roles::<SignalingPeerLeft>(&mut server_app));
+    }
+    assert_eq!(left, [SignalingRole::Client]);
+
+    server_app.world_mut().remove_resource::<MatchboxHost>();
+    server_app.update();
+    std::thread::sleep(Duration::from_millis(100));
+    assert!(
+        TcpStream::connect(addr).is_err(),
+        "signaling server should stop with the host"
+    );
+}
+
+#[test]
+fn plugin_server_stop() {
+    let mut server_app = App::new();
+    server_app
+        .add_plugins((
+            MinimalPlugins,
+            StatesPlugin,
+            RepliconPlugins.set(ServerPlugin::new(PostUpdate)),
+            RepliconMatchboxPlugins,
+        ))
+        .finish();
+
+    server_app
+        .insert_resource(SignalingConfig::default().with_bind_address((Ipv4Addr::LOCALHOST, 0)));
+    server_app.update();
+    let signaling = server_app.world().resource::<MatchboxSignalingServer>();
+    let addr = signaling.local_addr();
+    let room_url = signaling.room_address("TestRoom");
+    let channels = server_app.world().resource::<RepliconChannels>();
+    let host = MatchboxHost::new(room_url, channels, MatchboxBackendConfig::default()).unwrap();
+    server_app.insert_resource(host);
+    server_app.update();
+    assert!(TcpStream::connect(addr).is_ok());
+
+    server_app.world_mut().remove_resource::<MatchboxHost>();
+    server_app.update();
+    assert!(
+        !server_app
+            .world()
+            .contains_resource::<MatchboxSignalingServer>()
+    );
+    assert!(!server_app.world().contains_resource::<SignalingConfig>());
+    std::thread::sleep(Duration::from_millis(100));
+    assert!(
+        TcpStream::connect(addr).is_err(),
+        "signaling server should stop with the host"
+    );
+}
+
+#[test]
+fn connection_filter() {
+    let mut server_app = App::new();
+    let mut client_app = App::new();
+    for app in [&mut server_app, &mut client_app] {
+        app.add_plugins((
+            MinimalPlugins,
+            StatesPlugin,
+            RepliconPlugins.set(ServerPlugin::new(PostUpdate)),
+            RepliconMatchboxPlugins,
+        ))
+        .finish();
+    }
+
+    let signaling = MatchboxSignalingServer::start(
+        SignalingConfig::default()
+            .with_bind_address((Ipv4Addr::LOCALHOST, 0))
+            .with_connection_filter(|request| !request.query.contains_key("banned")),
+    )
+    .unwrap();
+    let room_url = signaling.room_address("TestRoom");
+    server_app.insert_resource(signaling);
+
+    let channels = client_app.world().resource::<RepliconChannels>();
+    let client = MatchboxClient::new(
+        room_url.with_query("banned", true),
+        channels,
+        MatchboxBackendConfig::default().with_connection_attempts(Some(1)),
+    )
+    .unwrap();
+    client_app.insert_resource(client);
+
+    while client_app.world().contains_resource::<MatchboxClient>() {
+        client_app.update();
+        server_app.update();
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    assert!(drain_roles::<SignalingPeerJoined>(&mut server_app).is_empty());
+}
+
+#[test]
+fn lobby() {
+    let mut server_app = App::new();
+    let mut client_app = App::new();
+    for app in [&mut server_app, &mut client_app] {
+        app.add_plugins((
+            MinimalPlugins,
+            StatesPlugin,
+            RepliconPlugins.set(ServerPlugin::new(PostUpdate)),
+            RepliconMatchboxPlugins,
+        ))
+        .finish();
+    }
+
+    let signaling = MatchboxSignalingServer::start(
+        SignalingConfig::default()
+            .with_bind_address((Ipv4Addr::LOCALHOST, 0))
+            .with_lobby(true),
+    )
+    .unwrap();
+    let address = signaling.room_address("TestRoom");
+    let channels = server_app.world().resource::<RepliconChannels>();
+    let host = MatchboxHost::new(&address, channels, MatchboxBackendConfig::default())
+        .unwrap()
+        .with_game_version("1.0")
+        .with_max_clients(2)
+        .with_lobby(
+            &address,
+            RoomInfo::new("Test").with_property("map", "forest"),
+        )
+        .with_signaling_server(signaling);
+    server_app.insert_resource(host);
+    client_app.insert_resource(LobbyBrowser::new(address.base_url()).with_game_version("1.0"));
+
+    let room = wait_for_room(&mut server_app, &mut client_app, |room| room.players == 0);
+    assert_eq!(room.room, "TestRoom");
+    assert_eq!(room.name, "Test");
+    assert_eq!(room.max_players, Some(2));
+    assert_eq!(room.version, "1.0");
+    assert_eq!(room.properties["map"], "forest");
+
+    let channels = client_app.world().resource::<RepliconChannels>();
+    let client = MatchboxClient::new(&address, channels, MatchboxBackendConfig::default())
+        .unwrap()
+        .with_game_version("1.0");
+    client_app.insert_resource(client);
+    wait_for_room(&mut server_app, &mut client_app, |room| room.players == 1);
+
+    server_app.world_mut().remove_resource::<MatchboxHost>();
+    while !client_app.world().resource::<LobbyRooms>().is_empty() {
+        server_app.update();
+        client_app.update();
+        std::thread::sleep(Duration::from_millis(10));
+    }
+}
+
+#[test]
+fn lobby_reconnect() {
+    let mut server_app = App::new();
+    let mut client_app = App::new();
+    for app in [&mut server_app, &mut client_app] {
+        app.add_plugins((
+            MinimalPlugins,
+            StatesPlugin,
+            RepliconPlugins.set(ServerPlugin::new(PostUpdate)),
+            RepliconMatchboxPlugins,
+        ))
+        .finish();
+    }
+
+    // Reserve a port for the lobby, which starts only after the host tried to advertise.
+    let lobby_port = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let lobby_address = RoomAddress::new(format!("ws://127.0.0.1:{lobby_port}"), "TestRoom");
+
+    let signaling = MatchboxSignalingServer::start(
+        SignalingConfig::default().with_bind_address((Ipv4Addr::LOCALHOST, 0)),
+    )
+    .unwrap();
+    let address = signaling.room_address("TestRoom");
+    let channels = server_app.world().resource::<RepliconChannels>();
+    let host = MatchboxHost::new(&address, channels, MatchboxBackendConfig::default())
+        .unwrap()
+        .with_lobby(&lobby_address, RoomInfo::new("Test"))
+        .with_signaling_server(signaling);
+    server_app.insert_resource(host);
+    for _ in 0..10 {
+        server_app.update();
+        std::thread::sleep(Duration::from_millis(10));
+    }
+
+    let _lobby = MatchboxSignalingServer::start(
+        SignalingConfig::default()
+            .with_bind_address((Ipv4Addr::LOCALHOST, lobby_port))
+            .with_lobby(true),
+    )
+    .unwrap();
+    client_app.insert_resource(LobbyBrowser::new(lobby_address.base_url()));
+
+    let room = wait_for_room(&mut server_app, &mut client_app, |_| true);
+    assert_eq!(room.name, "Test");
+}
+
+fn wait_for_room(
+    server_app: &mut App,
+    client_app: &mut App,
+    condition: impl Fn(&RoomInfo) -> bool,
+) -> RoomInfo {
+    loop {
+        server_app.update();
+        client_app.update();
+        let rooms = client_app.world().resource::<LobbyRooms>();
+        if let [room] = rooms.as_slice()
+            && condition(room)
+        {
+            return room.clone();
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    }
+}
+
+trait PeerChange: Message {
+    fn role(&self) -> SignalingRole;
+}
+
+impl PeerChange for SignalingPeerJoined {
+    fn role(&self) -> SignalingRole {
+        self.role
+    }
+}
+
+impl PeerChange for SignalingPeerLeft {
+    fn role(&self) -> SignalingRole {
+        self.role
+    }
+}
+
+fn drain_roles<M: PeerChange>(app: &mut App) -> Vec<SignalingRole> {
+    app.world_mut()
+        .resource_mut::<Messages<M>>()
+        .drain()
+        .map(|change| change.role())
+        .collect()
+}